@@ -0,0 +1,369 @@
+mod options;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use traffic::{Simulation, EventKind, Recovery, TrafficLightState};
+use traffic::scenario::{NetworkSpec, DemandSpec, DemandQueue};
+use traffic::ctm::{Ctm, LinkTotals, VehicleClass};
+use traffic::record::Recording;
+use options::{Options, USAGE};
+
+fn main() {
+    let opts = match Options::parse(std::env::args().skip(1)) {
+        Ok(Some(opts)) => opts,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Err(err) = run(&opts) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run(opts: &Options) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = &opts.replay {
+        return replay(path);
+    }
+    let demand: DemandSpec = match &opts.demand {
+        Some(path) => read_json(path)?,
+        None => DemandSpec::default()
+    };
+
+    let network = match &opts.network {
+        Some(path) => {
+            let mut network: NetworkSpec = read_json(path)?;
+            if let Some(model) = opts.link_model {
+                for link in network.links.iter_mut() {
+                    link.model.get_or_insert(model);
+                }
+            }
+            Some(network)
+        }
+        None => None
+    };
+
+    let mut sim = match (&network, &opts.load_snapshot) {
+        (Some(network), _) => {
+            let mut sim = Simulation::new(opts.delta);
+            network.add_to_simulation(&mut sim)
+                .map_err(|e| format!("Invalid network: {}", e))?;
+            sim
+        }
+        (None, Some(path)) => {
+            let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+            Simulation::load_snapshot(BufReader::new(file))
+                .map_err(|e| format!("Could not load {}: {}", path.display(), e))?
+        }
+        (None, None) => unreachable!("Checked when parsing options")
+    };
+    sim.set_gridlock_config(opts.gridlock);
+    sim.set_safety_config(opts.safety);
+    sim.set_safety_measures(true);
+    if opts.threads != 1 {
+        rayon::ThreadPoolBuilder::new().num_threads(opts.threads).build_global()?;
+        sim.set_parallel(true);
+    }
+    let delta = sim.get_step_delta();
+    let start_step = sim.get_step();
+    let mut departures = demand.departures(delta, opts.seed);
+    for dep in departures.iter_mut() {
+        dep.step += start_step;
+    }
+    let mut pedestrian_departures = demand.pedestrian_departures(delta, opts.seed);
+    for dep in pedestrian_departures.iter_mut() {
+        dep.step += start_step;
+    }
+    let mut queue = DemandQueue::new(departures).with_pedestrians(pedestrian_departures);
+
+    fs::create_dir_all(&opts.out)?;
+    let mut trajectories = if opts.trajectory_interval > 0 {
+        let mut w = BufWriter::new(File::create(opts.out.join("trajectories.csv"))?);
+        writeln!(w, "step,time,vehicle,link,pos,vel,lat")?;
+        Some(w)
+    } else { None };
+    let corridor = match &opts.corridor {
+        Some(links) => Some(sim.corridor(links).map_err(|e| format!("Invalid corridor: {}", e))?),
+        None => None
+    };
+    let mut timespace = match &corridor {
+        Some(_) => {
+            let mut w = BufWriter::new(File::create(opts.out.join("timespace.csv"))?);
+            writeln!(w, "step,time,vehicle,lane,dist,vel")?;
+            let mut signals = BufWriter::new(File::create(opts.out.join("timespace_signals.csv"))?);
+            writeln!(signals, "step,time,stop_line,dist,state")?;
+            Some((w, signals))
+        }
+        None => None
+    };
+    // Last state written for each traffic light on the corridor
+    let mut signal_states = HashMap::new();
+    let mut stats = if opts.stats_interval > 0 {
+        let mut w = BufWriter::new(File::create(opts.out.join("stats.csv"))?);
+        writeln!(w, "step,time,vehicles,inserted,arrived,mean_speed")?;
+        Some(w)
+    } else { None };
+    let mut links = if opts.link_interval > 0.0 {
+        let mut w = BufWriter::new(File::create(opts.out.join("links.csv"))?);
+        writeln!(w, "time,link,flow,density,speed")?;
+        Some(w)
+    } else { None };
+    let link_steps = interval_steps(opts.link_interval, delta);
+    let mut link_totals: HashMap<usize, LinkTotals> = HashMap::new();
+    let mut events = BufWriter::new(File::create(opts.out.join("events.csv"))?);
+    writeln!(events, "step,time,event,vehicle,link,pos,stop_lines")?;
+
+    // Step at which each vehicle currently in the network was first seen, by user id
+    let mut departed: HashMap<usize, usize> = HashMap::new();
+    let mut arrived = 0;
+    let mut total_travel_time = 0.0;
+    let mut stuck = 0;
+    let mut gridlocks = 0;
+    // Time each pedestrian on a crossing has waited so far, by user id
+    let mut pedestrian_waits: HashMap<usize, f32> = HashMap::new();
+    let mut crossed = 0;
+    let mut total_wait = 0.0;
+
+    loop {
+        if opts.steps.map(|n| sim.get_step() - start_step >= n).unwrap_or(false) {
+            break;
+        }
+        if queue.is_empty() && sim.num_vehicles() == 0 && sim.num_pedestrians() == 0 {
+            break;
+        }
+
+        queue.release(&mut sim).map_err(|e| format!("Invalid demand: {}", e))?;
+        sim.step();
+        let step = sim.get_step();
+        let time = step as f32 * delta;
+
+        for event in sim.drain_events() {
+            let time = event.step as f32 * delta;
+            match event.kind {
+                EventKind::VehicleStuck { user_id, link, pos, .. } => {
+                    stuck += 1;
+                    writeln!(events, "{},{},stuck,{},{},{},", event.step, time, user_id, link, pos)?;
+                }
+                EventKind::Gridlock { stop_lines } => {
+                    gridlocks += 1;
+                    let stop_lines = stop_lines.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(" ");
+                    writeln!(events, "{},{},gridlock,,,,{}", event.step, time, stop_lines)?;
+                }
+                EventKind::Recovered { user_id, recovery } => {
+                    if recovery == Recovery::Remove {
+                        // Removed vehicles have not arrived
+                        departed.remove(&user_id);
+                    }
+                    let kind = match recovery {
+                        Recovery::None => "none",
+                        Recovery::TeleportAhead => "teleported",
+                        Recovery::Remove => "removed",
+                        Recovery::ForceCommit => "committed"
+                    };
+                    writeln!(events, "{},{},{},{},,,", event.step, time, kind, user_id)?;
+                }
+                EventKind::Collision { link, user_ids } => {
+                    writeln!(events, "{},{},collision,{} {},{},,", event.step, time, user_ids[0], user_ids[1], link)?;
+                }
+                EventKind::NearMiss { link, follower, leader, .. } => {
+                    writeln!(events, "{},{},near_miss,{} {},{},,", event.step, time, follower, leader, link)?;
+                }
+                EventKind::Encroachment { stop_line, user_id, conflicting_user_id, .. } => {
+                    writeln!(events, "{},{},encroachment,{} {},,,{}", event.step, time, conflicting_user_id, user_id, stop_line)?;
+                }
+            }
+        }
+
+        // Detect arrivals
+        let mut present = HashMap::with_capacity(departed.len());
+        for veh in sim.get_vehicle_states() {
+            present.insert(veh.user_id, *departed.get(&veh.user_id).unwrap_or(&step));
+        }
+        for (user_id, start) in departed.iter() {
+            if !present.contains_key(user_id) {
+                arrived += 1;
+                total_travel_time += (step - start) as f32 * delta;
+            }
+        }
+        departed = present;
+        let waits = sim.get_pedestrian_states().map(|p| (p.user_id, p.wait)).collect::<HashMap<_, _>>();
+        for (user_id, wait) in pedestrian_waits.iter() {
+            if !waits.contains_key(user_id) {
+                crossed += 1;
+                total_wait += wait;
+            }
+        }
+        pedestrian_waits = waits;
+
+        if let Some(w) = trajectories.as_mut() {
+            if step.is_multiple_of(opts.trajectory_interval) {
+                for veh in sim.get_vehicle_states() {
+                    writeln!(w, "{},{},{},{},{},{},{}", step, time, veh.user_id, veh.link, veh.pos, veh.vel, veh.lat)?;
+                }
+            }
+        }
+        if let (Some((w, signals)), Some(corridor)) = (timespace.as_mut(), &corridor) {
+            if step.is_multiple_of(opts.trajectory_interval) {
+                for veh in sim.get_vehicle_states() {
+                    if let Some(dist) = corridor.distance(veh.link, veh.pos) {
+                        writeln!(w, "{},{},{},{},{},{}", step, time, veh.user_id, veh.lane, dist, veh.vel)?;
+                    }
+                }
+            }
+            // Only changes are written, so the diagram can draw each state up to the next
+            for &(id, dist) in corridor.get_signals() {
+                let state = sim.get_traffic_light(id);
+                if signal_states.insert(id, state) != Some(state) {
+                    let state = match state {
+                        Some(TrafficLightState::Red) => "red",
+                        Some(TrafficLightState::Amber) => "amber",
+                        Some(TrafficLightState::Green) => "green",
+                        None => ""
+                    };
+                    writeln!(signals, "{},{},{},{},{}", step, time, id, dist, state)?;
+                }
+            }
+        }
+        if let Some(w) = stats.as_mut() {
+            if step.is_multiple_of(opts.stats_interval) {
+                let vehicles = sim.num_vehicles();
+                let mean_speed = if vehicles > 0 {
+                    sim.get_vehicle_states().map(|v| v.vel).sum::<f32>() / vehicles as f32
+                } else { 0.0 };
+                writeln!(w, "{},{},{},{},{},{}", step, time, vehicles, queue.num_inserted(), arrived, mean_speed)?;
+            }
+        }
+        if let Some(w) = links.as_mut() {
+            for veh in sim.get_vehicle_states() {
+                link_totals.entry(veh.link).or_default().add_vehicle(veh.vel, delta);
+            }
+            if (step - start_step).is_multiple_of(link_steps) {
+                let totals = link_totals.drain().collect();
+                write_link_rows(w, time, totals, link_steps as f32 * delta, |link| sim.get_link_length(link))?;
+            }
+        }
+    }
+
+    if let Some(mut w) = trajectories { w.flush()?; }
+    if let Some((mut w, mut signals)) = timespace {
+        w.flush()?;
+        signals.flush()?;
+    }
+    if let Some(mut w) = stats { w.flush()?; }
+    if let Some(mut w) = links { w.flush()?; }
+    events.flush()?;
+    write_safety(&sim, &opts.out.join("safety.csv"))?;
+    if let Some(path) = &opts.save_snapshot {
+        let file = File::create(path).map_err(|e| format!("Could not create {}: {}", path.display(), e))?;
+        let mut w = BufWriter::new(file);
+        sim.save_snapshot(&mut w)?;
+        w.flush()?;
+    }
+
+    println!("Steps run:           {}", sim.get_step() - start_step);
+    println!("Vehicles inserted:   {}", queue.num_inserted());
+    println!("Vehicles arrived:    {}", arrived);
+    if arrived > 0 {
+        println!("Mean travel time:    {:.1} s", total_travel_time / arrived as f32);
+    }
+    if crossed > 0 {
+        println!("Pedestrians crossed: {}", crossed);
+        println!("Mean pedestrian wait: {:.1} s", total_wait / crossed as f32);
+    }
+    if stuck > 0 || gridlocks > 0 {
+        println!("Vehicles stuck:      {}", stuck);
+        println!("Gridlocks:           {}", gridlocks);
+    }
+    let safety = sim.get_total_safety_stats();
+    println!("Collisions:          {}", safety.collisions);
+    println!("Conflicts (TTC/DRAC/PET): {}/{}/{}", safety.ttc_conflicts, safety.drac_conflicts, safety.pet_conflicts);
+
+    if let (Some(ctm_delta), Some(network)) = (opts.ctm_delta, &network) {
+        let duration = (sim.get_step() - start_step) as f32 * delta;
+        run_ctm(opts, network, &demand, ctm_delta, duration)?;
+    }
+    Ok(())
+}
+
+/// Runs a cell transmission model of the network and demand for the given time, writing the
+/// flow, density and speed of its links in the same form as those of the simulation.
+fn run_ctm(opts: &Options, network: &NetworkSpec, demand: &DemandSpec, delta: f32, duration: f32) -> Result<(), Box<dyn Error>> {
+    let departures = demand.departures(delta, opts.seed);
+    let mut ctm = Ctm::new(network, &departures, VehicleClass::default(), delta)
+        .map_err(|e| format!("Invalid demand: {}", e))?;
+    let mut w = BufWriter::new(File::create(opts.out.join("ctm.csv"))?);
+    writeln!(w, "time,link,flow,density,speed")?;
+    let link_steps = interval_steps(opts.link_interval, delta);
+    let steps = (duration / delta).round() as usize;
+    while ctm.get_step() < steps {
+        ctm.step();
+        if ctm.get_step().is_multiple_of(link_steps) {
+            let time = ctm.get_step() as f32 * delta;
+            let totals = ctm.take_link_totals();
+            write_link_rows(&mut w, time, totals, link_steps as f32 * delta, |link| ctm.get_link_length(link))?;
+        }
+    }
+    w.flush()?;
+    println!("CTM vehicles arrived: {:.1}", ctm.num_arrived());
+    Ok(())
+}
+
+/// The number of steps in an interval, rounded to at least one.
+fn interval_steps(interval: f32, delta: f32) -> usize {
+    ((interval / delta).round() as usize).max(1)
+}
+
+/// Writes the flow, density and speed of each link vehicles were on over an interval, by link.
+fn write_link_rows<W, F>(w: &mut W, time: f32, mut totals: Vec<(usize, LinkTotals)>, duration: f32, length: F) -> Result<(), Box<dyn Error>>
+    where W: Write, F: Fn(usize) -> Option<f32>
+{
+    totals.sort_by_key(|&(link, _)| link);
+    for (link, t) in totals {
+        if t.time <= 0.0 {
+            continue;
+        }
+        let length = length(link).unwrap_or(f32::INFINITY);
+        writeln!(w, "{},{},{},{},{}", time, link, t.flow(length, duration), t.density(length, duration), t.speed())?;
+    }
+    Ok(())
+}
+
+/// Writes the safety statistics of each link, followed by those of the whole network.
+fn write_safety(sim: &Simulation, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut w = BufWriter::new(File::create(path)?);
+    writeln!(w, "link,vehicle_time,collisions,ttc_conflicts,min_ttc,drac_conflicts,max_drac,pet_conflicts,min_pet")?;
+    let total = sim.get_total_safety_stats();
+    let rows = sim.get_safety_stats()
+        .map(|(link, stats)| (link.to_string(), *stats))
+        .chain(std::iter::once(("total".to_string(), total)));
+    let opt = |x: Option<f32>| x.map(|x| x.to_string()).unwrap_or_default();
+    for (link, s) in rows {
+        writeln!(w, "{},{},{},{},{},{},{},{},{}", link, s.vehicle_time, s.collisions, s.ttc_conflicts, opt(s.min_ttc),
+            s.drac_conflicts, s.max_drac, s.pet_conflicts, opt(s.min_pet))?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+fn replay(path: &Path) -> Result<(), Box<dyn Error>> {
+    let recording: Recording = read_json(path)?;
+    let sim = recording.replay()?;
+    println!("Replayed {} actions over {} steps, every frame matched.", recording.entries.len(), recording.num_steps());
+    println!("Vehicles remaining:  {}", sim.num_vehicles());
+    Ok(())
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, Box<dyn Error>> {
+    let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+    let value = serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| format!("Could not parse {}: {}", path.display(), e))?;
+    Ok(value)
+}
@@ -0,0 +1,171 @@
+use std::path::PathBuf;
+use traffic::{GridlockConfig, Recovery, SafetyConfig, LinkModel};
+
+pub const USAGE: &str = "\
+Usage: traffic-cli (--network <file> | --load-snapshot <file>) [options]
+       traffic-cli --replay <file>
+
+Runs a scenario without a front end, writing the results to CSV files, or replays a recording
+of a server session and checks that it reproduces the same frames.
+
+Options:
+    --network <file>              Road network description (JSON)
+    --load-snapshot <file>        Start from a saved simulation state instead of a network
+    --save-snapshot <file>        Save the simulation state at the end of the run
+    --link-model <model>          Model for network links which do not give one: micro or meso
+                                  (default: micro)
+    --demand <file>               Travel demand description (JSON), timed from the start of the run
+    --steps <n>                   Maximum number of steps to run (default: until all vehicles arrive)
+    --delta <seconds>             Length of a simulation step, unless loading a snapshot (default: 0.1)
+    --seed <n>                    Seed for randomly generated demand (default: 0)
+    --out <dir>                   Directory to write outputs to (default: .)
+    --trajectory-interval <n>     Steps between trajectory samples, 0 to disable (default: 10)
+    --corridor <links>            Comma separated links of a corridor, each leading to the next,
+                                  along which to write vehicle trajectories (at the trajectory
+                                  interval) and traffic light changes against distance, for a
+                                  time-space diagram
+    --stats-interval <n>          Steps between statistics rows, 0 to disable (default: 10)
+    --link-interval <seconds>     Time between rows of the flow, density and speed of each link,
+                                  0 to disable (default: 0)
+    --ctm-delta <seconds>         Also run a cell transmission model of the network and demand
+                                  with this step length, for the same time, writing its link rows
+                                  to ctm.csv; requires --network and --link-interval
+    --stuck-time <seconds>        Time stationary before a vehicle is reported stuck (default: 300)
+    --recovery <action>           What to do with stuck vehicles: none, teleport, remove or commit
+                                  (default: none)
+    --ttc <seconds>               Time-to-collision below which vehicles are in conflict (default: 1.5)
+    --pet <seconds>               Post-encroachment time below which vehicles are in conflict
+                                  (default: 1.5)
+    --drac <m/s^2>                Deceleration rate to avoid a crash above which vehicles are in
+                                  conflict (default: 3.35)
+    --threads <n>                 Threads to step the simulation with, 0 for one per core; the
+                                  results are the same for any number (default: 1)
+    --replay <file>               Replay a recorded session (JSON) instead of running a scenario
+    --help                        Print this message";
+
+pub struct Options {
+    pub network: Option<PathBuf>,
+    pub load_snapshot: Option<PathBuf>,
+    pub link_model: Option<LinkModel>,
+    pub save_snapshot: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    pub demand: Option<PathBuf>,
+    pub steps: Option<usize>,
+    pub delta: f32,
+    pub seed: u64,
+    pub out: PathBuf,
+    pub trajectory_interval: usize,
+    pub corridor: Option<Vec<usize>>,
+    pub stats_interval: usize,
+    pub link_interval: f32,
+    pub ctm_delta: Option<f32>,
+    pub gridlock: GridlockConfig,
+    pub safety: SafetyConfig,
+    pub threads: usize
+}
+
+impl Options {
+    /// Parses the command line arguments, excluding the program name.
+    /// Returns `Ok(None)` if help was requested.
+    pub fn parse<I: Iterator<Item=String>>(mut args: I) -> Result<Option<Self>, String> {
+        let mut opts = Options {
+            network: None,
+            load_snapshot: None,
+            link_model: None,
+            save_snapshot: None,
+            replay: None,
+            demand: None,
+            steps: None,
+            delta: 0.1,
+            seed: 0,
+            out: PathBuf::from("."),
+            trajectory_interval: 10,
+            corridor: None,
+            stats_interval: 10,
+            link_interval: 0.0,
+            ctm_delta: None,
+            gridlock: GridlockConfig::default(),
+            safety: SafetyConfig::default(),
+            threads: 1
+        };
+
+        while let Some(flag) = args.next() {
+            if flag == "--help" || flag == "-h" {
+                return Ok(None);
+            }
+            let value = args.next().ok_or_else(|| format!("Missing value for {}", flag))?;
+            match flag.as_str() {
+                "--network" => opts.network = Some(PathBuf::from(value)),
+                "--load-snapshot" => opts.load_snapshot = Some(PathBuf::from(value)),
+                "--save-snapshot" => opts.save_snapshot = Some(PathBuf::from(value)),
+                "--replay" => opts.replay = Some(PathBuf::from(value)),
+                "--link-model" => opts.link_model = match value.as_str() {
+                    "micro" => Some(LinkModel::Micro),
+                    "meso" => Some(LinkModel::Meso),
+                    _ => return Err(format!("Invalid value \"{}\" for {}", value, flag))
+                },
+                "--demand" => opts.demand = Some(PathBuf::from(value)),
+                "--steps" => opts.steps = Some(parse(&flag, &value)?),
+                "--delta" => opts.delta = parse(&flag, &value)?,
+                "--seed" => opts.seed = parse(&flag, &value)?,
+                "--out" => opts.out = PathBuf::from(value),
+                "--trajectory-interval" => opts.trajectory_interval = parse(&flag, &value)?,
+                "--corridor" => opts.corridor = Some(value.split(',')
+                    .map(|link| parse(&flag, link.trim()))
+                    .collect::<Result<_, _>>()?),
+                "--stats-interval" => opts.stats_interval = parse(&flag, &value)?,
+                "--link-interval" => opts.link_interval = parse(&flag, &value)?,
+                "--ctm-delta" => opts.ctm_delta = Some(parse(&flag, &value)?),
+                "--stuck-time" => opts.gridlock.stationary_time = parse(&flag, &value)?,
+                "--recovery" => opts.gridlock.recovery = match value.as_str() {
+                    "none" => Recovery::None,
+                    "teleport" => Recovery::TeleportAhead,
+                    "remove" => Recovery::Remove,
+                    "commit" => Recovery::ForceCommit,
+                    _ => return Err(format!("Invalid value \"{}\" for {}", value, flag))
+                },
+                "--ttc" => opts.safety.ttc = parse(&flag, &value)?,
+                "--pet" => opts.safety.pet = parse(&flag, &value)?,
+                "--drac" => opts.safety.drac = parse(&flag, &value)?,
+                "--threads" => opts.threads = parse(&flag, &value)?,
+                _ => return Err(format!("Unknown option {}", flag))
+            }
+        }
+
+        let sources = [&opts.network, &opts.load_snapshot, &opts.replay];
+        if sources.iter().filter(|s| s.is_some()).count() != 1 {
+            return Err("Exactly one of --network, --load-snapshot and --replay is required".into());
+        }
+        if !opts.delta.is_finite() || opts.delta <= 0.0 {
+            return Err("--delta must be a positive number".into());
+        }
+        if !opts.link_interval.is_finite() || opts.link_interval < 0.0 {
+            return Err("--link-interval must be a non-negative number".into());
+        }
+        if opts.corridor.is_some() && opts.trajectory_interval == 0 {
+            return Err("--corridor requires a non-zero --trajectory-interval".into());
+        }
+        if let Some(ctm_delta) = opts.ctm_delta {
+            if !ctm_delta.is_finite() || ctm_delta <= 0.0 {
+                return Err("--ctm-delta must be a positive number".into());
+            }
+            if opts.network.is_none() || opts.link_interval == 0.0 {
+                return Err("--ctm-delta requires --network and --link-interval".into());
+            }
+        }
+        if !opts.gridlock.stationary_time.is_finite() || opts.gridlock.stationary_time <= 0.0 {
+            return Err("--stuck-time must be a positive number".into());
+        }
+        let thresholds = [("--ttc", opts.safety.ttc), ("--pet", opts.safety.pet), ("--drac", opts.safety.drac)];
+        for (flag, value) in thresholds.iter() {
+            if !value.is_finite() || *value <= 0.0 {
+                return Err(format!("{} must be a positive number", flag));
+            }
+        }
+        Ok(Some(opts))
+    }
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value \"{}\" for {}", value, flag))
+}
@@ -0,0 +1,324 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use byteorder::{NetworkEndian as NE, WriteBytesExt};
+use traffic::{Simulation, VehicleState};
+use crate::protocol::{MSG_FRAME, MSG_FRAME_V2};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// Every vehicle, as `f32`s, in the original frame format
+    Full,
+    /// Every vehicle, quantised to 16 bit fixed point
+    Quantised,
+    /// Only the quantised fields which changed since the last frame sent to the client
+    Delta
+}
+
+const EVENT_SPAWN: u8 = 0;
+const EVENT_DESPAWN: u8 = 1;
+const EVENT_ENTER: u8 = 2;
+const EVENT_EXIT: u8 = 3;
+
+const FIELD_LINK: u8 = 1;
+const FIELD_POS: u8 = 2;
+const FIELD_VEL: u8 = 4;
+const FIELD_LAT: u8 = 8;
+const FIELD_DLAT: u8 = 16;
+
+/// Optional vehicle fields, which clients ask for in the handshake.
+const EXTRA_LANE: u8 = 1;
+const EXTRA_ACC: u8 = 2;
+const EXTRA_INDICATOR: u8 = 4;
+const EXTRA_SIZE: u8 = 8;
+const EXTRA_STOPLINE: u8 = 16;
+
+/// The names of the optional vehicle fields, as used in the protocol.
+pub const EXTRA_FIELDS: &[(&str, u8)] = &[
+    ("lane", EXTRA_LANE),
+    ("acc", EXTRA_ACC),
+    ("indicator", EXTRA_INDICATOR),
+    ("size", EXTRA_SIZE),
+    ("stop_line", EXTRA_STOPLINE)
+];
+
+/// The straight line along which a link runs, used to place vehicles in the plane.
+#[derive(Clone, Copy)]
+pub struct LinkGeometry {
+    pub x1: f32,
+    pub y1: f32,
+    pub x2: f32,
+    pub y2: f32
+}
+
+impl LinkGeometry {
+    /// The position of a point on a link of the given length, with positive `lat` to the left.
+    fn point(&self, length: f32, pos: f32, lat: f32) -> (f32, f32) {
+        let (dx, dy) = (self.x2 - self.x1, self.y2 - self.y1);
+        let norm = (dx * dx + dy * dy).sqrt().max(f32::EPSILON);
+        let t = pos / length;
+        (self.x1 + t * dx - lat * dy / norm, self.y1 + t * dy + lat * dx / norm)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Area {
+    pub min_x: f32,
+    pub min_y: f32,
+    pub max_x: f32,
+    pub max_y: f32
+}
+
+/// Which vehicles a client wants to receive, how often, and how they should be encoded.
+#[derive(Clone)]
+pub struct Subscription {
+    pub links: Option<HashSet<usize>>,
+    pub area: Option<Area>,
+    pub max_rate: Option<f32>,
+    pub encoding: Encoding
+}
+
+impl Default for Subscription {
+    fn default() -> Self {
+        Self {
+            links: None,
+            area: None,
+            max_rate: None,
+            encoding: Encoding::Full
+        }
+    }
+}
+
+/// A vehicle state in fixed point, as sent in quantised and delta frames.
+#[derive(Clone, Copy, PartialEq)]
+struct Quantised {
+    link: u32,
+    pos: u16,
+    vel: i16,
+    lat: i16,
+    dlat: i16,
+    lane: u8,
+    acc: i16,
+    indicator: i8,
+    len: u16,
+    wid: u16,
+    stopline: u32
+}
+
+impl Quantised {
+    fn new(veh: &VehicleState) -> Self {
+        Self {
+            link: veh.link as u32,
+            pos: (veh.pos * 10.0).round().max(0.0).min(u16::MAX as f32) as u16,
+            vel: quantise_i16(veh.vel, 100.0),
+            lat: quantise_i16(veh.lat, 100.0),
+            dlat: quantise_i16(veh.dlat, 1000.0),
+            lane: veh.lane,
+            acc: quantise_i16(veh.acc, 100.0),
+            indicator: veh.lane_change,
+            len: (veh.len * 100.0).round().min(u16::MAX as f32) as u16,
+            wid: (veh.wid * 100.0).round().min(u16::MAX as f32) as u16,
+            stopline: veh.stopline.map(|s| s as u32).unwrap_or(!0)
+        }
+    }
+
+    fn changed_fields(&self, prev: &Self) -> u8 {
+        let mut mask = 0;
+        if self.link != prev.link { mask |= FIELD_LINK; }
+        if self.pos != prev.pos { mask |= FIELD_POS; }
+        if self.vel != prev.vel { mask |= FIELD_VEL; }
+        if self.lat != prev.lat { mask |= FIELD_LAT; }
+        if self.dlat != prev.dlat { mask |= FIELD_DLAT; }
+        mask
+    }
+
+    fn changed_extras(&self, prev: &Self) -> u8 {
+        let mut mask = 0;
+        if self.lane != prev.lane { mask |= EXTRA_LANE; }
+        if self.acc != prev.acc { mask |= EXTRA_ACC; }
+        if self.indicator != prev.indicator { mask |= EXTRA_INDICATOR; }
+        if (self.len, self.wid) != (prev.len, prev.wid) { mask |= EXTRA_SIZE; }
+        if self.stopline != prev.stopline { mask |= EXTRA_STOPLINE; }
+        mask
+    }
+
+    fn write<W: Write>(&self, w: &mut W, mask: u8) -> io::Result<()> {
+        if mask & FIELD_LINK != 0 { w.write_u32::<NE>(self.link)?; }
+        if mask & FIELD_POS != 0 { w.write_u16::<NE>(self.pos)?; }
+        if mask & FIELD_VEL != 0 { w.write_i16::<NE>(self.vel)?; }
+        if mask & FIELD_LAT != 0 { w.write_i16::<NE>(self.lat)?; }
+        if mask & FIELD_DLAT != 0 { w.write_i16::<NE>(self.dlat)?; }
+        Ok(())
+    }
+
+    fn write_extras<W: Write>(&self, w: &mut W, mask: u8) -> io::Result<()> {
+        if mask & EXTRA_LANE != 0 { w.write_u8(self.lane)?; }
+        if mask & EXTRA_ACC != 0 { w.write_i16::<NE>(self.acc)?; }
+        if mask & EXTRA_INDICATOR != 0 { w.write_i8(self.indicator)?; }
+        if mask & EXTRA_SIZE != 0 {
+            w.write_u16::<NE>(self.len)?;
+            w.write_u16::<NE>(self.wid)?;
+        }
+        if mask & EXTRA_STOPLINE != 0 { w.write_u32::<NE>(self.stopline)?; }
+        Ok(())
+    }
+}
+
+/// Writes the optional fields of a vehicle at full precision.
+fn write_extras_full<W: Write>(w: &mut W, veh: &VehicleState, mask: u8) -> io::Result<()> {
+    if mask & EXTRA_LANE != 0 { w.write_u8(veh.lane)?; }
+    if mask & EXTRA_ACC != 0 { w.write_f32::<NE>(veh.acc)?; }
+    if mask & EXTRA_INDICATOR != 0 { w.write_i8(veh.lane_change)?; }
+    if mask & EXTRA_SIZE != 0 {
+        w.write_f32::<NE>(veh.len)?;
+        w.write_f32::<NE>(veh.wid)?;
+    }
+    if mask & EXTRA_STOPLINE != 0 { w.write_u32::<NE>(veh.stopline.map(|s| s as u32).unwrap_or(!0))?; }
+    Ok(())
+}
+
+fn quantise_i16(x: f32, scale: f32) -> i16 {
+    (x * scale).round().max(i16::MIN as f32).min(i16::MAX as f32) as i16
+}
+
+/**
+ * The frames sent to one client: what it subscribed to, and what it has been sent so far.
+ * */
+pub struct View {
+    pub sub: Subscription,
+    /// The optional fields the client asked for
+    pub extras: u8,
+    /// The vehicles visible to the client in the last frame, by user id
+    sent: HashMap<usize, Quantised>,
+    /// Every vehicle in the simulation at the last frame, by user id
+    existing: HashSet<usize>,
+    last_sent: Option<Instant>
+}
+
+impl View {
+    pub fn new(sub: Subscription, extras: u8) -> Self {
+        Self {
+            sub,
+            extras,
+            sent: HashMap::new(),
+            existing: HashSet::new(),
+            last_sent: None
+        }
+    }
+
+    /// Whether a streamed frame should be sent now, given the client's maximum rate.
+    pub fn is_due(&self, now: Instant) -> bool {
+        match (self.sub.max_rate, self.last_sent) {
+            (Some(rate), Some(last)) => now.duration_since(last) >= Duration::from_secs_f32(1.0 / rate),
+            _ => true
+        }
+    }
+
+    fn is_visible(&self, veh: &VehicleState, sim: &Simulation, geometry: &HashMap<usize, LinkGeometry>) -> bool {
+        if let Some(links) = &self.sub.links {
+            if !links.contains(&veh.link) {
+                return false;
+            }
+        }
+        if let Some(area) = &self.sub.area {
+            // Vehicles on links without geometry cannot be placed, so are never in an area
+            let (geom, length) = match (geometry.get(&veh.link), sim.get_link_length(veh.link)) {
+                (Some(geom), Some(length)) => (geom, length),
+                _ => return false
+            };
+            let (x, y) = geom.point(length, veh.pos, veh.lat);
+            if x < area.min_x || x > area.max_x || y < area.min_y || y > area.max_y {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Writes the current state of the simulation as a frame.
+    pub fn write_frame<W: Write>(&mut self, w: &mut W, sim: &Simulation, geometry: &HashMap<usize, LinkGeometry>) -> io::Result<()> {
+        self.last_sent = Some(Instant::now());
+        let vehs = sim.get_vehicle_states()
+            .filter(|v| self.is_visible(v, sim, geometry))
+            .collect::<Vec<_>>();
+
+        if self.sub.encoding == Encoding::Full {
+            // Code for a frame message
+            w.write_u32::<NE>(MSG_FRAME)?;
+            // The frame index
+            w.write_u32::<NE>(sim.get_step() as u32)?;
+            // The vehicle positions
+            for veh in vehs.iter() {
+                w.write_u32::<NE>(veh.user_id as u32)?;
+                w.write_u32::<NE>(veh.link as u32)?;
+                w.write_f32::<NE>(veh.pos)?;
+                w.write_f32::<NE>(veh.vel)?;
+                w.write_f32::<NE>(veh.lat)?;
+                w.write_f32::<NE>(veh.dlat)?;
+                write_extras_full(w, veh, self.extras)?;
+            }
+            w.write_u32::<NE>(!0)?;
+            // Everything succeeded
+            return Ok(());
+        }
+
+        // Work out which vehicles appeared and disappeared
+        let existing = sim.get_vehicle_states().map(|v| v.user_id).collect::<HashSet<_>>();
+        let visible = vehs.iter().map(|v| v.user_id).collect::<HashSet<_>>();
+        let mut events = vec![];
+        for id in self.sent.keys() {
+            if !visible.contains(id) {
+                events.push((if existing.contains(id) { EVENT_EXIT } else { EVENT_DESPAWN }, *id));
+            }
+        }
+        for id in visible.iter() {
+            if !self.sent.contains_key(id) {
+                events.push((if self.existing.contains(id) { EVENT_ENTER } else { EVENT_SPAWN }, *id));
+            }
+        }
+        events.sort_by_key(|e| e.1);
+
+        w.write_u32::<NE>(MSG_FRAME_V2)?;
+        w.write_u32::<NE>(sim.get_step() as u32)?;
+        w.write_u8(self.sub.encoding as u8)?;
+        w.write_u32::<NE>(events.len() as u32)?;
+        for (kind, id) in events.iter() {
+            w.write_u8(*kind)?;
+            w.write_u32::<NE>(*id as u32)?;
+        }
+
+        let mut body = vec![];
+        let mut count = 0;
+        let mut sent = HashMap::with_capacity(vehs.len());
+        for veh in vehs.iter() {
+            let q = Quantised::new(veh);
+            if self.sub.encoding == Encoding::Delta {
+                let (mask, extras) = match self.sent.get(&veh.user_id) {
+                    Some(prev) => (q.changed_fields(prev), q.changed_extras(prev) & self.extras),
+                    None => (FIELD_LINK | FIELD_POS | FIELD_VEL | FIELD_LAT | FIELD_DLAT, self.extras)
+                };
+                if mask != 0 || extras != 0 {
+                    body.write_u32::<NE>(veh.user_id as u32)?;
+                    body.write_u8(mask)?;
+                    if self.extras != 0 {
+                        body.write_u8(extras)?;
+                    }
+                    q.write(&mut body, mask)?;
+                    q.write_extras(&mut body, extras)?;
+                    count += 1;
+                }
+            } else {
+                body.write_u32::<NE>(veh.user_id as u32)?;
+                q.write(&mut body, 0xff)?;
+                q.write_extras(&mut body, self.extras)?;
+                count += 1;
+            }
+            sent.insert(veh.user_id, q);
+        }
+        w.write_u32::<NE>(count)?;
+        w.write_all(&body)?;
+
+        self.sent = sent;
+        self.existing = existing;
+        Ok(())
+    }
+}
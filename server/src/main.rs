@@ -0,0 +1,400 @@
+mod protocol;
+mod frame;
+mod stream;
+mod session;
+
+use traffic::SimError;
+use traffic::record::Action;
+use traffic::scenario::{LinkSpec, ConnectionSpec, LanePair, StopLineSpec, ConflictSpec, CrossingSpec, CrossingConflictSpec, ClosureSpec, DetectorSpec, ControllerSpec};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use ws::{listen, Message, CloseCode};
+use stream::Stream;
+use session::{Role, Session, Sessions};
+use frame::EXTRA_FIELDS;
+use protocol::{Command, ProtocolError, Response, PROTOCOL_VERSION, CAPABILITIES};
+
+/// The most named sessions that may exist at once.
+const MAX_SESSIONS: usize = 16;
+
+/// How long a named session is kept after its last client disconnects.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(120);
+
+struct Client {
+    out: ws::Sender,
+    sessions: Rc<RefCell<Sessions>>,
+    /// The session this client is connected to, which is private to the client unless named.
+    session: Arc<Mutex<Session>>,
+    session_name: Option<String>,
+    role: Role,
+    /// The optional vehicle fields asked for in the handshake
+    extras: u8
+}
+
+// `ws::Result` is dictated by the `ws::Handler` trait.
+#[allow(clippy::result_large_err)]
+impl Client {
+    fn new(out: ws::Sender, sessions: Rc<RefCell<Sessions>>) -> Self {
+        let session = Arc::new(Mutex::new(Session::new()));
+        let role = session.lock().unwrap().join(out.clone(), true, 0);
+        Self {
+            out,
+            sessions,
+            session,
+            session_name: None,
+            role,
+            extras: 0
+        }
+    }
+
+    /// Leaves the current session, stopping it if it was private.
+    fn leave_session(&mut self) {
+        let mut session = self.session.lock().unwrap();
+        session.leave(self.out.connection_id());
+        if self.session_name.is_none() {
+            // Stops the stream thread, if any
+            session.stream = None;
+        }
+    }
+
+    fn send(&self, response: Response) -> ws::Result<()> {
+        self.out.send(response.to_json())
+    }
+
+    fn handle(&mut self, id: Option<u64>, command: Command) -> ws::Result<()> {
+        match command {
+            Command::Hello { version, extras } => {
+                if version != PROTOCOL_VERSION {
+                    let message = format!("Unsupported protocol version, server speaks version {}", PROTOCOL_VERSION);
+                    return self.send(ProtocolError::new(Some("version"), message).into_response(id));
+                }
+                self.extras = extras;
+                self.session.lock().unwrap().set_extras(self.out.connection_id(), extras);
+                self.send(Response::Hello {
+                    request_id: id,
+                    version: PROTOCOL_VERSION,
+                    capabilities: CAPABILITIES,
+                    fields: EXTRA_FIELDS.iter().filter(|(_, bit)| extras & bit != 0).map(|(name, _)| *name).collect()
+                })
+            }
+
+            Command::Join { session: name, role } => {
+                let session = match self.sessions.borrow_mut().get_or_create(&name) {
+                    Some(session) => session,
+                    None => {
+                        let err = ProtocolError::new(Some("session"), "Too many sessions are active");
+                        return self.send(err.into_response(id));
+                    }
+                };
+                if Arc::ptr_eq(&session, &self.session) {
+                    let err = ProtocolError::new(Some("session"), "Already a member of this session");
+                    return self.send(err.into_response(id));
+                }
+                if role == Role::Controller && session.lock().unwrap().has_controller() {
+                    let err = ProtocolError::new(Some("role"), "The session already has a controller");
+                    return self.send(err.into_response(id));
+                }
+                self.leave_session();
+                self.role = session.lock().unwrap().join(self.out.clone(), role == Role::Controller, self.extras);
+                self.session = session;
+                self.session_name = Some(name.clone());
+                self.send(Response::Joined {
+                    request_id: id,
+                    session: Some(name),
+                    role: self.role
+                })
+            }
+
+            Command::Leave => {
+                self.leave_session();
+                self.session = Arc::new(Mutex::new(Session::new()));
+                self.session_name = None;
+                self.role = self.session.lock().unwrap().join(self.out.clone(), true, self.extras);
+                self.send(Response::Joined {
+                    request_id: id,
+                    session: None,
+                    role: self.role
+                })
+            }
+
+            Command::Recording => {
+                let recording = self.session.lock().unwrap().recording.clone();
+                self.send(Response::Recording { request_id: id, recording })
+            }
+
+            Command::Safety => {
+                let state = self.session.lock().unwrap();
+                let total = state.sim.get_total_safety_stats();
+                let links = state.sim.get_safety_stats().map(|(link, stats)| (link, *stats)).collect();
+                drop(state);
+                self.send(Response::Safety { request_id: id, total, links })
+            }
+
+            Command::QueryPedestrians => {
+                let pedestrians = self.session.lock().unwrap().sim.get_pedestrian_states().collect();
+                self.send(Response::Pedestrians { request_id: id, pedestrians })
+            }
+
+            Command::QueryVehicle { id: user_id } => {
+                let state = self.session.lock().unwrap();
+                let vehicle = state.sim.find_vehicle(user_id)
+                    .and_then(|veh| state.sim.get_vehicle_state(veh))
+                    .ok_or(SimError::UnknownVehicle(user_id));
+                drop(state);
+                match vehicle {
+                    Ok(vehicle) => self.send(Response::Vehicle { request_id: id, vehicle }),
+                    Err(e) => self.send(ProtocolError::sim("id", e).into_response(id))
+                }
+            }
+
+            Command::Subscribe { sub } => {
+                self.session.lock().unwrap().subscribe(self.out.connection_id(), sub);
+                self.send(Response::Ok { request_id: id })
+            }
+
+            _ if self.role != Role::Controller => {
+                let err = ProtocolError::new(Some("cmd"), "Only the controller of a session can do that");
+                self.send(err.into_response(id))
+            }
+
+            Command::Step { count } => {
+                let request_id = id.map(|id| id as u32).unwrap_or(!0);
+                self.session.lock().unwrap().step_batch(request_id, count);
+                Ok(())
+            }
+
+            Command::Stream { frame_rate, speed } => {
+                let state = &mut *self.session.lock().unwrap();
+                match &mut state.stream {
+                    Some(stream) => {
+                        stream.frame_rate = frame_rate;
+                        stream.speed = speed;
+                        stream.resume();
+                    }
+                    None => {
+                        state.stream = Some(Stream::new(frame_rate, speed));
+                        let session = self.session.clone();
+                        thread::spawn(move || stream_frames(session));
+                    }
+                }
+                self.send(Response::Ok { request_id: id })
+            }
+
+            Command::Pause | Command::Resume | Command::Speed { .. } => {
+                let state = &mut *self.session.lock().unwrap();
+                let stream = match &mut state.stream {
+                    Some(stream) => stream,
+                    None => {
+                        let err = ProtocolError::new(Some("cmd"), "No stream has been started");
+                        return self.send(err.into_response(id));
+                    }
+                };
+                match command {
+                    Command::Pause => stream.pause(),
+                    Command::Resume => stream.resume(),
+                    Command::Speed { speed } => stream.speed = speed,
+                    _ => unreachable!()
+                }
+                self.send(Response::Ok { request_id: id })
+            }
+
+            command => match self.apply(command) {
+                Ok(()) => self.send(Response::Ok { request_id: id }),
+                Err(err) => self.send(err.into_response(id))
+            }
+        }
+    }
+
+    fn apply(&mut self, command: Command) -> Result<(), ProtocolError> {
+        let state = &mut *self.session.lock().unwrap();
+        match command {
+            Command::Start { delta } => {
+                state.restart(delta);
+            }
+
+            Command::Link { id, length, speed_limit, lanes, geometry, model } => {
+                state.apply(Action::Link(LinkSpec { id, length, speed_limit, lanes, model })).map_err(|e| match e {
+                    SimError::DuplicateId { .. } | SimError::IdOutOfRange { .. } => ProtocolError::sim("id", e),
+                    _ => ProtocolError::sim("lanes", e)
+                })?;
+                if let Some(geometry) = geometry {
+                    state.geometry.insert(id, geometry);
+                }
+            }
+
+            Command::Connection { src_link, dst_link, lanes, offset } => {
+                let lanes = lanes.into_iter().map(|(from, to)| LanePair { from, to }).collect();
+                state.apply(Action::Connection(ConnectionSpec { src_link, dst_link, lanes, offset })).map_err(|e| match e {
+                    SimError::UnknownLink(link) if link == src_link => ProtocolError::sim("src_link", e),
+                    SimError::UnknownLink(_) => ProtocolError::sim("dst_link", e),
+                    _ => ProtocolError::sim("lanes", e)
+                })?;
+            }
+
+            Command::StopLine { id: stop, link, lane, pos, length, kind } => {
+                state.apply(Action::StopLine(StopLineSpec { id: stop, link, lane, pos, length, kind })).map_err(|e| match e {
+                    SimError::UnknownLink(_) => ProtocolError::sim("link", e),
+                    SimError::UnknownLane { .. } => ProtocolError::sim("lane", e),
+                    SimError::InvalidGeometry(_) => ProtocolError::sim("pos", e),
+                    _ => ProtocolError::sim("id", e)
+                })?;
+            }
+
+            Command::Conflict { stop1, stop2, priority, max_pos } => {
+                state.apply(Action::Conflict(ConflictSpec { stop1, stop2, priority, max_pos })).map_err(|e| match e {
+                    SimError::UnknownStopLine(stop) if stop == stop1 => ProtocolError::sim("stop1", e),
+                    _ => ProtocolError::sim("stop2", e)
+                })?;
+            }
+
+            Command::Crossing { id: crossing, length, kind, conflicts } => {
+                let conflicts = conflicts.into_iter()
+                    .map(|(stop_line, max_pos)| CrossingConflictSpec { stop_line, max_pos })
+                    .collect();
+                state.apply(Action::Crossing(CrossingSpec { id: crossing, length, kind, conflicts })).map_err(|e| match e {
+                    SimError::UnknownStopLine(_) => ProtocolError::sim("conflicts", e),
+                    _ => ProtocolError::sim("id", e)
+                })?;
+            }
+
+            Command::Pedestrian { id: user_id, crossing, speed, reverse } => {
+                state.apply(Action::Pedestrian { user_id, crossing, speed, reverse }).map_err(|e| match e {
+                    SimError::UnknownCrossing(_) => ProtocolError::sim("crossing", e),
+                    _ => ProtocolError::sim("id", e)
+                })?;
+            }
+
+            Command::Vehicle { id: user_id, src_link, dst_link, lane, pos } => {
+                state.apply(Action::Vehicle { user_id, src_link, dst_link, lane, pos }).map_err(|e| match e {
+                    SimError::UnknownLink(link) if link == src_link => ProtocolError::sim("src_link", e),
+                    SimError::UnknownLink(_) | SimError::UnreachableDestination { .. } => ProtocolError::sim("dst_link", e),
+                    SimError::UnknownLane { .. } => ProtocolError::sim("lane", e),
+                    SimError::DuplicateId { .. } | SimError::IdOutOfRange { .. } => ProtocolError::sim("id", e),
+                    _ => ProtocolError::sim("pos", e)
+                })?;
+            }
+
+            Command::RemoveVehicle { id: user_id } => {
+                state.apply(Action::RemoveVehicle { user_id }).map_err(|e| ProtocolError::sim("id", e))?;
+            }
+
+            Command::Gridlock { config } => {
+                state.apply(Action::Gridlock(config)).map_err(|e| ProtocolError::sim("stationary_time", e))?;
+            }
+
+            Command::Close { id, link, lane, start_pos, end_pos, from, until, routing } => {
+                let from = from.unwrap_or(state.sim.get_step() as f32 * state.sim.get_step_delta());
+                if until.map(|until| until <= from).unwrap_or(false) {
+                    return Err(ProtocolError::new(Some("until"), "Expected a time after the closure starts"));
+                }
+                let closure = ClosureSpec { id, link, lane, start_pos, end_pos, from, until, routing };
+                state.apply(Action::Closure(closure)).map_err(|e| match e {
+                    SimError::UnknownLink(_) => ProtocolError::sim("link", e),
+                    SimError::UnknownLane { .. } => ProtocolError::sim("lane", e),
+                    SimError::DuplicateId { .. } | SimError::IdOutOfRange { .. } => ProtocolError::sim("id", e),
+                    _ => ProtocolError::sim("end_pos", e)
+                })?;
+            }
+
+            Command::Reopen { id } => {
+                state.apply(Action::RemoveClosure { id }).map_err(|e| ProtocolError::sim("id", e))?;
+            }
+
+            Command::Detector { id, link, lane, pos, length } => {
+                state.apply(Action::Detector(DetectorSpec { id, link, lane, pos, length })).map_err(|e| match e {
+                    SimError::UnknownLink(_) => ProtocolError::sim("link", e),
+                    SimError::UnknownLane { .. } => ProtocolError::sim("lane", e),
+                    SimError::DuplicateId { .. } | SimError::IdOutOfRange { .. } => ProtocolError::sim("id", e),
+                    _ => ProtocolError::sim("pos", e)
+                })?;
+            }
+
+            Command::Controller { phases } => {
+                state.apply(Action::Controller(ControllerSpec::Actuated { phases })).map_err(|e| ProtocolError::sim("phases", e))?;
+            }
+
+            Command::CoordinatedController { cycle, offset, phases } => {
+                state.apply(Action::Controller(ControllerSpec::Coordinated { cycle, offset, phases })).map_err(|e| ProtocolError::sim("phases", e))?;
+            }
+
+            Command::MoveVehicle { id: user_id, link, lane, pos } => {
+                state.apply(Action::MoveVehicle { user_id, link, lane, pos }).map_err(|e| match e {
+                    SimError::UnknownVehicle(_) | SimError::StaleVehicle(_) | SimError::VehicleNotPlaced(_) =>
+                        ProtocolError::sim("id", e),
+                    SimError::UnknownLink(_) | SimError::UnreachableDestination { .. } => ProtocolError::sim("link", e),
+                    SimError::UnknownLane { .. } => ProtocolError::sim("lane", e),
+                    _ => ProtocolError::sim("pos", e)
+                })?;
+            }
+
+            Command::RouteVehicle { id: user_id, dst_link } => {
+                state.apply(Action::RouteVehicle { user_id, dst_link }).map_err(|e| match e {
+                    SimError::UnknownLink(_) | SimError::UnreachableDestination { .. } => ProtocolError::sim("dst_link", e),
+                    _ => ProtocolError::sim("id", e)
+                })?;
+            }
+
+            Command::SetVehicleSpeed { id: user_id, max_speed } => {
+                state.apply(Action::SetVehicleSpeed { user_id, max_speed }).map_err(|e| match e {
+                    SimError::InvalidSpeed(_) => ProtocolError::sim("max_speed", e),
+                    _ => ProtocolError::sim("id", e)
+                })?;
+            }
+
+            _ => unreachable!()
+        }
+        Ok(())
+    }
+}
+
+impl ws::Handler for Client {
+    fn on_message(&mut self, msg: Message) -> ws::Result<()> {
+        let text = match msg {
+            Message::Text(text) => text,
+            Message::Binary(_) => {
+                let err = ProtocolError::new(None, "Expected a JSON text message");
+                return self.send(err.into_response(None));
+            }
+        };
+        match protocol::parse_request(&text) {
+            Ok(request) => self.handle(request.id, request.command),
+            Err((id, err)) => self.send(err.into_response(id))
+        }
+    }
+
+    fn on_close(&mut self, _code: CloseCode, _reason: &str) {
+        self.leave_session();
+    }
+}
+
+/// Advances the simulation in real time, pushing a frame to the session's members at the
+/// stream's frame rate. Runs until the stream is removed.
+fn stream_frames(session: Arc<Mutex<Session>>) {
+    loop {
+        let interval = match &session.lock().unwrap().stream {
+            Some(stream) => stream.interval(),
+            None => return
+        };
+        thread::sleep(interval);
+
+        let state = &mut *session.lock().unwrap();
+        let delta = state.sim.get_step_delta();
+        let steps = match &mut state.stream {
+            Some(stream) if stream.is_paused() => continue,
+            Some(stream) => stream.tick(delta),
+            None => return
+        };
+        for _i in 0..steps {
+            state.step();
+        }
+        state.push_frame();
+    }
+}
+
+fn main() {
+    let sessions = Rc::new(RefCell::new(Sessions::new(MAX_SESSIONS, SESSION_TIMEOUT)));
+    listen("0.0.0.0:8080", |out| Client::new(out, sessions.clone())).unwrap();
+}
@@ -0,0 +1,614 @@
+/*!
+ * The WebSocket protocol spoken between the server and front-end clients.
+ *
+ * Requests are JSON text messages of the form `{"cmd": "<name>", "request_id": <n>, ...}`,
+ * where `request_id` is an optional integer echoed back in the matching response. Responses
+ * to requests are JSON text messages tagged with a `type`:
+ *
+ * - `{"type": "hello", "request_id": n, "version": 1, "capabilities": [...], "fields": [...]}`
+ * - `{"type": "joined", "request_id": n, "session": "name", "role": "controller"}`
+ * - `{"type": "ok", "request_id": n}`
+ * - `{"type": "recording", "request_id": n, "recording": {...}}`
+ * - `{"type": "safety", "request_id": n, "total": {...}, "links": {"<link>": {...}, ...}}`
+ * - `{"type": "vehicle", "request_id": n, "vehicle": {"user_id": n, "link": n, "pos": x, ...}}`
+ * - `{"type": "pedestrians", "request_id": n, "pedestrians": [{"user_id": n, "crossing": n, "pos": x, ...}, ...]}`
+ *
+ * Events in the simulation, such as stuck vehicles, gridlock, collisions and near misses, are pushed to every member of a
+ * session as they happen, after the frame in which they happened:
+ *
+ * - `{"type": "event", "step": n, "event": "vehicle_stuck", "user_id": n, ...}`
+ * - `{"type": "error", "request_id": n, "field": "length", "message": "..."}`
+ *
+ * `field` names the offending request field, or is `null` if the message as a whole
+ * could not be understood.
+ *
+ * Simulation frames are sent as binary messages, with all values in network byte order:
+ *
+ * ```text
+ * batch   := u32 MSG_BATCH, u32 request id (!0 if none), u32 frame count, frame*
+ * frame   := u32 MSG_FRAME, u32 step, vehicle*, u32 !0
+ * vehicle := u32 user id, u32 link, f32 pos, f32 vel, f32 lat, f32 dlat
+ * ```
+ *
+ * The `hello` request may ask for optional vehicle `fields`, which are then appended to each
+ * vehicle in the order below, and echoed back in the response:
+ *
+ * ```text
+ * lane      := u8 lane
+ * acc       := f32 acceleration             (i16, 0.01 m/s^2 when quantised)
+ * indicator := i8 lateral direction of a lane change (-1, 0 or 1)
+ * size      := f32 length, f32 width         (u16, u16 in cm when quantised)
+ * stop_line := u32 stop line waited at (!0 if none)
+ * ```
+ *
+ * A `subscribe` request narrows the frames a client receives to vehicles on a set of `links`
+ * and/or within a rectangular `area`, limits streamed frames to `max_rate` per second (0.01 to 1000), and picks
+ * an `encoding`. Placing vehicles in an area requires the links to have been given a `geometry`
+ * (`{"x1", "y1", "x2", "y2"}`, with positive lateral offsets to the left); vehicles on links
+ * without one are never in an area. The `full` encoding is the frame format above, whereas the
+ * `quantised` and `delta` encodings replace each frame with:
+ *
+ * ```text
+ * frame2   := u32 MSG_FRAME_V2, u32 step, u8 encoding (1 quantised, 2 delta),
+ *             u32 event count, event*, u32 vehicle count, vehicle2*
+ * event    := u8 kind (0 spawn, 1 despawn, 2 enter, 3 exit), u32 user id
+ * vehicle2 := u32 user id, [u8 field mask], [u8 extra mask], [u32 link], [u16 pos], [i16 vel],
+ *             [i16 lat], [i16 dlat], extra*
+ * ```
+ *
+ * Quantised values are in units of 0.1 m for `pos`, 0.01 m/s for `vel`, 0.01 m for `lat` and
+ * 0.001 for `dlat`. The quantised encoding sends every field of every vehicle, and no mask. The
+ * delta encoding sends only the fields which changed since the client's previous frame, flagged
+ * in the mask by bits 0 to 4 in the order above, and omits vehicles for which nothing changed.
+ * If optional fields were negotiated, a second mask flags which of them follow, by bits 0 to 4
+ * in the order of the optional fields.
+ * Events report vehicles entering or leaving the simulation (spawn, despawn) or the client's
+ * subscription (enter, exit), so clients never need to infer them from absent vehicles.
+ *
+ * A `step` request is answered with a batch of up to 100000 frames. Alternatively, a `stream`
+ * request has the server advance the simulation in real time (or a multiple of it), pushing
+ * single frames unprompted at a fixed `frame_rate` of 0.01 to 1000 per second until it is paused.
+ *
+ * Each connection starts out with a private simulation. A `join` request instead connects it to
+ * a named session, which can be shared by one controller and any number of observers. Observers
+ * receive every frame but cannot send commands which change the simulation. A named session
+ * outlives its clients for a while, so that they can reconnect to it.
+ *
+ * Every change the controller makes to a session's simulation is recorded along with a digest of
+ * each frame since the last `start`. A `recording` request returns the log, which can be replayed
+ * and checked with `traffic-cli --replay`, e.g. to reproduce a bug seen in a front end.
+ *
+ * A `close` request blocks a lane of a link between `start_pos` and `end_pos`, e.g. for a crash
+ * or roadworks, from `from` (default: now) until `until` (default: until reopened), in seconds of
+ * simulation time. Its `routing` is `"none"` (the default), `"avoid"`, or a number of metres to
+ * add to routes through the link. A `reopen` request removes the closure with the given `id`.
+ *
+ * Vehicles are addressed by the `id` they were added with, which must be unique among the
+ * vehicles in the simulation. A `query` request returns the state of a vehicle, a `route`
+ * request sends it to a new `dst_link` from where it is, and a `set_speed` request caps its
+ * speed at `max_speed`, or lifts the cap if `max_speed` is omitted.
+ *
+ * A `safety` request returns the collisions and surrogate safety conflicts (time-to-collision,
+ * deceleration rate to avoid a crash and post-encroachment time) counted on each link since the
+ * simulation started, for comparing the safety of different designs.
+ *
+ * A `link` request may set its `model` to `"meso"`, so vehicles queue through it at a speed set
+ * by its density rather than being simulated one by one, e.g. for the outskirts of a large
+ * network. The default is `"micro"`.
+ *
+ * A `detector` request places a detector `length` metres long (default: 2) at `pos` in a lane,
+ * and a `controller` request of `"type": "actuated"` hands a set of traffic lights to a
+ * vehicle-actuated controller. Each of its `phases` lists the `stop_lines` which turn green
+ * together and the `detectors` which call and extend them, with `min_green`, `max_green`,
+ * `extension`, `amber` and `all_red` times in seconds, and a `recall` of `"none"` (the default),
+ * `"min"` or `"max"`.
+ *
+ * A `controller` request of `"type": "coordinated"` runs its `phases` on a fixed `cycle` in
+ * seconds instead, each with `green`, `amber` and `all_red` times adding up to the cycle. The
+ * first phase turns green `offset` seconds (default: 0) into each cycle of the simulation clock,
+ * so that neighbouring controllers with the same cycle can form a green wave.
+ *
+ * A `crossing` request adds a pedestrian crossing `length` metres long, of `kind` `"zebra"` (the
+ * default) or `"signalised"`, over the lanes of the stop lines in its `conflicts`. Pedestrians
+ * wait for the vehicles committed to each of these `stop_line`s to be past `max_pos`, and
+ * vehicles wait for the crossing to be clear of pedestrians; a stop line of kind `"zebra"` gives
+ * way to pedestrians rather than stopping. The phases of either type of controller may list the
+ * signalised `crossings` whose pedestrians can start during their green. A `ped` request adds a
+ * pedestrian waiting to cross at `speed` (default: 1.4 m/s) from the start of a crossing, or from
+ * its end if `reverse`, and a `pedestrians` request returns those yet to reach the other side.
+ * Pedestrians are not sent in frames.
+ * */
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use traffic::{SimError, StopLineType, VehicleState, EventKind, GridlockConfig, Recovery, SafetyStats, RoutePenalty, LinkModel};
+use traffic::{Phase, Recall, TimedPhase, CrossingType, PedestrianState};
+use traffic::scenario::LaneSpec;
+use traffic::record::Recording;
+use crate::frame::{Area, Encoding, LinkGeometry, Subscription, EXTRA_FIELDS};
+use crate::session::Role;
+
+/// The most steps a single `step` request may run, as the session is busy until they finish.
+pub const MAX_STEP_COUNT: usize = 100_000;
+
+/// The range of frame rates, per second, a stream or subscription may ask for.
+pub const MIN_FRAME_RATE: f32 = 0.01;
+pub const MAX_FRAME_RATE: f32 = 1000.0;
+
+/// The version of the protocol implemented by this server.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The optional features supported by this server, reported in the handshake.
+pub const CAPABILITIES: &[&str] = &["network", "stoplines", "conflicts", "vehicles", "frames", "streaming", "sessions", "subscriptions", "fields", "recording", "safety", "closures", "signals", "pedestrians"];
+
+/// Binary message code for a batch of frames.
+pub const MSG_BATCH: u32 = 2;
+
+/// Binary message code for a single frame.
+pub const MSG_FRAME: u32 = 1;
+
+/// Binary message code for a single quantised or delta encoded frame.
+pub const MSG_FRAME_V2: u32 = 3;
+
+pub struct Request {
+    pub id: Option<u64>,
+    pub command: Command
+}
+
+pub enum Command {
+    Hello { version: u32, extras: u8 },
+    Join { session: String, role: Role },
+    Leave,
+    Start { delta: f32 },
+    Link { id: usize, length: f32, speed_limit: f32, lanes: Vec<LaneSpec>, geometry: Option<LinkGeometry>, model: Option<LinkModel> },
+    Connection { src_link: usize, dst_link: usize, lanes: Vec<(u8, u8)>, offset: f32 },
+    StopLine { id: usize, link: usize, lane: u8, pos: f32, length: f32, kind: StopLineType },
+    Conflict { stop1: usize, stop2: usize, priority: i8, max_pos: f32 },
+    Crossing { id: usize, length: f32, kind: CrossingType, conflicts: Vec<(usize, f32)> },
+    Pedestrian { id: usize, crossing: usize, speed: f32, reverse: bool },
+    QueryPedestrians,
+    Vehicle { id: usize, src_link: usize, dst_link: usize, lane: u8, pos: f32 },
+    RemoveVehicle { id: usize },
+    MoveVehicle { id: usize, link: usize, lane: u8, pos: f32 },
+    QueryVehicle { id: usize },
+    RouteVehicle { id: usize, dst_link: usize },
+    SetVehicleSpeed { id: usize, max_speed: Option<f32> },
+    Gridlock { config: GridlockConfig },
+    Close { id: usize, link: usize, lane: u8, start_pos: f32, end_pos: f32, from: Option<f32>, until: Option<f32>, routing: RoutePenalty },
+    Reopen { id: usize },
+    Detector { id: usize, link: usize, lane: u8, pos: f32, length: f32 },
+    Controller { phases: Vec<Phase> },
+    CoordinatedController { cycle: f32, offset: f32, phases: Vec<TimedPhase> },
+    Step { count: usize },
+    Stream { frame_rate: f32, speed: f32 },
+    Pause,
+    Resume,
+    Speed { speed: f32 },
+    Subscribe { sub: Subscription },
+    Recording,
+    Safety
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Hello { request_id: Option<u64>, version: u32, capabilities: &'static [&'static str], fields: Vec<&'static str> },
+    Joined { request_id: Option<u64>, session: Option<String>, role: Role },
+    Ok { request_id: Option<u64> },
+    Recording { request_id: Option<u64>, recording: Recording },
+    Safety { request_id: Option<u64>, total: SafetyStats, links: BTreeMap<usize, SafetyStats> },
+    Vehicle { request_id: Option<u64>, vehicle: VehicleState },
+    Pedestrians { request_id: Option<u64>, pedestrians: Vec<PedestrianState> },
+    Event { step: usize, #[serde(flatten)] event: EventKind },
+    Error { request_id: Option<u64>, field: Option<String>, message: String }
+}
+
+pub struct ProtocolError {
+    pub field: Option<String>,
+    pub message: String
+}
+
+impl ProtocolError {
+    pub fn new(field: Option<&str>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.map(String::from),
+            message: message.into()
+        }
+    }
+
+    pub fn sim(field: &str, err: SimError) -> Self {
+        Self::new(Some(field), err.to_string())
+    }
+
+    pub fn into_response(self, request_id: Option<u64>) -> Response {
+        Response::Error {
+            request_id,
+            field: self.field,
+            message: self.message
+        }
+    }
+}
+
+impl Response {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Responses are always serialisable.")
+    }
+}
+
+/// Parses a request, returning the request id alongside any error so it can be echoed back.
+pub fn parse_request(text: &str) -> Result<Request, (Option<u64>, ProtocolError)> {
+    let value: Value = serde_json::from_str(text)
+        .map_err(|e| (None, ProtocolError::new(None, format!("Malformed JSON: {}", e))))?;
+    let obj = value.as_object()
+        .ok_or_else(|| (None, ProtocolError::new(None, "Expected a JSON object")))?;
+    let id = match obj.get("request_id") {
+        None | Some(Value::Null) => None,
+        Some(v) => Some(v.as_u64().ok_or_else(|| {
+            (None, ProtocolError::new(Some("request_id"), "Expected a non-negative integer"))
+        })?)
+    };
+    let command = parse_command(&Args { obj, prefix: String::new() }).map_err(|e| (id, e))?;
+    Ok(Request { id, command })
+}
+
+fn parse_command(args: &Args) -> Result<Command, ProtocolError> {
+    let cmd = args.str("cmd")?;
+    let command = match cmd {
+        "hello" => Command::Hello {
+            version: args.u32("version")?,
+            extras: args.opt("fields", 0, |args, field| {
+                let names = args.get(field)?.as_array().ok_or_else(|| args.error(field, "Expected an array"))?;
+                names.iter().try_fold(0, |mask, name| {
+                    let name = name.as_str().ok_or_else(|| args.error(field, "Expected an array of strings"))?;
+                    let (_, bit) = EXTRA_FIELDS.iter().find(|(n, _)| *n == name)
+                        .ok_or_else(|| args.error(field, format!("Unknown field \"{}\"", name)))?;
+                    Ok(mask | bit)
+                })
+            })?
+        },
+        "join" => Command::Join {
+            session: args.str("session")?.to_string(),
+            role: match args.opt("role", "controller", Args::str)? {
+                "controller" => Role::Controller,
+                "observer" => Role::Observer,
+                _ => return Err(args.error("role", "Expected \"controller\" or \"observer\""))
+            }
+        },
+        "leave" => Command::Leave,
+        "start" => Command::Start {
+            delta: args.positive_f32("delta")?
+        },
+        "link" => Command::Link {
+            id: args.usize("id")?,
+            length: args.positive_f32("length")?,
+            speed_limit: args.positive_f32("speed_limit")?,
+            lanes: args.array("lanes", |lane| Ok(LaneSpec {
+                start_lat: lane.f32("start_lat")?,
+                end_lat: lane.f32("end_lat")?
+            }))?,
+            geometry: args.opt("geometry", None, |args, field| {
+                let geom = args.object(field)?;
+                Ok(Some(LinkGeometry {
+                    x1: geom.f32("x1")?,
+                    y1: geom.f32("y1")?,
+                    x2: geom.f32("x2")?,
+                    y2: geom.f32("y2")?
+                }))
+            })?,
+            model: args.opt("model", None, |args, field| match args.str(field)? {
+                "micro" => Ok(Some(LinkModel::Micro)),
+                "meso" => Ok(Some(LinkModel::Meso)),
+                _ => Err(args.error(field, "Expected \"micro\" or \"meso\""))
+            })?
+        },
+        "conn" => Command::Connection {
+            src_link: args.usize("src_link")?,
+            dst_link: args.usize("dst_link")?,
+            lanes: args.array("lanes", |pair| Ok((pair.u8("from")?, pair.u8("to")?)))?,
+            offset: args.f32("offset")?
+        },
+        "stop" => Command::StopLine {
+            id: args.usize("id")?,
+            link: args.usize("link")?,
+            lane: args.u8("lane")?,
+            pos: args.f32("pos")?,
+            length: args.f32("length")?,
+            kind: args.str("kind")?.parse()
+                .map_err(|_| args.error("kind", "Expected one of \"none\", \"giveway\", \"stop\", \"zebra\" or \"light\""))?
+        },
+        "conflict" => Command::Conflict {
+            stop1: args.usize("stop1")?,
+            stop2: args.usize("stop2")?,
+            priority: args.i8("priority")?,
+            max_pos: args.f32("max_pos")?
+        },
+        "crossing" => Command::Crossing {
+            id: args.usize("id")?,
+            length: args.positive_f32("length")?,
+            kind: args.opt("kind", CrossingType::Zebra, |args, field| match args.str(field)? {
+                "zebra" => Ok(CrossingType::Zebra),
+                "signalised" => Ok(CrossingType::Signalised),
+                _ => Err(args.error(field, "Expected \"zebra\" or \"signalised\""))
+            })?,
+            conflicts: args.array("conflicts", |conflict| Ok((conflict.usize("stop_line")?, conflict.f32("max_pos")?)))?
+        },
+        "ped" => Command::Pedestrian {
+            id: args.usize("id")?,
+            crossing: args.usize("crossing")?,
+            speed: args.opt("speed", 1.4, Args::positive_f32)?,
+            reverse: args.opt("reverse", false, Args::bool)?
+        },
+        "pedestrians" => Command::QueryPedestrians,
+        "veh" => Command::Vehicle {
+            id: args.usize("id")?,
+            src_link: args.usize("src_link")?,
+            dst_link: args.usize("dst_link")?,
+            lane: args.u8("lane")?,
+            pos: args.f32("pos")?
+        },
+        "remove" => Command::RemoveVehicle {
+            id: args.usize("id")?
+        },
+        "move" => Command::MoveVehicle {
+            id: args.usize("id")?,
+            link: args.usize("link")?,
+            lane: args.u8("lane")?,
+            pos: args.f32("pos")?
+        },
+        "query" => Command::QueryVehicle {
+            id: args.usize("id")?
+        },
+        "route" => Command::RouteVehicle {
+            id: args.usize("id")?,
+            dst_link: args.usize("dst_link")?
+        },
+        "set_speed" => Command::SetVehicleSpeed {
+            id: args.usize("id")?,
+            max_speed: args.opt("max_speed", None, |args, field| args.positive_f32(field).map(Some))?
+        },
+        "gridlock" => Command::Gridlock {
+            config: GridlockConfig {
+                stationary_time: args.positive_f32("stationary_time")?,
+                recovery: match args.opt("recovery", "none", Args::str)? {
+                    "none" => Recovery::None,
+                    "teleport" => Recovery::TeleportAhead,
+                    "remove" => Recovery::Remove,
+                    "commit" => Recovery::ForceCommit,
+                    _ => return Err(args.error("recovery", "Expected one of \"none\", \"teleport\", \"remove\" or \"commit\""))
+                }
+            }
+        },
+        "close" => Command::Close {
+            id: args.usize("id")?,
+            link: args.usize("link")?,
+            lane: args.u8("lane")?,
+            start_pos: args.f32("start_pos")?,
+            end_pos: args.f32("end_pos")?,
+            from: args.opt("from", None, |args, field| args.f32(field).map(Some))?,
+            until: args.opt("until", None, |args, field| args.f32(field).map(Some))?,
+            routing: args.opt("routing", RoutePenalty::None, |args, field| {
+                let value = args.get(field)?;
+                match (value.as_f64(), value.as_str()) {
+                    (Some(dist), _) if dist >= 0.0 => Ok(RoutePenalty::Distance(dist as f32)),
+                    (_, Some("none")) => Ok(RoutePenalty::None),
+                    (_, Some("avoid")) => Ok(RoutePenalty::Avoid),
+                    _ => Err(args.error(field, "Expected \"none\", \"avoid\" or a non-negative distance"))
+                }
+            })?
+        },
+        "reopen" => Command::Reopen {
+            id: args.usize("id")?
+        },
+        "detector" => Command::Detector {
+            id: args.usize("id")?,
+            link: args.usize("link")?,
+            lane: args.u8("lane")?,
+            pos: args.f32("pos")?,
+            length: args.opt("length", 2.0, Args::f32)?
+        },
+        "controller" => match args.str("type")? {
+            "actuated" => Command::Controller {
+                phases: args.array("phases", |phase| Ok(Phase {
+                    stop_lines: phase.usizes("stop_lines")?,
+                    crossings: phase.opt("crossings", vec![], Args::usizes)?,
+                    detectors: phase.opt("detectors", vec![], Args::usizes)?,
+                    min_green: phase.f32("min_green")?,
+                    max_green: phase.f32("max_green")?,
+                    extension: phase.f32("extension")?,
+                    amber: phase.f32("amber")?,
+                    all_red: phase.f32("all_red")?,
+                    recall: phase.opt("recall", Recall::None, |args, field| match args.str(field)? {
+                        "none" => Ok(Recall::None),
+                        "min" => Ok(Recall::Min),
+                        "max" => Ok(Recall::Max),
+                        _ => Err(args.error(field, "Expected \"none\", \"min\" or \"max\""))
+                    })?
+                }))?
+            },
+            "coordinated" => Command::CoordinatedController {
+                cycle: args.positive_f32("cycle")?,
+                offset: args.opt("offset", 0.0, Args::f32)?,
+                phases: args.array("phases", |phase| Ok(TimedPhase {
+                    stop_lines: phase.usizes("stop_lines")?,
+                    crossings: phase.opt("crossings", vec![], Args::usizes)?,
+                    green: phase.f32("green")?,
+                    amber: phase.f32("amber")?,
+                    all_red: phase.f32("all_red")?
+                }))?
+            },
+            _ => return Err(args.error("type", "Expected \"actuated\" or \"coordinated\""))
+        },
+        "step" => Command::Step {
+            count: args.usize_in("count", 0, MAX_STEP_COUNT)?
+        },
+        "stream" => Command::Stream {
+            frame_rate: args.opt("frame_rate", 10.0, Args::frame_rate)?,
+            speed: args.opt("speed", 1.0, Args::positive_f32)?
+        },
+        "recording" => Command::Recording,
+        "safety" => Command::Safety,
+        "pause" => Command::Pause,
+        "resume" => Command::Resume,
+        "speed" => Command::Speed {
+            speed: args.positive_f32("speed")?
+        },
+        "subscribe" => Command::Subscribe {
+            sub: Subscription {
+                links: args.opt("links", None, |args, field| {
+                    let links = args.get(field)?.as_array().ok_or_else(|| args.error(field, "Expected an array"))?;
+                    links.iter().map(|link| link.as_u64().map(|x| x as usize))
+                        .collect::<Option<_>>()
+                        .map(Some)
+                        .ok_or_else(|| args.error(field, "Expected an array of link ids"))
+                })?,
+                area: args.opt("area", None, |args, field| {
+                    let area = args.object(field)?;
+                    let (min_x, max_x) = (area.f32("min_x")?, area.f32("max_x")?);
+                    let (min_y, max_y) = (area.f32("min_y")?, area.f32("max_y")?);
+                    if min_x > max_x || min_y > max_y {
+                        return Err(args.error(field, "Minimum must not exceed maximum"));
+                    }
+                    Ok(Some(Area { min_x, min_y, max_x, max_y }))
+                })?,
+                max_rate: args.opt("max_rate", None, |args, field| args.frame_rate(field).map(Some))?,
+                encoding: match args.opt("encoding", "full", Args::str)? {
+                    "full" => Encoding::Full,
+                    "quantised" => Encoding::Quantised,
+                    "delta" => Encoding::Delta,
+                    _ => return Err(args.error("encoding", "Expected one of \"full\", \"quantised\" or \"delta\""))
+                }
+            }
+        },
+        _ => return Err(args.error("cmd", format!("Unknown command \"{}\"", cmd)))
+    };
+    Ok(command)
+}
+
+/// Typed access to the fields of a JSON object, producing errors which name the field.
+struct Args<'a> {
+    obj: &'a Map<String, Value>,
+    prefix: String
+}
+
+impl<'a> Args<'a> {
+    fn error(&self, field: &str, message: impl Into<String>) -> ProtocolError {
+        ProtocolError {
+            field: Some(format!("{}{}", self.prefix, field)),
+            message: message.into()
+        }
+    }
+
+    fn get(&self, field: &str) -> Result<&'a Value, ProtocolError> {
+        self.obj.get(field).ok_or_else(|| self.error(field, "Missing field"))
+    }
+
+    /// Parses an optional field, falling back to a default when it is absent.
+    fn opt<T, F>(&self, field: &str, default: T, parse: F) -> Result<T, ProtocolError>
+        where F: Fn(&Self, &str) -> Result<T, ProtocolError>
+    {
+        match self.obj.get(field) {
+            None | Some(Value::Null) => Ok(default),
+            Some(_) => parse(self, field)
+        }
+    }
+
+    fn str(&self, field: &str) -> Result<&'a str, ProtocolError> {
+        self.get(field)?.as_str().ok_or_else(|| self.error(field, "Expected a string"))
+    }
+
+    fn bool(&self, field: &str) -> Result<bool, ProtocolError> {
+        self.get(field)?.as_bool().ok_or_else(|| self.error(field, "Expected a boolean"))
+    }
+
+    fn f32(&self, field: &str) -> Result<f32, ProtocolError> {
+        let value = self.get(field)?.as_f64().ok_or_else(|| self.error(field, "Expected a number"))?;
+        if !value.is_finite() {
+            return Err(self.error(field, "Expected a finite number"));
+        }
+        Ok(value as f32)
+    }
+
+    fn positive_f32(&self, field: &str) -> Result<f32, ProtocolError> {
+        let value = self.f32(field)?;
+        if value <= 0.0 {
+            return Err(self.error(field, "Expected a positive number"));
+        }
+        Ok(value)
+    }
+
+    fn frame_rate(&self, field: &str) -> Result<f32, ProtocolError> {
+        let value = self.f32(field)?;
+        if !(MIN_FRAME_RATE..=MAX_FRAME_RATE).contains(&value) {
+            return Err(self.error(field, format!("Expected a rate from {} to {} per second", MIN_FRAME_RATE, MAX_FRAME_RATE)));
+        }
+        Ok(value)
+    }
+
+    fn u64(&self, field: &str) -> Result<u64, ProtocolError> {
+        self.get(field)?.as_u64().ok_or_else(|| self.error(field, "Expected a non-negative integer"))
+    }
+
+    fn usize(&self, field: &str) -> Result<usize, ProtocolError> {
+        self.u64(field).map(|x| x as usize)
+    }
+
+    fn usize_in(&self, field: &str, min: usize, max: usize) -> Result<usize, ProtocolError> {
+        let value = self.u64(field)?;
+        if value < min as u64 || value > max as u64 {
+            return Err(self.error(field, format!("Expected an integer from {} to {}", min, max)));
+        }
+        Ok(value as usize)
+    }
+
+    fn usizes(&self, field: &str) -> Result<Vec<usize>, ProtocolError> {
+        let items = self.get(field)?.as_array().ok_or_else(|| self.error(field, "Expected an array"))?;
+        items.iter()
+            .map(|item| item.as_u64().map(|x| x as usize))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| self.error(field, "Expected an array of non-negative integers"))
+    }
+
+    fn u32(&self, field: &str) -> Result<u32, ProtocolError> {
+        let value = self.u64(field)?;
+        if value > u32::MAX as u64 {
+            return Err(self.error(field, "Integer out of range"));
+        }
+        Ok(value as u32)
+    }
+
+    fn u8(&self, field: &str) -> Result<u8, ProtocolError> {
+        let value = self.u64(field)?;
+        if value > u8::MAX as u64 {
+            return Err(self.error(field, "Integer out of range"));
+        }
+        Ok(value as u8)
+    }
+
+    fn i8(&self, field: &str) -> Result<i8, ProtocolError> {
+        let value = self.get(field)?.as_i64().ok_or_else(|| self.error(field, "Expected an integer"))?;
+        if value < i8::MIN as i64 || value > i8::MAX as i64 {
+            return Err(self.error(field, "Integer out of range"));
+        }
+        Ok(value as i8)
+    }
+
+    fn object(&self, field: &str) -> Result<Args<'a>, ProtocolError> {
+        let obj = self.get(field)?.as_object().ok_or_else(|| self.error(field, "Expected an object"))?;
+        Ok(Args { obj, prefix: format!("{}{}.", self.prefix, field) })
+    }
+
+    fn array<T, F>(&self, field: &str, parse: F) -> Result<Vec<T>, ProtocolError>
+        where F: Fn(&Args<'a>) -> Result<T, ProtocolError>
+    {
+        let items = self.get(field)?.as_array().ok_or_else(|| self.error(field, "Expected an array"))?;
+        items.iter().enumerate().map(|(i, item)| {
+            let prefix = format!("{}{}[{}].", self.prefix, field, i);
+            let obj = item.as_object().ok_or_else(|| ProtocolError {
+                field: Some(prefix[..prefix.len() - 1].to_string()),
+                message: "Expected an object".into()
+            })?;
+            parse(&Args { obj, prefix })
+        }).collect()
+    }
+}
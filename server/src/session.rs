@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use byteorder::{NetworkEndian as NE, WriteBytesExt};
+use traffic::{Simulation, SimError};
+use traffic::record::{Action, Recording};
+use crate::frame::{LinkGeometry, Subscription, View};
+use crate::protocol::{Response, MSG_BATCH};
+use crate::stream::Stream;
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Controller,
+    Observer
+}
+
+struct Member {
+    connection: u32,
+    out: ws::Sender,
+    view: View
+}
+
+/**
+ * A simulation run, along with the clients connected to it.
+ *
+ * At most one member controls the session; the rest observe it. Each member receives frames
+ * filtered and encoded according to its own subscription.
+ * */
+pub struct Session {
+    pub sim: Simulation,
+    /// Everything the controller has done to the simulation, for replaying it later
+    pub recording: Recording,
+    pub stream: Option<Stream>,
+    /// Where links lie in the plane, for members which subscribe to an area
+    pub geometry: HashMap<usize, LinkGeometry>,
+    members: Vec<Member>,
+    controller: Option<u32>,
+    empty_since: Option<Instant>
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            sim: new_simulation(1f32 / 10f32),
+            recording: Recording::new(1f32 / 10f32),
+            stream: None,
+            geometry: HashMap::new(),
+            members: vec![],
+            controller: None,
+            empty_since: None
+        }
+    }
+
+    /// Adds a member, making them the controller if they asked to be and the role is vacant.
+    /// `extras` are the optional vehicle fields the member's frames should include.
+    pub fn join(&mut self, out: ws::Sender, want_control: bool, extras: u8) -> Role {
+        let connection = out.connection_id();
+        self.members.push(Member { connection, out, view: View::new(Subscription::default(), extras) });
+        self.empty_since = None;
+        if want_control && self.controller.is_none() {
+            self.controller = Some(connection);
+            Role::Controller
+        } else {
+            Role::Observer
+        }
+    }
+
+    pub fn leave(&mut self, connection: u32) {
+        self.members.retain(|m| m.connection != connection);
+        if self.controller == Some(connection) {
+            self.controller = None;
+        }
+        if self.members.is_empty() {
+            self.empty_since = Some(Instant::now());
+        }
+    }
+
+    pub fn has_controller(&self) -> bool {
+        self.controller.is_some()
+    }
+
+    /// Replaces the simulation with an empty one.
+    pub fn restart(&mut self, step_delta: f32) {
+        self.sim = new_simulation(step_delta);
+        self.recording = Recording::new(step_delta);
+        self.geometry.clear();
+    }
+
+    pub fn apply(&mut self, action: Action) -> Result<(), SimError> {
+        self.recording.apply(&mut self.sim, action)
+    }
+
+    pub fn step(&mut self) {
+        self.recording.step(&mut self.sim);
+    }
+
+    /// Replaces a member's subscription, so that the next frame it receives starts afresh.
+    pub fn subscribe(&mut self, connection: u32, sub: Subscription) {
+        if let Some(member) = self.members.iter_mut().find(|m| m.connection == connection) {
+            member.view = View::new(sub, member.view.extras);
+        }
+    }
+
+    /// Changes the optional vehicle fields sent to a member.
+    pub fn set_extras(&mut self, connection: u32, extras: u8) {
+        if let Some(member) = self.members.iter_mut().find(|m| m.connection == connection) {
+            member.view = View::new(member.view.sub.clone(), extras);
+        }
+    }
+
+    /// Runs a number of steps, sending every member a batch holding a frame for each.
+    pub fn step_batch(&mut self, request_id: u32, count: usize) {
+        let mut buffers = self.members.iter().map(|_| {
+            let mut buffer = vec![];
+            buffer.write_u32::<NE>(MSG_BATCH).unwrap();
+            buffer.write_u32::<NE>(request_id).unwrap();
+            buffer.write_u32::<NE>(count as u32).unwrap();
+            buffer
+        }).collect::<Vec<_>>();
+        for _i in 0..count {
+            self.recording.step(&mut self.sim);
+            for (member, buffer) in self.members.iter_mut().zip(buffers.iter_mut()) {
+                member.view.write_frame(buffer, &self.sim, &self.geometry).unwrap();
+            }
+        }
+        for (member, buffer) in self.members.iter().zip(buffers) {
+            member.out.send(buffer).ok();
+        }
+        self.send_events();
+    }
+
+    /// Sends the current frame to every member whose maximum rate allows it.
+    pub fn push_frame(&mut self) {
+        let now = Instant::now();
+        for member in self.members.iter_mut() {
+            if !member.view.is_due(now) {
+                continue;
+            }
+            let mut buffer = vec![];
+            member.view.write_frame(&mut buffer, &self.sim, &self.geometry).unwrap();
+            member.out.send(buffer).ok();
+        }
+        self.send_events();
+    }
+
+    /// Sends every member the events which have happened since the last call.
+    fn send_events(&mut self) {
+        for event in self.sim.drain_events() {
+            let msg = Response::Event { step: event.step, event: event.kind }.to_json();
+            for member in self.members.iter() {
+                member.out.send(msg.clone()).ok();
+            }
+        }
+    }
+}
+
+/// An empty simulation, measuring safety so that `safety` requests cover the whole run.
+fn new_simulation(step_delta: f32) -> Simulation {
+    let mut sim = Simulation::new(step_delta);
+    sim.set_safety_measures(true);
+    sim
+}
+
+/// The named sessions which clients can join, limited in number.
+pub struct Sessions {
+    sessions: HashMap<String, Arc<Mutex<Session>>>,
+    max_sessions: usize,
+    timeout: Duration
+}
+
+impl Sessions {
+    /// Creates a registry holding at most `max_sessions` sessions, each of which is discarded
+    /// once it has had no members for `timeout`.
+    pub fn new(max_sessions: usize, timeout: Duration) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            max_sessions,
+            timeout
+        }
+    }
+
+    /// Returns the session with the given name, creating it if there is room.
+    pub fn get_or_create(&mut self, name: &str) -> Option<Arc<Mutex<Session>>> {
+        self.remove_expired();
+        if let Some(session) = self.sessions.get(name) {
+            return Some(session.clone());
+        }
+        if self.sessions.len() >= self.max_sessions {
+            return None;
+        }
+        let session = Arc::new(Mutex::new(Session::new()));
+        self.sessions.insert(name.to_string(), session.clone());
+        Some(session)
+    }
+
+    fn remove_expired(&mut self) {
+        let timeout = self.timeout;
+        self.sessions.retain(|_, session| {
+            let mut session = session.lock().unwrap();
+            let expired = session.empty_since.map(|t| t.elapsed() > timeout).unwrap_or(false);
+            if expired {
+                // Stops the stream thread, if any
+                session.stream = None;
+            }
+            !expired
+        });
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::{Duration, Instant};
+
+/// The most steps a single tick may run, so a slow simulation falls behind rather than stalling.
+const MAX_STEPS_PER_TICK: usize = 1000;
+
+/**
+ * Paces a simulation against the wall clock when frames are streamed to a client.
+ *
+ * Simulated time accrues at `speed` times the rate of real time while the stream is playing,
+ * and is paid off in whole simulation steps on each tick.
+ * */
+pub struct Stream {
+    pub frame_rate: f32,
+    pub speed: f32,
+    paused: bool,
+    last_tick: Instant,
+    owed_time: f32
+}
+
+impl Stream {
+    pub fn new(frame_rate: f32, speed: f32) -> Self {
+        Self {
+            frame_rate,
+            speed,
+            paused: false,
+            last_tick: Instant::now(),
+            owed_time: 0.0
+        }
+    }
+
+    /// The wall-clock interval between frames.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs_f32(1.0 / self.frame_rate)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        if self.paused {
+            self.paused = false;
+            self.last_tick = Instant::now();
+        }
+    }
+
+    /// Returns the number of steps needed to catch up with the wall clock.
+    pub fn tick(&mut self, step_delta: f32) -> usize {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_tick).as_secs_f32();
+        self.last_tick = now;
+        if self.paused {
+            return 0;
+        }
+        self.owed_time += elapsed * self.speed;
+        let steps = ((self.owed_time / step_delta) as usize).min(MAX_STEPS_PER_TICK);
+        self.owed_time = (self.owed_time - steps as f32 * step_delta).min(step_delta);
+        steps
+    }
+}
@@ -0,0 +1,253 @@
+/*!
+ * Benchmarks of `Simulation::step` on synthetic networks at several densities, broken down by
+ * the phases of a step.
+ *
+ * Run with `cargo bench -p traffic`, or e.g. `cargo bench -p traffic -- freeway` for one network.
+ * */
+
+use std::time::Duration;
+use criterion::{criterion_group, criterion_main, Criterion};
+use traffic::{Simulation, StepProfile, StopLineType, TrafficLightState, LinkModel, Phase, Recall};
+use traffic::scenario::{NetworkSpec, LinkSpec, LaneSpec, ConnectionSpec, LanePair, StopLineSpec, ConflictSpec};
+use traffic::scenario::{DetectorSpec, ControllerSpec};
+
+const STEP_DELTA: f32 = 0.1;
+
+/// Vehicles per kilometre of lane when the vehicles are first placed.
+const DENSITIES: &[f32] = &[10.0, 30.0, 60.0];
+
+/// Steps taken before measuring, so that queues and lane changes are under way.
+const WARMUP_STEPS: usize = 100;
+
+/// The most steps measured before the simulation is reset, so vehicles leaving the network
+/// barely change the density.
+const RUN_STEPS: u64 = 100;
+
+/// Reads the time spent in a phase from a profile.
+type PhaseTime = fn(&StepProfile) -> Duration;
+
+/// The phases of a step which are measured, as reported by `Simulation::get_profile`.
+const PHASES: &[(&str, PhaseTime)] = &[
+	("step", |p| p.total()),
+	("lane_decisions", |p| p.lane_decisions),
+	("update_obstacles", |p| p.update_obstacles),
+	("car_following", |p| p.car_following),
+	("stop_lines", |p| p.stop_lines),
+	("integration", |p| p.integration),
+	("meso", |p| p.meso),
+	("other", |p| p.other)
+];
+
+/// A network, and where to place vehicles in it.
+struct Scenario {
+	network: NetworkSpec,
+	/// Each link vehicles are placed on, its number of lanes and their destination
+	sources: Vec<(usize, u8, usize)>
+}
+
+impl Scenario {
+	fn new() -> Self {
+		Self {
+			network: NetworkSpec::default(),
+			sources: vec![]
+		}
+	}
+
+	fn add_link(&mut self, length: f32, speed_limit: f32, lanes: u8) -> usize {
+		let id = self.network.links.len();
+		self.network.links.push(LinkSpec {
+			id,
+			length,
+			speed_limit,
+			lanes: (0..lanes).map(|l| LaneSpec { start_lat: 3.5 * l as f32, end_lat: 3.5 * l as f32 }).collect(),
+			model: None
+		});
+		id
+	}
+
+	fn connect(&mut self, src_link: usize, dst_link: usize, lanes: &[(u8, u8)]) {
+		self.network.connections.push(ConnectionSpec {
+			src_link,
+			dst_link,
+			lanes: lanes.iter().map(|&(from, to)| LanePair { from, to }).collect(),
+			offset: 0.0
+		});
+	}
+
+	fn add_stop_line(&mut self, link: usize, pos: f32, kind: StopLineType) -> usize {
+		let id = self.network.stop_lines.len();
+		self.network.stop_lines.push(StopLineSpec { id, link, lane: 0, pos, length: 0.0, kind });
+		id
+	}
+
+	fn add_conflict(&mut self, stop1: usize, stop2: usize, priority: i8, max_pos: f32) {
+		self.network.conflicts.push(ConflictSpec { stop1, stop2, priority, max_pos });
+	}
+
+	fn add_detector(&mut self, link: usize, pos: f32) -> usize {
+		let id = self.network.detectors.len();
+		self.network.detectors.push(DetectorSpec { id, link, lane: 0, pos, length: 2.0 });
+		id
+	}
+
+	/// Builds the network and places vehicles evenly along each source link, then steps it until
+	/// it has settled.
+	fn build(&self, density: f32) -> Simulation {
+		let mut sim = Simulation::new(STEP_DELTA);
+		self.network.add_to_simulation(&mut sim).unwrap();
+		let spacing = 1000.0 / density;
+		let mut user_id = 0;
+		for &(link, lanes, dst_link) in self.sources.iter() {
+			let length = self.network.links[link].length;
+			for lane in 0..lanes {
+				let mut pos = 0.5 * spacing;
+				while pos < length {
+					sim.insert_vehicle(user_id, link, lane, pos, dst_link).unwrap();
+					user_id += 1;
+					pos += spacing;
+				}
+			}
+		}
+		for _ in 0..WARMUP_STEPS {
+			sim.step();
+		}
+		sim
+	}
+}
+
+/// A three lane freeway, five kilometres long, which narrows to two lanes at the end.
+fn freeway() -> Scenario {
+	let mut scenario = Scenario::new();
+	let links = (0..10).map(|_| scenario.add_link(500.0, 30.0, 3)).collect::<Vec<_>>();
+	let end = scenario.add_link(500.0, 30.0, 2);
+	for pair in links.windows(2) {
+		scenario.connect(pair[0], pair[1], &[(0, 0), (1, 1), (2, 2)]);
+	}
+	scenario.connect(links[9], end, &[(1, 0), (2, 1)]);
+	scenario.sources = links.iter().map(|&link| (link, 3, end)).collect();
+	scenario
+}
+
+/// The freeway, with every link but the last simulated mesoscopically.
+fn freeway_meso() -> Scenario {
+	let mut scenario = freeway();
+	let last = scenario.network.links.len() - 1;
+	for link in scenario.network.links[..last].iter_mut() {
+		link.model = Some(LinkModel::Meso);
+	}
+	scenario
+}
+
+/// A grid of one way streets, four running east and four south, with actuated traffic lights at
+/// each of the sixteen intersections.
+fn grid() -> Scenario {
+	const SIZE: usize = 4;
+	let mut scenario = Scenario::new();
+	// The junction links of each street and the links leading to them, by the index of the
+	// street crossing it
+	let mut junctions = vec![];
+	for _street in 0..2 * SIZE {
+		let mut prev = scenario.add_link(100.0, 14.0, 1);
+		let mut street = vec![];
+		for _cross in 0..SIZE {
+			let junction = scenario.add_link(30.0, 14.0, 1);
+			let next = scenario.add_link(100.0, 14.0, 1);
+			scenario.connect(prev, junction, &[(0, 0)]);
+			scenario.connect(junction, next, &[(0, 0)]);
+			scenario.add_stop_line(junction, 5.0, StopLineType::TrafficLight { state: TrafficLightState::Red });
+			scenario.sources.push((prev, 1, 0));
+			street.push((junction, prev));
+			prev = next;
+		}
+		for source in scenario.sources.iter_mut().rev().take(SIZE) {
+			source.2 = prev;
+		}
+		junctions.push(street);
+	}
+	let stop_line = |scenario: &Scenario, link| scenario.network.stop_lines.iter().position(|s| s.link == link).unwrap();
+	for (east, street) in junctions[..SIZE].iter().enumerate() {
+		for (south, &(junction, approach)) in street.iter().enumerate() {
+			let (cross_junction, cross_approach) = junctions[SIZE + south][east];
+			let a = stop_line(&scenario, junction);
+			let b = stop_line(&scenario, cross_junction);
+			scenario.add_conflict(a, b, 0, 25.0);
+			scenario.add_conflict(b, a, 0, 25.0);
+			let phases = [(a, approach), (b, cross_approach)].iter().map(|&(stop_line, link)| Phase {
+				stop_lines: vec![stop_line],
+				crossings: vec![],
+				detectors: vec![scenario.add_detector(link, 60.0)],
+				min_green: 5.0,
+				max_green: 30.0,
+				extension: 3.0,
+				amber: 3.0,
+				all_red: 1.0,
+				recall: Recall::None
+			}).collect();
+			scenario.network.controllers.push(ControllerSpec::Actuated { phases });
+		}
+	}
+	scenario
+}
+
+/// A single lane roundabout with four arms, where entering vehicles give way to circulating ones.
+fn roundabout() -> Scenario {
+	const ARMS: usize = 4;
+	let mut scenario = Scenario::new();
+	let ring = (0..2 * ARMS).map(|_| scenario.add_link(25.0, 10.0, 1)).collect::<Vec<_>>();
+	for i in 0..ring.len() {
+		scenario.connect(ring[i], ring[(i + 1) % ring.len()], &[(0, 0)]);
+	}
+	let exits = (0..ARMS).map(|arm| {
+		let exit = scenario.add_link(200.0, 14.0, 1);
+		scenario.connect(ring[2 * arm + 1], exit, &[(0, 0)]);
+		exit
+	}).collect::<Vec<_>>();
+	for arm in 0..ARMS {
+		let entry = scenario.add_link(500.0, 14.0, 1);
+		let upstream = ring[(2 * arm + ring.len() - 1) % ring.len()];
+		scenario.connect(entry, ring[2 * arm], &[(0, 0)]);
+		let giveway = scenario.add_stop_line(entry, 495.0, StopLineType::Giveway);
+		let circulating = scenario.add_stop_line(upstream, 5.0, StopLineType::None);
+		scenario.add_conflict(giveway, circulating, -1, 25.0);
+		scenario.sources.push((entry, 1, exits[(arm + 2) % ARMS]));
+		scenario.sources.push((ring[2 * arm], 1, exits[(arm + 1) % ARMS]));
+	}
+	scenario
+}
+
+fn bench_network(c: &mut Criterion, name: &str, scenario: Scenario) {
+	for &density in DENSITIES {
+		let snapshot = scenario.build(density).to_snapshot_bytes().unwrap();
+		let mut group = c.benchmark_group(format!("{}/{}_veh_per_km", name, density));
+		group.sample_size(10)
+			.warm_up_time(Duration::from_secs(1))
+			.measurement_time(Duration::from_secs(3));
+		for &(phase, time) in PHASES {
+			group.bench_function(phase, |b| b.iter_custom(|iters| {
+				let mut total = Duration::from_secs(0);
+				let mut left = iters;
+				while left > 0 {
+					let mut sim = Simulation::from_snapshot_bytes(&snapshot).unwrap();
+					sim.set_profiling(true);
+					for _ in 0..left.min(RUN_STEPS) {
+						sim.step();
+					}
+					total += time(sim.get_profile().unwrap());
+					left -= left.min(RUN_STEPS);
+				}
+				total
+			}));
+		}
+		group.finish();
+	}
+}
+
+fn benches(c: &mut Criterion) {
+	bench_network(c, "freeway", freeway());
+	bench_network(c, "freeway_meso", freeway_meso());
+	bench_network(c, "grid", grid());
+	bench_network(c, "roundabout", roundabout());
+}
+
+criterion_group!(step, benches);
+criterion_main!(step);
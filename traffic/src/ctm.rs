@@ -0,0 +1,371 @@
+/*!
+ * A macroscopic cell transmission model (CTM) of a network, for calibrating the microscopic
+ * simulation against.
+ *
+ * Each link is split into cells which take at least a step to cross at its speed limit, and
+ * vehicles flow from cell to cell as allowed by the link's fundamental diagram. The model is built
+ * from the same network and departures as a [`Simulation`], and vehicles take the same routes.
+ * Flow, density and speed are measured on each link in the same way as can be done for the
+ * microscopic simulation with [`LinkTotals`], so the two can be compared directly.
+ * */
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use crate::scenario::{NetworkSpec, Departure};
+use crate::simulation::{Simulation, SimError};
+
+/// The properties of the vehicles which determine the fundamental diagram of each link.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VehicleClass {
+	pub len: f32,
+	/// The time gap kept to the vehicle ahead, in seconds
+	pub headway: f32,
+	/// The gap kept to the vehicle ahead when stopped, in metres
+	pub min_gap: f32
+}
+
+impl Default for VehicleClass {
+	/// The vehicles of the microscopic simulation.
+	fn default() -> Self {
+		Self {
+			len: 4.6,
+			headway: 2.0,
+			min_gap: 2.0
+		}
+	}
+}
+
+/// A triangular fundamental diagram, relating the flow of a link to its density.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FundamentalDiagram {
+	/// The speed of traffic below the critical density, in m/s
+	pub free_speed: f32,
+	/// The greatest flow, in vehicles per second
+	pub capacity: f32,
+	/// The density at which traffic stops, in vehicles per metre
+	pub jam_density: f32,
+	/// The speed at which congestion travels back up the link, in m/s
+	pub wave_speed: f32
+}
+
+impl FundamentalDiagram {
+	/// Derives the diagram of a link from its speed limit and number of lanes, assuming the
+	/// vehicles keep their headway at capacity and their minimum gap when jammed.
+	pub fn new(speed_limit: f32, lanes: usize, class: &VehicleClass) -> Self {
+		let lanes = lanes.max(1) as f32;
+		let spacing = class.len + class.min_gap;
+		let jam_density = lanes / spacing;
+		let capacity = lanes * speed_limit / (speed_limit * class.headway + spacing);
+		let critical_density = capacity / speed_limit;
+		Self {
+			free_speed: speed_limit,
+			capacity,
+			jam_density,
+			wave_speed: capacity / (jam_density - critical_density)
+		}
+	}
+}
+
+/**
+ * The distance travelled and time spent by vehicles on a link over an interval, from which its
+ * flow, density and speed follow by Edie's generalised definitions.
+ *
+ * These can be accumulated from the vehicles of a [`Simulation`] each step, to compare with the
+ * totals of a [`Ctm`] over the same interval.
+ * */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkTotals {
+	/// In vehicle metres
+	pub distance: f32,
+	/// In vehicle seconds
+	pub time: f32
+}
+
+impl LinkTotals {
+	/// Adds a vehicle travelling at `vel` for `delta` seconds.
+	pub fn add_vehicle(&mut self, vel: f32, delta: f32) {
+		self.distance += vel * delta;
+		self.time += delta;
+	}
+
+	/// The mean flow over an interval of `duration` seconds, in vehicles per hour.
+	pub fn flow(&self, length: f32, duration: f32) -> f32 {
+		3600.0 * self.distance / (length * duration)
+	}
+
+	/// The mean density over an interval of `duration` seconds, in vehicles per kilometre.
+	pub fn density(&self, length: f32, duration: f32) -> f32 {
+		1000.0 * self.time / (length * duration)
+	}
+
+	/// The space mean speed, in m/s, or 0 if no vehicles were on the link.
+	pub fn speed(&self) -> f32 {
+		if self.time > 0.0 { self.distance / self.time } else { 0.0 }
+	}
+}
+
+/**
+ * A cell transmission model of a network, loaded with departures.
+ *
+ * The vehicles on each link are tracked by destination so that they can be sent the right way
+ * at each junction, but are assumed to be evenly mixed along the link rather than kept in order.
+ * Where several links feed one, they share its space in proportion to how many vehicles they are
+ * sending, and a link sends no more to any of its successors than the most congested allows.
+ * */
+pub struct Ctm {
+	step: usize,
+	step_delta: f32,
+	links: Vec<CtmLink>,
+	/// The index of each link, by id
+	link_index: HashMap<usize, usize>,
+	/// The link after each link on the way to each destination, by destination then link index,
+	/// or `None` off the routes to the destination
+	next_links: Vec<Vec<Option<usize>>>,
+	/// The step each departure is due, its first link and its destination, in order
+	pending: VecDeque<(usize, usize, usize)>,
+	arrived: f32
+}
+
+struct CtmLink {
+	id: usize,
+	length: f32,
+	fd: FundamentalDiagram,
+	cell_len: f32,
+	/// The number of vehicles in each cell
+	cells: Vec<f32>,
+	/// The number of vehicles on the link headed for each destination
+	dsts: Vec<f32>,
+	/// The number of vehicles waiting to enter the link headed for each destination
+	waiting: Vec<f32>,
+	totals: LinkTotals
+}
+
+impl CtmLink {
+	fn num_vehicles(&self) -> f32 {
+		self.cells.iter().sum()
+	}
+
+	/// The most vehicles which can leave a cell this step.
+	fn sending(&self, cell: usize, delta: f32) -> f32 {
+		let n = self.cells[cell];
+		let free = n * self.fd.free_speed * delta / self.cell_len;
+		free.min(self.fd.capacity * delta).min(n)
+	}
+
+	/// The most vehicles which can enter a cell this step.
+	fn receiving(&self, cell: usize, delta: f32) -> f32 {
+		let space = (self.fd.jam_density * self.cell_len - self.cells[cell]).max(0.0);
+		let congested = space * self.fd.wave_speed * delta / self.cell_len;
+		congested.min(self.fd.capacity * delta).min(space)
+	}
+}
+
+impl Ctm {
+	/// Builds the model of a network, routing each departure as the microscopic simulation would.
+	/// The departures must have been expanded with the given step length.
+	pub fn new(network: &NetworkSpec, departures: &[Departure], class: VehicleClass, step_delta: f32) -> Result<Self, SimError> {
+		let mut sim = Simulation::new(step_delta);
+		network.add_to_simulation(&mut sim)?;
+
+		let link_index = network.links.iter()
+			.enumerate()
+			.map(|(i, link)| (link.id, i))
+			.collect::<HashMap<_, _>>();
+		let mut dst_index = HashMap::new();
+		let mut next_links: Vec<Vec<Option<usize>>> = vec![];
+		let mut routed = HashSet::new();
+		let mut pending = VecDeque::new();
+		for dep in departures.iter() {
+			let dst = *dst_index.entry(dep.dst_link).or_insert_with(|| {
+				next_links.push(vec![None; network.links.len()]);
+				next_links.len() - 1
+			});
+			if routed.insert((dep.src_link, dep.dst_link)) {
+				let route = sim.find_route(dep.src_link, dep.dst_link)?;
+				for pair in route.windows(2) {
+					next_links[dst][link_index[&pair[0]]] = Some(link_index[&pair[1]]);
+				}
+			}
+			pending.push_back((dep.step, link_index[&dep.src_link], dst));
+		}
+
+		let links = network.links.iter().map(|link| {
+			let fd = FundamentalDiagram::new(link.speed_limit, link.lanes.len(), &class);
+			let num_cells = ((link.length / (fd.free_speed * step_delta)).floor() as usize).max(1);
+			CtmLink {
+				id: link.id,
+				length: link.length,
+				fd,
+				cell_len: link.length / num_cells as f32,
+				cells: vec![0.0; num_cells],
+				dsts: vec![0.0; next_links.len()],
+				waiting: vec![0.0; next_links.len()],
+				totals: LinkTotals::default()
+			}
+		}).collect();
+
+		Ok(Self {
+			step: 0,
+			step_delta,
+			links,
+			link_index,
+			next_links,
+			pending,
+			arrived: 0.0
+		})
+	}
+
+	pub fn get_step(&self) -> usize {
+		self.step
+	}
+
+	pub fn get_step_delta(&self) -> f32 {
+		self.step_delta
+	}
+
+	pub fn get_fundamental_diagram(&self, link: usize) -> Option<FundamentalDiagram> {
+		self.link_index.get(&link).map(|&i| self.links[i].fd)
+	}
+
+	pub fn get_link_length(&self, link: usize) -> Option<f32> {
+		self.link_index.get(&link).map(|&i| self.links[i].length)
+	}
+
+	/// The number of vehicles on the links, which need not be whole.
+	pub fn num_vehicles(&self) -> f32 {
+		self.links.iter().map(|l| l.num_vehicles()).sum()
+	}
+
+	/// The number of vehicles waiting to enter the network, whether or not they are due.
+	pub fn num_waiting(&self) -> f32 {
+		self.pending.len() as f32 + self.links.iter().map(|l| l.waiting.iter().sum::<f32>()).sum::<f32>()
+	}
+
+	/// The number of vehicles which have reached their destinations.
+	pub fn num_arrived(&self) -> f32 {
+		self.arrived
+	}
+
+	/// Returns the totals of each link since they were last taken, and starts them again.
+	pub fn take_link_totals(&mut self) -> Vec<(usize, LinkTotals)> {
+		self.links.iter_mut()
+			.map(|l| (l.id, std::mem::take(&mut l.totals)))
+			.collect()
+	}
+
+	pub fn step(&mut self) {
+		let delta = self.step_delta;
+
+		// Departures join the queue to enter their first link
+		while self.pending.front().map(|d| d.0 <= self.step).unwrap_or(false) {
+			let (_, link, dst) = self.pending.pop_front().unwrap();
+			self.links[link].waiting[dst] += 1.0;
+		}
+
+		// Flows between the cells of each link
+		let mut outflows = self.links.iter().map(|link| {
+			let n = link.cells.len();
+			(0..n).map(|i| match i + 1 < n {
+				true => link.sending(i, delta).min(link.receiving(i + 1, delta)),
+				false => 0.0
+			}).collect::<Vec<_>>()
+		}).collect::<Vec<_>>();
+
+		// The share of the vehicles leaving each link bound for each successor, or `None` for
+		// those which have arrived
+		let shares = self.links.iter().enumerate().map(|(i, link)| {
+			let total = link.dsts.iter().sum::<f32>();
+			let mut shares: Vec<(Option<usize>, f32)> = vec![];
+			if total <= 0.0 {
+				return shares;
+			}
+			for (dst, &count) in link.dsts.iter().enumerate() {
+				if count <= 0.0 {
+					continue;
+				}
+				let next = self.next_links[dst][i];
+				match shares.iter_mut().find(|(n, _)| *n == next) {
+					Some(share) => share.1 += count / total,
+					None => shares.push((next, count / total))
+				}
+			}
+			shares
+		}).collect::<Vec<_>>();
+
+		// Junctions: each successor admits vehicles in proportion to what is sent to it, and a
+		// link sends only as much as its most restrictive successor admits
+		let mut demand = vec![0.0; self.links.len()];
+		for (i, link) in self.links.iter().enumerate() {
+			let sending = link.sending(link.cells.len() - 1, delta);
+			for &(next, share) in shares[i].iter() {
+				if let Some(next) = next {
+					demand[next] += share * sending;
+				}
+			}
+		}
+		let admitted = self.links.iter().zip(demand.iter()).map(|(link, &demand)| {
+			if demand <= 0.0 { 1.0 } else { (link.receiving(0, delta) / demand).min(1.0) }
+		}).collect::<Vec<_>>();
+		let mut entering = vec![0.0; self.links.len()];
+		let mut transfers = vec![];
+		for (i, link) in self.links.iter().enumerate() {
+			let ratio = shares[i].iter()
+				.filter_map(|&(next, _)| next.map(|next| admitted[next]))
+				.fold(1.0, f32::min);
+			let flow = ratio * link.sending(link.cells.len() - 1, delta);
+			*outflows[i].last_mut().unwrap() = flow;
+			if flow <= 0.0 {
+				continue;
+			}
+			let total = link.dsts.iter().sum::<f32>();
+			for (dst, &count) in link.dsts.iter().enumerate() {
+				if count > 0.0 {
+					transfers.push((i, self.next_links[dst][i], dst, flow * count / total));
+				}
+			}
+			for &(next, share) in shares[i].iter() {
+				if let Some(next) = next {
+					entering[next] += flow * share;
+				}
+			}
+		}
+
+		// Move the vehicles, recording the distance they travel and the time they spend
+		for (link, outflows) in self.links.iter_mut().zip(outflows.iter()) {
+			for (cell, &flow) in outflows.iter().enumerate() {
+				link.totals.distance += flow * link.cell_len;
+				link.totals.time += link.cells[cell] * delta;
+				link.cells[cell] -= flow;
+				if let Some(next) = link.cells.get_mut(cell + 1) {
+					*next += flow;
+				}
+			}
+		}
+		for (link, next, dst, flow) in transfers {
+			self.links[link].dsts[dst] -= flow;
+			match next {
+				Some(next) => self.links[next].dsts[dst] += flow,
+				None => self.arrived += flow
+			}
+		}
+		for (link, entering) in self.links.iter_mut().zip(entering.iter()) {
+			link.cells[0] += entering;
+		}
+
+		// Waiting vehicles enter their first links in what space is left
+		for link in self.links.iter_mut() {
+			let waiting = link.waiting.iter().sum::<f32>();
+			if waiting <= 0.0 {
+				continue;
+			}
+			let flow = waiting.min(link.receiving(0, delta));
+			let fraction = flow / waiting;
+			for (dst, count) in link.waiting.iter_mut().enumerate() {
+				link.dsts[dst] += fraction * *count;
+				*count -= fraction * *count;
+			}
+			link.cells[0] += flow;
+		}
+
+		self.step += 1;
+	}
+}
@@ -0,0 +1,21 @@
+/*!
+ * A microscopic traffic simulation engine.
+ *
+ * A [`Simulation`] is built up from links, lanes, connections between links and stop lines,
+ * after which vehicles can be added and the simulation advanced one step at a time.
+ * */
+
+mod util;
+mod simulation;
+pub mod scenario;
+pub mod record;
+pub mod ctm;
+
+pub use util::{LinearFunc, CubicFunc};
+pub use simulation::{Simulation, SimError, StopLineBuilder, StopLineType, TrafficLightState, VehicleState, VehicleId};
+pub use simulation::{Event, EventKind, GridlockConfig, Recovery};
+pub use simulation::{SafetyConfig, SafetyStats, PairMeasure};
+pub use simulation::{LaneClosure, RoutePenalty, StepProfile, LinkModel, MesoConfig};
+pub use simulation::{Detector, DetectorReading, Controller, ActuatedController, Phase, Recall};
+pub use simulation::{CoordinatedController, TimedPhase, SavedController, Corridor};
+pub use simulation::{CrossingBuilder, CrossingType, PedestrianState};
@@ -0,0 +1,218 @@
+/*!
+ * Recording of the changes made to a simulation from outside, so that a run can be replayed.
+ *
+ * The simulation is deterministic, so a run is fully described by the changes made to it and
+ * the steps at which they were made. A [`Recording`] also keeps a digest of every frame, against
+ * which a replay is checked.
+ * */
+
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::simulation::{Simulation, SimError, GridlockConfig};
+use crate::scenario::{LinkSpec, ConnectionSpec, StopLineSpec, ConflictSpec, CrossingSpec, ClosureSpec, DetectorSpec, ControllerSpec};
+
+/// A change made to a simulation from outside.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+	Link(LinkSpec),
+	Connection(ConnectionSpec),
+	StopLine(StopLineSpec),
+	Conflict(ConflictSpec),
+	Crossing(CrossingSpec),
+	Vehicle {
+		user_id: usize,
+		src_link: usize,
+		dst_link: usize,
+		lane: u8,
+		pos: f32
+	},
+	RemoveVehicle {
+		user_id: usize
+	},
+	MoveVehicle {
+		user_id: usize,
+		link: usize,
+		lane: u8,
+		pos: f32
+	},
+	RouteVehicle {
+		user_id: usize,
+		dst_link: usize
+	},
+	SetVehicleSpeed {
+		user_id: usize,
+		max_speed: Option<f32>
+	},
+	Gridlock(GridlockConfig),
+	Closure(ClosureSpec),
+	RemoveClosure {
+		id: usize
+	},
+	Detector(DetectorSpec),
+	Controller(ControllerSpec),
+	Pedestrian {
+		user_id: usize,
+		crossing: usize,
+		speed: f32,
+		reverse: bool
+	}
+}
+
+impl Action {
+	pub fn apply(&self, sim: &mut Simulation) -> Result<(), SimError> {
+		match self {
+			Action::Link(link) => link.add_to_simulation(sim),
+			Action::Connection(conn) => conn.add_to_simulation(sim),
+			Action::StopLine(stop) => stop.add_to_simulation(sim),
+			Action::Conflict(conflict) => conflict.add_to_simulation(sim),
+			Action::Crossing(crossing) => crossing.add_to_simulation(sim),
+			Action::Vehicle { user_id, src_link, dst_link, lane, pos } => {
+				sim.insert_vehicle(*user_id, *src_link, *lane, *pos, *dst_link).map(|_| ())
+			}
+			Action::RemoveVehicle { user_id } => {
+				let id = sim.find_vehicle(*user_id).ok_or(SimError::UnknownVehicle(*user_id))?;
+				sim.remove_vehicle(id)
+			}
+			Action::MoveVehicle { user_id, link, lane, pos } => {
+				let id = sim.find_vehicle(*user_id).ok_or(SimError::UnknownVehicle(*user_id))?;
+				sim.relocate_vehicle(id, *link, *lane, *pos)
+			}
+			Action::RouteVehicle { user_id, dst_link } => {
+				let id = sim.find_vehicle(*user_id).ok_or(SimError::UnknownVehicle(*user_id))?;
+				sim.set_vehicle_dest(id, *dst_link)
+			}
+			Action::SetVehicleSpeed { user_id, max_speed } => {
+				let id = sim.find_vehicle(*user_id).ok_or(SimError::UnknownVehicle(*user_id))?;
+				sim.set_vehicle_max_speed(id, *max_speed)
+			}
+			Action::Gridlock(config) => {
+				sim.set_gridlock_config(*config);
+				Ok(())
+			}
+			Action::Closure(closure) => closure.add_to_simulation(sim),
+			Action::RemoveClosure { id } => sim.remove_closure(*id),
+			Action::Detector(detector) => detector.add_to_simulation(sim),
+			Action::Controller(controller) => controller.add_to_simulation(sim),
+			Action::Pedestrian { user_id, crossing, speed, reverse } => {
+				sim.add_pedestrian(*user_id, *crossing, *speed, *reverse)
+			}
+		}
+	}
+}
+
+/// An action, along with the step it was applied before.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Entry {
+	pub step: usize,
+	pub action: Action
+}
+
+/**
+ * A log of every action applied to a simulation, and of every frame it produced.
+ *
+ * Actions must be applied, and steps taken, through the recording for it to stay complete.
+ * */
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Recording {
+	pub step_delta: f32,
+	pub entries: Vec<Entry>,
+	/// The digest of each frame, starting with the frame after the first step
+	pub digests: Vec<u64>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplayError {
+	/// An action which succeeded when recorded failed on replay
+	Action { index: usize, step: usize, error: SimError },
+	/// A frame differed from the recorded one
+	Diverged { step: usize, expected: u64, actual: u64 }
+}
+
+impl fmt::Display for ReplayError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ReplayError::Action { index, step, error } =>
+				write!(f, "Action {} failed at step {}: {}", index, step, error),
+			ReplayError::Diverged { step, expected, actual } =>
+				write!(f, "Frame {} differs from the recording (digest {:016x}, expected {:016x})", step, actual, expected)
+		}
+	}
+}
+
+impl std::error::Error for ReplayError {}
+
+impl Recording {
+	/// Starts recording a new simulation, which should not have been changed or stepped yet.
+	pub fn new(step_delta: f32) -> Self {
+		Self {
+			step_delta,
+			entries: vec![],
+			digests: vec![]
+		}
+	}
+
+	/// Applies an action to the simulation, recording it if it succeeds.
+	pub fn apply(&mut self, sim: &mut Simulation, action: Action) -> Result<(), SimError> {
+		action.apply(sim)?;
+		self.entries.push(Entry { step: sim.get_step(), action });
+		Ok(())
+	}
+
+	/// Advances the simulation by one step, recording the frame it produces.
+	pub fn step(&mut self, sim: &mut Simulation) {
+		sim.step();
+		self.digests.push(frame_digest(sim));
+	}
+
+	/// The number of steps recorded.
+	pub fn num_steps(&self) -> usize {
+		self.digests.len()
+	}
+
+	/// Re-runs the recording from scratch, checking every frame against the recorded digests.
+	/// Returns the simulation as it was at the end of the recording.
+	pub fn replay(&self) -> Result<Simulation, ReplayError> {
+		let mut sim = Simulation::new(self.step_delta);
+		let mut entries = self.entries.iter().enumerate().peekable();
+		loop {
+			while let Some((index, entry)) = entries.next_if(|(_, e)| e.step <= sim.get_step()) {
+				entry.action.apply(&mut sim).map_err(|error| ReplayError::Action { index, step: entry.step, error })?;
+			}
+			let step = sim.get_step();
+			let expected = match self.digests.get(step) {
+				Some(digest) => *digest,
+				None => break
+			};
+			sim.step();
+			let actual = frame_digest(&sim);
+			if actual != expected {
+				return Err(ReplayError::Diverged { step: step + 1, expected, actual });
+			}
+		}
+		Ok(sim)
+	}
+}
+
+/// A hash of the step and the exact state of every vehicle, for comparing frames.
+pub fn frame_digest(sim: &Simulation) -> u64 {
+	// FNV-1a, which unlike `DefaultHasher` is guaranteed to be stable
+	let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+	let mut write = |x: u64| {
+		for byte in x.to_le_bytes().iter() {
+			hash ^= *byte as u64;
+			hash = hash.wrapping_mul(0x0100_0000_01b3);
+		}
+	};
+	write(sim.get_step() as u64);
+	for veh in sim.get_vehicle_states() {
+		write(veh.user_id as u64);
+		write(veh.link as u64);
+		write(veh.lane as u64);
+		write(veh.pos.to_bits() as u64);
+		write(veh.vel.to_bits() as u64);
+		write(veh.lat.to_bits() as u64);
+		write(veh.dlat.to_bits() as u64);
+	}
+	hash
+}
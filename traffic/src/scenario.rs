@@ -0,0 +1,497 @@
+/*!
+ * Serialisable descriptions of road networks and travel demand.
+ *
+ * These are used to load scenarios from files, rather than building them up one call at a time.
+ * */
+
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use serde::{Deserialize, Deserializer, Serialize};
+use crate::simulation::{Simulation, SimError, StopLineBuilder, StopLineType, LaneClosure, RoutePenalty, LinkModel};
+use crate::simulation::{Detector, ActuatedController, Phase, CoordinatedController, TimedPhase, CrossingBuilder, CrossingType};
+use crate::util::{LinearFunc, CubicFunc, Rng};
+
+/// The length assumed when checking whether there is space to insert a vehicle.
+const INSERT_LEN: f32 = 4.6;
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkSpec {
+	pub links: Vec<LinkSpec>,
+	pub connections: Vec<ConnectionSpec>,
+	pub stop_lines: Vec<StopLineSpec>,
+	pub conflicts: Vec<ConflictSpec>,
+	pub crossings: Vec<CrossingSpec>,
+	pub closures: Vec<ClosureSpec>,
+	pub detectors: Vec<DetectorSpec>,
+	pub controllers: Vec<ControllerSpec>
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LinkSpec {
+	pub id: usize,
+	pub length: f32,
+	pub speed_limit: f32,
+	pub lanes: Vec<LaneSpec>,
+	/// Microscopic unless given
+	#[serde(default)]
+	pub model: Option<LinkModel>
+}
+
+/// A lane which moves smoothly from one lateral offset to another along the length of its link.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct LaneSpec {
+	pub start_lat: f32,
+	pub end_lat: f32
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ConnectionSpec {
+	pub src_link: usize,
+	pub dst_link: usize,
+	pub lanes: Vec<LanePair>,
+	#[serde(default)]
+	pub offset: f32
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct LanePair {
+	pub from: u8,
+	pub to: u8
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StopLineSpec {
+	pub id: usize,
+	pub link: usize,
+	pub lane: u8,
+	pub pos: f32,
+	pub length: f32,
+	#[serde(deserialize_with = "deserialize_kind", serialize_with = "serialize_kind")]
+	pub kind: StopLineType
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ConflictSpec {
+	pub stop1: usize,
+	pub stop2: usize,
+	pub priority: i8,
+	pub max_pos: f32
+}
+
+/// A pedestrian crossing `length` metres long, over the lanes of the stop lines it conflicts with.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CrossingSpec {
+	pub id: usize,
+	pub length: f32,
+	#[serde(default)]
+	pub kind: CrossingType,
+	pub conflicts: Vec<CrossingConflictSpec>
+}
+
+/// A stop line whose vehicles must be past `max_pos` before pedestrians can start across.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct CrossingConflictSpec {
+	pub stop_line: usize,
+	pub max_pos: f32
+}
+
+/// A lane closed from `start_pos` to `end_pos`, from time `from` until `until`, in seconds.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ClosureSpec {
+	pub id: usize,
+	pub link: usize,
+	pub lane: u8,
+	pub start_pos: f32,
+	pub end_pos: f32,
+	#[serde(default)]
+	pub from: f32,
+	#[serde(default)]
+	pub until: Option<f32>,
+	#[serde(default)]
+	pub routing: RoutePenalty
+}
+
+/// A detector covering `length` metres of a lane from `pos`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DetectorSpec {
+	pub id: usize,
+	pub link: usize,
+	pub lane: u8,
+	pub pos: f32,
+	#[serde(default = "default_detector_length")]
+	pub length: f32
+}
+
+fn default_detector_length() -> f32 {
+	2.0
+}
+
+/// A controller of a set of traffic lights.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControllerSpec {
+	Actuated {
+		phases: Vec<Phase>
+	},
+	Coordinated {
+		cycle: f32,
+		#[serde(default)]
+		offset: f32,
+		phases: Vec<TimedPhase>
+	}
+}
+
+impl NetworkSpec {
+	pub fn add_to_simulation(&self, sim: &mut Simulation) -> Result<(), SimError> {
+		for link in self.links.iter() {
+			link.add_to_simulation(sim)?;
+		}
+		for conn in self.connections.iter() {
+			conn.add_to_simulation(sim)?;
+		}
+		for stop in self.stop_lines.iter() {
+			stop.add_to_simulation(sim)?;
+		}
+		for conflict in self.conflicts.iter() {
+			conflict.add_to_simulation(sim)?;
+		}
+		for crossing in self.crossings.iter() {
+			crossing.add_to_simulation(sim)?;
+		}
+		for closure in self.closures.iter() {
+			closure.add_to_simulation(sim)?;
+		}
+		for detector in self.detectors.iter() {
+			detector.add_to_simulation(sim)?;
+		}
+		for controller in self.controllers.iter() {
+			controller.add_to_simulation(sim)?;
+		}
+		Ok(())
+	}
+}
+
+impl LinkSpec {
+	/// Adds the link with its lanes and model, or nothing at all if any of them is invalid.
+	pub fn add_to_simulation(&self, sim: &mut Simulation) -> Result<(), SimError> {
+		// Checked before the link is added, as adding its lanes would be the first to fail
+		if self.lanes.len() >= u8::MAX as usize {
+			return Err(SimError::InvalidGeometry(format!("Link {} has too many lanes", self.id)));
+		}
+		sim.add_link(self.id, self.length, self.speed_limit)?;
+		for lane in self.lanes.iter() {
+			// todo: curvature
+			// todo: more complex lateral curves
+			let dist_func = LinearFunc::from_points(&[(0.0, 0.0), (self.length, self.length)]);
+			let lat_func = CubicFunc::from_points(&[(0.0, lane.start_lat), (self.length, lane.end_lat)]);
+			sim.add_lane(self.id, dist_func, lat_func)?;
+		}
+		if let Some(model) = self.model {
+			sim.set_link_model(self.id, model)?;
+		}
+		Ok(())
+	}
+}
+
+impl ConnectionSpec {
+	pub fn add_to_simulation(&self, sim: &mut Simulation) -> Result<(), SimError> {
+		let lanes = self.lanes.iter().map(|p| (p.from, p.to)).collect::<Vec<_>>();
+		sim.add_connection(self.src_link, self.dst_link, &lanes, self.offset)
+	}
+}
+
+impl StopLineSpec {
+	pub fn add_to_simulation(&self, sim: &mut Simulation) -> Result<(), SimError> {
+		StopLineBuilder::new(self.id, self.link, self.lane, self.pos)
+			.with_length(self.length)
+			.of_type(self.kind)
+			.add_to_simulation(sim)
+	}
+}
+
+impl ConflictSpec {
+	pub fn add_to_simulation(&self, sim: &mut Simulation) -> Result<(), SimError> {
+		sim.add_conflict(self.stop1, self.stop2, self.priority.cmp(&0), self.max_pos)
+	}
+}
+
+impl CrossingSpec {
+	pub fn add_to_simulation(&self, sim: &mut Simulation) -> Result<(), SimError> {
+		let mut builder = CrossingBuilder::new(self.id, self.length).of_type(self.kind);
+		for conflict in self.conflicts.iter() {
+			builder = builder.conflicts_with(conflict.stop_line, conflict.max_pos);
+		}
+		builder.add_to_simulation(sim)
+	}
+}
+
+impl ClosureSpec {
+	pub fn add_to_simulation(&self, sim: &mut Simulation) -> Result<(), SimError> {
+		sim.add_closure(self.id, LaneClosure {
+			link: self.link,
+			lane: self.lane,
+			start_pos: self.start_pos,
+			end_pos: self.end_pos,
+			from: self.from,
+			until: self.until,
+			routing: self.routing
+		})
+	}
+}
+
+impl DetectorSpec {
+	pub fn add_to_simulation(&self, sim: &mut Simulation) -> Result<(), SimError> {
+		sim.add_detector(self.id, Detector {
+			link: self.link,
+			lane: self.lane,
+			pos: self.pos,
+			len: self.length
+		})
+	}
+}
+
+impl ControllerSpec {
+	pub fn add_to_simulation(&self, sim: &mut Simulation) -> Result<(), SimError> {
+		match self {
+			ControllerSpec::Actuated { phases } => {
+				sim.add_controller(Box::new(ActuatedController::new(phases.clone())?))
+			}
+			ControllerSpec::Coordinated { cycle, offset, phases } => {
+				sim.add_controller(Box::new(CoordinatedController::new(*cycle, *offset, phases.clone())?))
+			}
+		}
+	}
+}
+
+fn deserialize_kind<'de, D>(deserializer: D) -> Result<StopLineType, D::Error> where D: Deserializer<'de> {
+	let s = String::deserialize(deserializer)?;
+	s.parse().map_err(|_| serde::de::Error::custom(format!("unknown stop line type \"{}\"", s)))
+}
+
+fn serialize_kind<S>(kind: &StopLineType, serializer: S) -> Result<S::Ok, S::Error> where S: serde::Serializer {
+	let s = match kind {
+		StopLineType::None => "none",
+		StopLineType::Giveway => "giveway",
+		StopLineType::Stop => "stop",
+		StopLineType::Zebra => "zebra",
+		StopLineType::TrafficLight { .. } => "light"
+	};
+	serializer.serialize_str(s)
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DemandSpec {
+	pub trips: Vec<TripSpec>,
+	pub flows: Vec<FlowSpec>,
+	pub pedestrian_flows: Vec<PedestrianFlowSpec>
+}
+
+/// A single vehicle departing at a given time, in seconds.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TripSpec {
+	pub id: usize,
+	pub depart: f32,
+	pub src_link: usize,
+	pub dst_link: usize,
+	#[serde(default)]
+	pub lane: u8,
+	#[serde(default)]
+	pub pos: f32
+}
+
+/// A stream of vehicles with random (Poisson) arrivals at the given rate, in vehicles per hour.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FlowSpec {
+	pub src_link: usize,
+	pub dst_link: usize,
+	#[serde(default)]
+	pub lane: u8,
+	#[serde(default)]
+	pub pos: f32,
+	pub rate: f32,
+	#[serde(default)]
+	pub start: f32,
+	pub end: f32
+}
+
+/// A stream of pedestrians with random (Poisson) arrivals at the given rate, in pedestrians per
+/// hour, split evenly between the two directions across a crossing.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PedestrianFlowSpec {
+	pub crossing: usize,
+	pub rate: f32,
+	#[serde(default)]
+	pub start: f32,
+	pub end: f32,
+	/// In m/s
+	#[serde(default = "default_walking_speed")]
+	pub speed: f32
+}
+
+fn default_walking_speed() -> f32 {
+	1.4
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Departure {
+	pub step: usize,
+	pub user_id: usize,
+	pub src_link: usize,
+	pub dst_link: usize,
+	pub lane: u8,
+	pub pos: f32
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PedestrianDeparture {
+	pub step: usize,
+	pub user_id: usize,
+	pub crossing: usize,
+	pub speed: f32,
+	pub reverse: bool
+}
+
+impl DemandSpec {
+	/// Expands the trips and flows into a list of departures ordered by step.
+	/// Vehicles generated by flows are numbered after the highest trip id.
+	pub fn departures(&self, step_delta: f32, seed: u64) -> Vec<Departure> {
+		let to_step = |time: f32| (time / step_delta).round().max(0.0) as usize;
+		let mut deps = self.trips.iter().map(|t| Departure {
+			step: to_step(t.depart),
+			user_id: t.id,
+			src_link: t.src_link,
+			dst_link: t.dst_link,
+			lane: t.lane,
+			pos: t.pos
+		}).collect::<Vec<_>>();
+
+		let mut rng = Rng::new(seed);
+		let mut flow_deps = vec![];
+		for flow in self.flows.iter() {
+			if flow.rate <= 0.0 {
+				continue;
+			}
+			let headway = 3600.0 / flow.rate;
+			let mut time = flow.start + rng.next_exp(headway);
+			while time < flow.end {
+				flow_deps.push((time, Departure {
+					step: to_step(time),
+					user_id: 0,
+					src_link: flow.src_link,
+					dst_link: flow.dst_link,
+					lane: flow.lane,
+					pos: flow.pos
+				}));
+				time += rng.next_exp(headway);
+			}
+		}
+		flow_deps.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+		let first_id = self.trips.iter().map(|t| t.id + 1).max().unwrap_or(0);
+		for (i, (_, mut dep)) in flow_deps.into_iter().enumerate() {
+			dep.user_id = first_id + i;
+			deps.push(dep);
+		}
+
+		deps.sort_by_key(|d| d.step);
+		deps
+	}
+
+	/// Expands the pedestrian flows into a list of departures ordered by step, numbered from 0.
+	pub fn pedestrian_departures(&self, step_delta: f32, seed: u64) -> Vec<PedestrianDeparture> {
+		let to_step = |time: f32| (time / step_delta).round().max(0.0) as usize;
+		// Drawn separately, so that adding pedestrians leaves the vehicles as they were
+		let mut rng = Rng::new(!seed);
+		let mut deps = vec![];
+		for flow in self.pedestrian_flows.iter() {
+			if flow.rate <= 0.0 {
+				continue;
+			}
+			let headway = 3600.0 / flow.rate;
+			let mut time = flow.start + rng.next_exp(headway);
+			while time < flow.end {
+				let reverse = rng.next_f32() < 0.5;
+				deps.push((time, PedestrianDeparture {
+					step: to_step(time),
+					user_id: 0,
+					crossing: flow.crossing,
+					speed: flow.speed,
+					reverse
+				}));
+				time += rng.next_exp(headway);
+			}
+		}
+		deps.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+		deps.into_iter().enumerate().map(|(i, (_, mut dep))| {
+			dep.user_id = i;
+			dep
+		}).collect()
+	}
+}
+
+/**
+ * Inserts departures into a simulation once they are due.
+ *
+ * A vehicle whose entry position is occupied waits until it is clear, as do any vehicles
+ * queued behind it at the same entry.
+ * */
+pub struct DemandQueue {
+	pending: VecDeque<Departure>,
+	waiting: Vec<Departure>,
+	inserted: usize,
+	pedestrians: VecDeque<PedestrianDeparture>
+}
+
+impl DemandQueue {
+	pub fn new(departures: Vec<Departure>) -> Self {
+		Self {
+			pending: departures.into(),
+			waiting: vec![],
+			inserted: 0,
+			pedestrians: VecDeque::new()
+		}
+	}
+
+	/// Also adds pedestrians to their crossings once they are due.
+	pub fn with_pedestrians(mut self, departures: Vec<PedestrianDeparture>) -> Self {
+		self.pedestrians = departures.into();
+		self
+	}
+
+	/// Inserts every due vehicle for which there is space.
+	pub fn release(&mut self, sim: &mut Simulation) -> Result<(), SimError> {
+		while self.pending.front().map(|d| d.step <= sim.get_step()).unwrap_or(false) {
+			self.waiting.push(self.pending.pop_front().unwrap());
+		}
+		let mut blocked: Vec<(usize, u8)> = vec![];
+		let mut still_waiting = vec![];
+		for dep in self.waiting.drain(..) {
+			let entry = (dep.src_link, dep.lane);
+			if blocked.contains(&entry) || !sim.is_space_clear(dep.src_link, dep.lane, dep.pos, INSERT_LEN) {
+				blocked.push(entry);
+				still_waiting.push(dep);
+				continue;
+			}
+			sim.insert_vehicle(dep.user_id, dep.src_link, dep.lane, dep.pos, dep.dst_link)?;
+			self.inserted += 1;
+		}
+		self.waiting = still_waiting;
+		// Pedestrians wait at the kerb, so never need space to be added
+		while self.pedestrians.front().map(|d| d.step <= sim.get_step()).unwrap_or(false) {
+			let dep = self.pedestrians.pop_front().unwrap();
+			sim.add_pedestrian(dep.user_id, dep.crossing, dep.speed, dep.reverse)?;
+		}
+		Ok(())
+	}
+
+	/// The number of vehicles inserted so far.
+	pub fn num_inserted(&self) -> usize {
+		self.inserted
+	}
+
+	/// Whether every departure has been inserted.
+	pub fn is_empty(&self) -> bool {
+		self.pending.is_empty() && self.waiting.is_empty() && self.pedestrians.is_empty()
+	}
+}
@@ -0,0 +1,150 @@
+use serde::{Serialize, Deserialize};
+use super::{Simulation, SimError};
+
+/// The extra distance used for links which routes should avoid, so that they are still used if
+/// there is no other way.
+const AVOID_PENALTY: f32 = 1.0e6;
+
+/// How routing should treat a link with a lane closure.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutePenalty {
+	#[default]
+	None,
+	/// Routes through the link are treated as this much longer, in metres
+	Distance(f32),
+	/// Routes only use the link if there is no other way
+	Avoid
+}
+
+impl RoutePenalty {
+	fn distance(self) -> f32 {
+		match self {
+			RoutePenalty::None => 0.0,
+			RoutePenalty::Distance(dist) => dist,
+			RoutePenalty::Avoid => AVOID_PENALTY
+		}
+	}
+}
+
+/**
+ * A lane blocked between two positions for a window of time, e.g. by a crash or roadworks.
+ *
+ * Vehicles approaching the closure change lanes to get past it, or stop at its start if they
+ * cannot. Vehicles in other lanes do not change into the lane until they are past its end.
+ * */
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LaneClosure {
+	pub link: usize,
+	pub lane: u8,
+	pub start_pos: f32,
+	pub end_pos: f32,
+	/// When the lane closes, in seconds since the simulation started
+	pub from: f32,
+	/// When the lane reopens, if ever
+	pub until: Option<f32>,
+	pub routing: RoutePenalty
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(super) struct Closure {
+	id: usize,
+	closure: LaneClosure,
+	active: bool
+}
+
+impl Simulation {
+	pub fn add_closure(&mut self, id: usize, closure: LaneClosure) -> Result<(), SimError> {
+		let link = self.links.get(closure.link).ok_or(SimError::UnknownLink(closure.link))?;
+		if closure.lane as usize >= link.lanes.len() {
+			return Err(SimError::UnknownLane { link: closure.link, lane: closure.lane });
+		}
+		if !(closure.start_pos >= 0.0 && closure.start_pos < closure.end_pos && closure.end_pos <= link.length) {
+			return Err(SimError::InvalidGeometry(format!("Closure must lie within the link, got {} to {}",
+				closure.start_pos, closure.end_pos)));
+		}
+		if !closure.from.is_finite() || closure.until.map(|t| t.is_nan() || t <= closure.from).unwrap_or(false) {
+			return Err(SimError::InvalidGeometry(format!("Closure must end after it starts at {}", closure.from)));
+		}
+		if let RoutePenalty::Distance(dist) = closure.routing {
+			if !dist.is_finite() || dist < 0.0 {
+				return Err(SimError::InvalidGeometry(format!("Route penalty must not be negative, got {}", dist)));
+			}
+		}
+		self.closures.insert(id, Closure { id, closure, active: false })
+			.map(|_| ())
+			.map_err(|e| SimError::from_insert(e, "closure", id))
+	}
+
+	/// Removes a closure, reopening the lane if it is closed.
+	pub fn remove_closure(&mut self, id: usize) -> Result<(), SimError> {
+		let closure = self.closures.remove(id).ok_or(SimError::UnknownClosure(id))?;
+		if closure.active {
+			let c = closure.closure;
+			self.links.get_mut(c.link).unwrap().remove_closure(c.lane, c.start_pos, c.end_pos);
+			self.closures_changed(c.routing != RoutePenalty::None);
+		}
+		Ok(())
+	}
+
+	/// Opens and closes lanes whose closures have started or ended by the current step.
+	pub(super) fn update_closures(&mut self) {
+		let time = self.step as f32 * self.step_delta;
+		let mut changed = false;
+		let mut rerouted = false;
+		let mut ended = vec![];
+		for closure in self.closures.iter_mut() {
+			let c = closure.closure;
+			let active = time >= c.from && c.until.map(|t| time < t).unwrap_or(true);
+			if active != closure.active {
+				let link = self.links.get_mut(c.link).unwrap();
+				if active {
+					link.add_closure(c.lane, c.start_pos, c.end_pos);
+				} else {
+					link.remove_closure(c.lane, c.start_pos, c.end_pos);
+				}
+				closure.active = active;
+				changed = true;
+				rerouted |= c.routing != RoutePenalty::None;
+			}
+			if !active && c.until.map(|t| time >= t).unwrap_or(false) {
+				ended.push(closure.id);
+			}
+		}
+		for id in ended {
+			self.closures.remove(id);
+		}
+		if changed {
+			self.closures_changed(rerouted);
+		}
+	}
+
+	/// Has vehicles reconsider their lanes, and if route penalties changed, their routes.
+	fn closures_changed(&mut self, rerouted: bool) {
+		for veh in self.vehs.iter_mut() {
+			veh.invalidate_lanes();
+		}
+		if !rerouted {
+			return;
+		}
+
+		for link in self.links.iter_mut() {
+			link.route_penalty = 0.0;
+		}
+		for closure in self.closures.iter().filter(|c| c.active) {
+			self.links.get_mut(closure.closure.link).unwrap().route_penalty += closure.closure.routing.distance();
+		}
+		self.route_table.clear();
+		let vehs = self.vehs.iter()
+			.filter_map(|v| Some((v.id, v.get_link(0)?, v.get_dest()?, v.get_links().to_vec())))
+			.collect::<Vec<_>>();
+		for (id, link, dst_link, old_route) in vehs {
+			// Vehicles keep their route if the destination can no longer be reached
+			if let Ok(route) = self.find_route(link, dst_link) {
+				if route != old_route {
+					self.vehs.get_mut(id).unwrap().reroute(route, &self.links);
+				}
+			}
+		}
+	}
+}
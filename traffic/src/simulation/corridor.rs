@@ -0,0 +1,114 @@
+use super::{Simulation, SimError, StopLineType};
+
+/**
+ * A run of connected links, such as an arterial road, along which traffic lights can be
+ * coordinated and trajectories plotted against distance.
+ *
+ * Distances along the corridor are measured from the start of its first link.
+ * */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Corridor {
+	links: Vec<usize>,
+	/// The distance to the start of each link
+	starts: Vec<f32>,
+	lengths: Vec<f32>,
+	speed_limits: Vec<f32>,
+	/// The traffic lights on the corridor and their distances, in order
+	signals: Vec<(usize, f32)>
+}
+
+impl Corridor {
+	pub fn get_links(&self) -> &[usize] {
+		&self.links
+	}
+
+	pub fn length(&self) -> f32 {
+		self.starts.last().unwrap() + self.lengths.last().unwrap()
+	}
+
+	/// The distance along the corridor of a position on one of its links.
+	pub fn distance(&self, link: usize, pos: f32) -> Option<f32> {
+		let i = self.links.iter().position(|&l| l == link)?;
+		Some(self.starts[i] + pos)
+	}
+
+	/// The traffic light stop lines on the corridor and their distances along it, in order.
+	pub fn get_signals(&self) -> &[(usize, f32)] {
+		&self.signals
+	}
+
+	/// The time taken to travel from the start of the corridor to `dist`, at the speed limits or
+	/// at `speed` if given, which must be positive.
+	pub fn travel_time(&self, dist: f32, speed: Option<f32>) -> Result<f32, SimError> {
+		if let Some(speed) = speed {
+			if !speed.is_finite() || speed <= 0.0 {
+				return Err(SimError::InvalidSpeed(speed));
+			}
+		}
+		let mut time = 0.0;
+		for i in 0..self.links.len() {
+			let len = (dist - self.starts[i]).clamp(0.0, self.lengths[i]);
+			time += len / speed.unwrap_or(self.speed_limits[i]);
+		}
+		Ok(time)
+	}
+
+	/// The offset of each traffic light on the corridor for a green wave, so that a vehicle which
+	/// passes the first at the start of its green reaches each of the others at the start of theirs.
+	///
+	/// Vehicles are assumed to travel at the speed limits, or at `speed` if given. Offsets are in
+	/// seconds from the start of the cycle, relative to the first traffic light. The cycle and
+	/// speed must be positive.
+	pub fn green_wave_offsets(&self, cycle: f32, speed: Option<f32>) -> Result<Vec<(usize, f32)>, SimError> {
+		if !cycle.is_finite() || cycle <= 0.0 {
+			return Err(SimError::InvalidController(format!("Cycle must be positive, got {}", cycle)));
+		}
+		let first = match self.signals.first() {
+			Some(&(_, dist)) => self.travel_time(dist, speed)?,
+			None => return Ok(vec![])
+		};
+		self.signals.iter()
+			.map(|&(id, dist)| Ok((id, (self.travel_time(dist, speed)? - first).rem_euclid(cycle))))
+			.collect()
+	}
+}
+
+impl Simulation {
+	/// Finds the corridor along the given links, each of which must lead to the next.
+	pub fn corridor(&self, links: &[usize]) -> Result<Corridor, SimError> {
+		if links.is_empty() {
+			return Err(SimError::InvalidGeometry("A corridor needs at least one link".into()));
+		}
+		let mut starts = vec![];
+		let mut lengths = vec![];
+		let mut speed_limits = vec![];
+		let mut dist = 0.0;
+		for (i, &id) in links.iter().enumerate() {
+			let link = self.links.get(id).ok_or(SimError::UnknownLink(id))?;
+			if let Some(&next) = links.get(i + 1) {
+				if !link.links_out.iter().any(|c| c.link_out == next) {
+					return Err(SimError::InvalidGeometry(format!("Link {} does not lead to link {}", id, next)));
+				}
+			}
+			starts.push(dist);
+			lengths.push(link.length);
+			speed_limits.push(link.speed_limit);
+			dist += link.length;
+		}
+		let mut signals = self.stoplines.iter()
+			.filter(|s| matches!(s.kind, StopLineType::TrafficLight { .. }))
+			.filter_map(|s| {
+				let i = links.iter().position(|&l| l == s.link)?;
+				Some((s.id, starts[i] + s.pos))
+			})
+			.collect::<Vec<_>>();
+		signals.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap().then(a.0.cmp(&b.0)));
+		Ok(Corridor {
+			links: links.to_vec(),
+			starts,
+			lengths,
+			speed_limits,
+			signals
+		})
+	}
+}
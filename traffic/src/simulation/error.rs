@@ -0,0 +1,63 @@
+use std::fmt;
+use crate::util::{InsertError, MAX_ID};
+use super::VehicleId;
+
+/// An error caused by invalid input to the simulation.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SimError {
+	UnknownLink(usize),
+	UnknownLane { link: usize, lane: u8 },
+	UnknownStopLine(usize),
+	/// No vehicle has the given user id
+	UnknownVehicle(usize),
+	/// The vehicle has been removed
+	StaleVehicle(VehicleId),
+	UnknownClosure(usize),
+	UnknownDetector(usize),
+	UnknownCrossing(usize),
+	DuplicateId { kind: &'static str, id: usize },
+	/// The id is too large to be stored
+	IdOutOfRange { kind: &'static str, id: usize },
+	VehicleNotPlaced(VehicleId),
+	UnreachableDestination { src_link: usize, dst_link: usize },
+	InvalidGeometry(String),
+	InvalidSpeed(f32),
+	InvalidController(String),
+	InvalidSnapshot(String)
+}
+
+impl fmt::Display for SimError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			SimError::UnknownLink(id) => write!(f, "Unknown link {}", id),
+			SimError::UnknownLane { link, lane } => write!(f, "Link {} has no lane {}", link, lane),
+			SimError::UnknownStopLine(id) => write!(f, "Unknown stop line {}", id),
+			SimError::UnknownVehicle(id) => write!(f, "Unknown vehicle {}", id),
+			SimError::StaleVehicle(id) => write!(f, "Vehicle {} has been removed", id),
+			SimError::UnknownClosure(id) => write!(f, "Unknown closure {}", id),
+			SimError::UnknownDetector(id) => write!(f, "Unknown detector {}", id),
+			SimError::UnknownCrossing(id) => write!(f, "Unknown crossing {}", id),
+			SimError::DuplicateId { kind, id } => write!(f, "A {} with id {} already exists", kind, id),
+			SimError::IdOutOfRange { kind, id } => write!(f, "A {} id must be at most {}, got {}", kind, MAX_ID, id),
+			SimError::VehicleNotPlaced(id) => write!(f, "Vehicle {} has not been placed on a link", id),
+			SimError::UnreachableDestination { src_link, dst_link } =>
+				write!(f, "Link {} cannot be reached from link {}", dst_link, src_link),
+			SimError::InvalidGeometry(msg) => write!(f, "Invalid geometry: {}", msg),
+			SimError::InvalidSpeed(speed) => write!(f, "Speed must be positive, got {}", speed),
+			SimError::InvalidController(msg) => write!(f, "Invalid controller: {}", msg),
+			SimError::InvalidSnapshot(msg) => write!(f, "Invalid snapshot: {}", msg)
+		}
+	}
+}
+
+impl std::error::Error for SimError {}
+
+impl SimError {
+	/// The error for a value which could not be inserted under the given id.
+	pub(crate) fn from_insert(err: InsertError, kind: &'static str, id: usize) -> Self {
+		match err {
+			InsertError::KeyExists => SimError::DuplicateId { kind, id },
+			InsertError::OutOfRange => SimError::IdOutOfRange { kind, id }
+		}
+	}
+}
@@ -0,0 +1,53 @@
+use serde::{Serialize, Deserialize};
+use super::Recovery;
+
+/// Something of note which happened during a step, reported through `Simulation::drain_events`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+	/// The step at the end of which the event happened
+	pub step: usize,
+	pub kind: EventKind
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+	/// A vehicle has been stationary for longer than the configured time
+	VehicleStuck {
+		user_id: usize,
+		link: usize,
+		pos: f32,
+		/// The stop line holding the vehicle, if any
+		stop_line: Option<usize>
+	},
+	/// Stop lines which are each waiting for the next to clear, the last waiting on the first
+	Gridlock {
+		stop_lines: Vec<usize>
+	},
+	/// A stuck vehicle was moved on
+	Recovered {
+		user_id: usize,
+		recovery: Recovery
+	},
+	/// Two vehicles started to overlap, the second ahead of the first
+	Collision {
+		link: usize,
+		user_ids: [usize; 2]
+	},
+	/// A vehicle came closer to the one ahead than the time-to-collision or DRAC thresholds allow
+	NearMiss {
+		link: usize,
+		follower: usize,
+		leader: usize,
+		ttc: Option<f32>,
+		drac: f32
+	},
+	/// A vehicle reached a stop line too soon after a conflicting vehicle cleared the conflict area
+	Encroachment {
+		stop_line: usize,
+		user_id: usize,
+		conflicting_user_id: usize,
+		/// Post-encroachment time, in seconds
+		pet: f32
+	}
+}
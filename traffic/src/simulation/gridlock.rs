@@ -0,0 +1,180 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use serde::{Serialize, Deserialize};
+use super::{Simulation, Event, EventKind, VehicleId};
+
+/// What to do with a vehicle which has been stationary for too long.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Recovery {
+	/// Only report it
+	None,
+	/// Move it to the next link on its route with space for it, or remove it if there is none
+	TeleportAhead,
+	Remove,
+	/// Let it through the stop line holding it, if any
+	ForceCommit
+}
+
+/**
+ * Settings for detecting stuck vehicles and gridlock, and recovering from them.
+ * */
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GridlockConfig {
+	/// How long a vehicle must be stationary for to be considered stuck, in seconds
+	pub stationary_time: f32,
+	pub recovery: Recovery
+}
+
+impl Default for GridlockConfig {
+	fn default() -> Self {
+		Self {
+			stationary_time: 300.0,
+			recovery: Recovery::None
+		}
+	}
+}
+
+impl Simulation {
+	pub fn set_gridlock_config(&mut self, config: GridlockConfig) {
+		self.gridlock = config;
+	}
+
+	pub fn get_gridlock_config(&self) -> GridlockConfig {
+		self.gridlock
+	}
+
+	/// Takes the events which have happened since the last call.
+	pub fn drain_events(&mut self) -> Vec<Event> {
+		std::mem::take(&mut self.events)
+	}
+
+	/// Reports vehicles which have just become stuck and any new cycles of waiting stop lines,
+	/// then recovers the stuck vehicles.
+	pub(super) fn detect_gridlock(&mut self) {
+		let threshold = ((self.gridlock.stationary_time / self.step_delta).ceil() as usize).max(1);
+		let step = self.step;
+
+		// Stuck vehicles, reported once as they cross the threshold
+		let mut stuck = vec![];
+		for veh in self.vehs.iter() {
+			if veh.stationary_steps == threshold {
+				stuck.push(veh.id);
+				self.events.push(Event {
+					step,
+					kind: EventKind::VehicleStuck {
+						user_id: veh.user_id,
+						link: veh.link,
+						pos: veh.pos,
+						stop_line: veh.stopline
+					}
+				});
+			}
+		}
+
+		// Cycles of stop lines, each holding a stuck vehicle because the next has not cleared
+		let waiting = self.vehs.iter()
+			.filter(|v| v.stationary_steps >= threshold)
+			.filter_map(|v| v.stopline)
+			.collect::<HashSet<_>>();
+		let mut graph = HashMap::new();
+		for id in waiting.iter() {
+			let stopline = self.stoplines.get(*id).unwrap();
+			let next = stopline.blocked_by.iter().cloned().filter(|s| waiting.contains(s)).collect::<Vec<_>>();
+			graph.insert(*id, next);
+		}
+		let cycles = find_cycles(&graph);
+		for cycle in cycles.iter() {
+			if !self.gridlocks.contains(cycle) {
+				self.events.push(Event { step, kind: EventKind::Gridlock { stop_lines: cycle.clone() } });
+			}
+		}
+		self.gridlocks = cycles;
+
+		// Recovery
+		let recovery = self.gridlock.recovery;
+		if recovery == Recovery::None {
+			return;
+		}
+		for id in stuck {
+			let user_id = self.vehs.get(id).unwrap().user_id;
+			let recovery = match recovery {
+				Recovery::None => recovery,
+				Recovery::Remove => match self.remove_vehicle(id) {
+					Ok(()) => recovery,
+					// The vehicle has already gone, so there is nothing to report
+					Err(_) => continue
+				},
+				Recovery::TeleportAhead => match self.teleport_ahead(id) {
+					Some(recovery) => recovery,
+					None => continue
+				},
+				Recovery::ForceCommit => {
+					let veh = self.vehs.get_mut(id).unwrap();
+					match veh.stopline {
+						Some(stopline) => {
+							veh.stationary_steps = 0;
+							self.stoplines.get_mut(stopline).unwrap().committed_vehs.insert(id);
+						}
+						// Nothing is holding the vehicle back, so there is nothing to do
+						None => continue
+					}
+					recovery
+				}
+			};
+			self.events.push(Event { step, kind: EventKind::Recovered { user_id, recovery } });
+		}
+	}
+
+	/// Moves a vehicle to the first link further along its route with room for it and a route on
+	/// from there, or removes it if there is none. Returns what was done, or `None` if the
+	/// vehicle has already gone.
+	fn teleport_ahead(&mut self, id: VehicleId) -> Option<Recovery> {
+		let veh = self.vehs.get(id)?;
+		let len = veh.len;
+		let candidates = veh.get_links().iter().cloned()
+			.enumerate()
+			.skip(1)
+			.map(|(i, link)| (link, veh.get_lane(i).unwrap_or(!0)))
+			.collect::<Vec<_>>();
+		for (link, lane) in candidates {
+			let num_lanes = self.links.get(link).map(|l| l.lanes.len()).unwrap_or(0);
+			let lane = if (lane as usize) < num_lanes { lane } else { 0 };
+			let pos = 0.5 * len;
+			// Nothing is changed if the vehicle cannot be placed there, e.g. as the link has no
+			// lanes or the destination can no longer be reached from it
+			if self.is_space_clear(link, lane, pos, len) && self.relocate_vehicle(id, link, lane, pos).is_ok() {
+				return Some(Recovery::TeleportAhead);
+			}
+		}
+		self.remove_vehicle(id).ok().map(|_| Recovery::Remove)
+	}
+}
+
+/// Finds the distinct cycles in a graph, each rotated to start from its lowest node.
+fn find_cycles(graph: &HashMap<usize, Vec<usize>>) -> BTreeSet<Vec<usize>> {
+	let mut cycles = BTreeSet::new();
+	let mut nodes = graph.keys().cloned().collect::<Vec<_>>();
+	nodes.sort();
+	for &start in nodes.iter() {
+		// Only look for cycles through nodes no lower than `start`, so each is found from its lowest
+		let mut path = vec![start];
+		let mut stack = vec![graph[&start].iter()];
+		while let Some(edges) = stack.last_mut() {
+			match edges.next() {
+				Some(&next) if next == start => {
+					cycles.insert(path.clone());
+				}
+				Some(&next) if next > start && !path.contains(&next) => {
+					path.push(next);
+					stack.push(graph[&next].iter());
+				}
+				Some(_) => {}
+				None => {
+					path.pop();
+					stack.pop();
+				}
+			}
+		}
+	}
+	cycles
+}
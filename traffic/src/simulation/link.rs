@@ -0,0 +1,314 @@
+use smallvec::{SmallVec, smallvec};
+use serde::{Serialize, Deserialize};
+use super::vehicle::{Vehicle, VehicleId};
+use super::meso::MesoLink;
+use crate::util::{IdMap, LinearFunc, CubicFunc, insertion_sort};
+
+/// Half the width of a lane, and of the area blocked by a lane closure.
+// todo: lane widths
+const LANE_HALF_WID: f32 = 1.5;
+
+/// Extra lateral clearance required before a lane is skipped when searching for obstacles,
+/// so that rounding never skips an obstacle which would have been followed.
+const LAT_MARGIN: f32 = 0.01;
+
+/// The most obstacles in other lanes which are checked one by one without using the lane index.
+const MAX_SCAN: usize = 8;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Link {
+	pub id: usize,
+	pub links_in: Vec<LinkConnection>,
+	pub links_out: Vec<LinkConnection>,
+	pub length: f32,
+	pub lanes: SmallVec<[Lane; 6]>,
+	pub speed_limit: f32,
+	/// The extra distance added to routes through the link, because of lane closures
+	pub route_penalty: f32,
+	/// The queue of vehicles if the link is mesoscopic, in which case they are not obstacles
+	pub meso: Option<MesoLink>,
+	/// The lane, start and end of each closed section of a lane
+	closures: Vec<(u8, f32, f32)>,
+	obstacles: Vec<Obstacle>,
+	/// The obstacles in each lane, rebuilt by `index_obstacles`
+	#[serde(skip)]
+	lane_index: Vec<LaneIndex>,
+	/// The next obstacle in the same lane as each obstacle, or `!0`
+	#[serde(skip)]
+	next_in_lane: Vec<usize>,
+	/// The obstacles which stick out of their lane, such as vehicles changing lanes
+	#[serde(skip)]
+	wide: Vec<usize>
+}
+
+impl Link {
+    pub fn new(id: usize, length: f32, speed_limit: f32) -> Self {
+        Self {
+			id,
+			links_in: vec![],
+			links_out: vec![],
+			length,
+			lanes: smallvec![],
+			speed_limit,
+			route_penalty: 0.0,
+			meso: None,
+			closures: vec![],
+			obstacles: vec![],
+			lane_index: vec![],
+			next_in_lane: vec![],
+			wide: vec![]
+		}
+    }
+
+	pub fn add_veh(&mut self, veh: VehicleId) {
+		self.obstacles.insert(0, Obstacle {
+			veh: Some(veh),
+			pos: 0.0,
+			vel: 0.0,
+			lat: 0.0,
+			half_wid: 0.0,
+			lane: 0
+		});
+	}
+
+	pub fn remove_veh(&mut self, veh: VehicleId) {
+		let ind = self.obstacles.iter().rposition(|o| o.veh == Some(veh));
+        if let Some(i) = ind {
+		    self.obstacles.remove(i);
+        }
+		if let Some(meso) = &mut self.meso {
+			meso.remove_veh(veh);
+		}
+		// Stop line commitments are released by `Simulation`
+	}
+
+	/// Closes a lane from `pos` to `end`, with a static obstacle at `pos` for vehicles in the lane
+	/// to stop at. Vehicles choose their lanes so as not to enter the closed section.
+	pub fn add_closure(&mut self, lane: u8, pos: f32, end: f32) {
+		self.closures.push((lane, pos, end));
+		let obst = Obstacle {
+			veh: None,
+			pos,
+			vel: 0.0,
+			lat: self.get_lat(lane, pos),
+			half_wid: LANE_HALF_WID,
+			lane
+		};
+		let i = self.obstacles.iter().position(|o| o.pos > pos).unwrap_or(self.obstacles.len());
+		self.obstacles.insert(i, obst);
+	}
+
+	pub fn remove_closure(&mut self, lane: u8, pos: f32, end: f32) {
+		if let Some(i) = self.closures.iter().position(|&c| c == (lane, pos, end)) {
+			self.closures.remove(i);
+		}
+		let ind = self.obstacles.iter().position(|o| o.veh.is_none() && o.lane == lane && o.pos == pos);
+		if let Some(i) = ind {
+			self.obstacles.remove(i);
+		}
+	}
+
+	/// The lanes which are closed, and the positions they are closed from and to.
+	pub fn get_closures<'a>(&'a self) -> impl Iterator<Item=(u8, f32, f32)> + 'a {
+		self.closures.iter().cloned()
+	}
+
+    pub fn get_offset_to_link(&self, link: usize) -> f32 {
+        self.links_out.iter().find(|c| c.link_out == link).unwrap().offset
+    }
+
+	pub fn get_lane_connections<'a>(&'a self, link: usize) -> impl Iterator<Item=(u8, u8)> + 'a {
+		self.links_out.iter()
+			.find(|c| c.link_out == link)
+			.map(|c| &c.lanes)
+			.as_ref()
+			.unwrap()
+			.iter()
+			.cloned()
+	}
+
+	pub fn update_obstacles(&mut self, vehs: &IdMap<Vehicle, VehicleId>) {
+		for obst in self.obstacles.iter_mut() {
+			if let Some(veh) = obst.veh {
+				*obst = vehs.get(veh).unwrap().get_obstacle();
+			}
+		}
+		insertion_sort(&mut self.obstacles, |a, b| a.pos.partial_cmp(&b.pos).unwrap());
+	}
+
+	/// Indexes the obstacles by lane, for the car-following model.
+	pub fn index_obstacles(&mut self) {
+		self.lane_index.resize_with(self.lanes.len(), LaneIndex::default);
+		for (index, lane) in self.lane_index.iter_mut().zip(self.lanes.iter()) {
+			*index = LaneIndex {
+				first: !0,
+				min_lat: f32::INFINITY,
+				max_lat: f32::NEG_INFINITY,
+				lane_lat: lane.lat.get_range()
+			};
+		}
+		self.next_in_lane.resize(self.obstacles.len(), !0);
+		self.wide.clear();
+		for (i, obst) in self.obstacles.iter().enumerate().rev() {
+			let index = &mut self.lane_index[obst.lane as usize];
+			self.next_in_lane[i] = index.first;
+			index.first = i;
+			let (lo, hi) = (obst.lat - obst.half_wid, obst.lat + obst.half_wid);
+			if lo < index.lane_lat.0 - LANE_HALF_WID || hi > index.lane_lat.1 + LANE_HALF_WID {
+				self.wide.push(i);
+			} else {
+				index.min_lat = index.min_lat.min(lo);
+				index.max_lat = index.max_lat.max(hi);
+			}
+		}
+		self.wide.reverse();
+	}
+
+	pub fn get_obstacles(&self) -> &[Obstacle] {
+		&self.obstacles
+	}
+
+	pub fn get_vehicles<'a>(&'a self) -> impl DoubleEndedIterator<Item=VehicleId> + 'a {
+		self.obstacles.iter().filter_map(|o| o.veh)
+	}
+
+	pub fn get_lat(&self, lane: u8, pos: f32) -> f32 {
+		self.lanes[lane as usize].lat.get_y(pos)
+	}
+
+	/// Applies the car-following model to the vehicle which is the `i`th obstacle on the link.
+	/// The obstacles of every link must have been updated and indexed since they last changed.
+	pub fn car_follow_model(&self, i: usize, veh: &mut Vehicle, links: &IdMap<Link>) {
+		self.car_follow_inner(i + 1, veh, veh.lane, 0, 0.0, 0.0, links);
+	}
+	
+	#[allow(clippy::too_many_arguments)]
+	fn car_follow_inner(&self, i: usize, veh: &mut Vehicle, lane: u8, r: usize, offset: f32, dist: f32, links: &IdMap<Link>) {
+		// The first obstacle from `i` in the same lane is followed, and any before it in other
+		// lanes which block the vehicle's path
+		let leader = if i == 0 {
+			self.lane_index[lane as usize].first
+		} else {
+			self.next_in_lane[i - 1]
+		};
+		let end = leader.min(self.obstacles.len());
+		if i < end {
+			self.follow_blocking(i, end, veh, lane, offset, dist);
+		}
+		// Follow if in same lane
+		if leader != !0 {
+			let obst = &self.obstacles[leader];
+			veh.follow(dist + obst.pos, obst.vel);
+			return;
+		}
+		// Next link
+		let r = r + 1;
+		if let Some(next_link) = veh.get_link(r) {
+			if let Some(next_lane) = veh.get_lane(r) {
+				if next_lane != !0 {
+					// Search the next link
+					let offset = offset + self.get_offset_to_link(next_link);
+					links.get(next_link).unwrap()
+						.car_follow_inner(0, veh, next_lane, r, offset, dist + self.length, links);
+					return;
+				}
+			}
+			// Stop before reaching the next link
+			veh.stop(dist + self.length);
+		}
+	}
+
+	/// Whether the vehicle's path stays clear of every lane but its own, apart from obstacles
+	/// which stick out of their lane.
+	fn is_clear_of_lanes(&self, veh: &Vehicle, lane: u8, offset: f32) -> bool {
+		let (mut min_lat, mut max_lat) = self.lane_index[lane as usize].lane_lat;
+		if let Some(path) = veh.path {
+			let (lo, hi) = path.get_range();
+			min_lat = min_lat.min(lo + offset);
+			max_lat = max_lat.max(hi + offset);
+		}
+		let clearance = 0.5 * veh.wid + 0.5 + LAT_MARGIN;
+		let (min_lat, max_lat) = (min_lat - clearance, max_lat + clearance);
+		self.lane_index.iter().enumerate()
+			.all(|(l, index)| l == lane as usize || index.min_lat >= max_lat || index.max_lat <= min_lat)
+	}
+
+	/// Follows the obstacles from `i` to `end` in other lanes which block the vehicle's path.
+	fn follow_blocking(&self, i: usize, end: usize, veh: &mut Vehicle, lane: u8, offset: f32, dist: f32) {
+		// If every other lane is too far to the side of the path, only obstacles which stick out
+		// of their lane need to be checked, which is worth finding out if there are many
+		let (mut wide, mut all);
+		let candidates: &mut dyn Iterator<Item=usize> = if end - i > MAX_SCAN && self.is_clear_of_lanes(veh, lane, offset) {
+			wide = self.wide[self.wide.partition_point(|&j| j < i)..].iter().cloned().take_while(|&j| j < end);
+			&mut wide
+		} else {
+			all = i..end;
+			&mut all
+		};
+		let halfwid = 0.5 * veh.wid;
+
+		for j in candidates {
+			let obst = &self.obstacles[j];
+			// Follow if blocking path
+			let pos = obst.pos - (0.5 * veh.len + 1.0);
+			let on_curr_path = veh.path.is_some()
+				&& pos + dist <= veh.path.unwrap().max_x;
+			let (lat, halfwid) = if on_curr_path {
+				let path = veh.path.unwrap();
+				let mut lat = path.get_y(pos + dist);
+				let mut halfwid = halfwid;
+				if veh.changing_lanes {
+					let half_delta = 0.5 * (path.get_y2() - lat);
+					lat += half_delta;
+					halfwid += half_delta.abs();
+				}
+				lat += offset;
+				(lat, halfwid)
+			} else {
+				(self.get_lat(lane, pos), halfwid)
+			};
+			let gap = (lat - obst.lat).abs() - (halfwid + obst.half_wid);
+			if gap < 0.5 {
+				veh.follow(dist + obst.pos, obst.vel);
+			}
+		}
+	}
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LinkConnection {
+	pub link_in: usize,
+	pub link_out: usize,
+	pub lanes: SmallVec<[(u8, u8); 8]>,
+    pub offset: f32
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Lane {
+	#[allow(dead_code)] // todo: curvature
+	pub dist: LinearFunc,
+	pub lat: CubicFunc
+}
+
+/// Where the obstacles in a lane of a link start, and how far they extend to either side.
+#[derive(Clone, Default)]
+struct LaneIndex {
+	/// The index of the first obstacle in the lane, or `!0`
+	first: usize,
+	/// Excluding obstacles which stick out of the lane
+	min_lat: f32,
+	max_lat: f32,
+	/// The range of lateral positions of the lane itself
+	lane_lat: (f32, f32)
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Obstacle {
+	/// The vehicle, or `None` for a lane closure
+	pub veh: Option<VehicleId>,
+	pub pos: f32,
+	pub vel: f32,
+	pub lat: f32,
+	pub half_wid: f32,
+	pub lane: u8
+}
\ No newline at end of file
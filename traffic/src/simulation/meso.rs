@@ -0,0 +1,322 @@
+use std::collections::VecDeque;
+use serde::{Serialize, Deserialize};
+use super::{Simulation, SimError, VehicleId};
+use super::link::Link;
+use super::vehicle::{STATIONARY_VEL, HEADWAY, MIN_GAP};
+
+/// How the vehicles on a link are simulated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkModel {
+	/// Vehicles follow the one ahead, change lanes and stop at stop lines
+	#[default]
+	Micro,
+	/// Vehicles travel at a speed set by how dense the link is, then queue to leave it
+	Meso
+}
+
+/**
+ * The speed-density relationship and capacity of mesoscopic links.
+ *
+ * A vehicle entering a link travels along it at the link's speed limit, reduced in proportion
+ * to the link's density as it approaches the jam density. It then leaves the link once it is at
+ * the front of the queue, the link's capacity allows and there is space on the next link.
+ * */
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MesoConfig {
+	/// The density at which traffic comes to a stop, in vehicles per kilometre per lane
+	pub jam_density: f32,
+	/// The most vehicles which can leave a link, in vehicles per hour per lane
+	pub capacity: f32,
+	/// The slowest speed vehicles travel at, however dense the link, in m/s
+	pub min_speed: f32
+}
+
+impl Default for MesoConfig {
+	fn default() -> Self {
+		Self {
+			jam_density: 140.0,
+			capacity: 1800.0,
+			min_speed: 2.0
+		}
+	}
+}
+
+/// The vehicles on a mesoscopic link, in the order they may leave it.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct MesoLink {
+	queue: VecDeque<MesoVehicle>,
+	/// The most vehicles the link can hold
+	storage: usize,
+	/// How many vehicles may leave the link, built up at its capacity and spent as they leave
+	exit_credit: f32
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct MesoVehicle {
+	veh: VehicleId,
+	/// The step from which the vehicle may leave the link
+	exit_step: usize,
+	/// The speed the vehicle travels along the link at
+	speed: f32
+}
+
+impl MesoLink {
+	fn new(link: &Link, config: &MesoConfig) -> Self {
+		let mut meso = Self::default();
+		meso.set_storage(link, config);
+		meso
+	}
+
+	fn set_storage(&mut self, link: &Link, config: &MesoConfig) {
+		let storage = 0.001 * link.length * link.lanes.len().max(1) as f32 * config.jam_density;
+		self.storage = (storage as usize).max(1);
+	}
+
+	/// Whether the link holds as many vehicles as it can.
+	pub fn is_full(&self) -> bool {
+		self.queue.len() >= self.storage
+	}
+
+	pub fn get_vehicles<'a>(&'a self) -> impl Iterator<Item=VehicleId> + 'a {
+		self.queue.iter().map(|v| v.veh)
+	}
+
+	pub fn remove_veh(&mut self, veh: VehicleId) {
+		self.queue.retain(|v| v.veh != veh);
+	}
+}
+
+impl Simulation {
+	pub fn set_meso_config(&mut self, config: MesoConfig) {
+		self.meso = config;
+		for link in self.links.iter_mut() {
+			let mut meso = link.meso.take();
+			if let Some(meso) = &mut meso {
+				meso.set_storage(link, &config);
+			}
+			link.meso = meso;
+		}
+	}
+
+	pub fn get_meso_config(&self) -> MesoConfig {
+		self.meso
+	}
+
+	/// Switches a link between the microscopic and mesoscopic models, carrying over the
+	/// vehicles on it. Stop lines on mesoscopic links have no effect, and links without lanes
+	/// can only be mesoscopic.
+	pub fn set_link_model(&mut self, link: usize, model: LinkModel) -> Result<(), SimError> {
+		let current = self.get_link_model(link).ok_or(SimError::UnknownLink(link))?;
+		if current == model {
+			return Ok(());
+		}
+		let l = self.links.get_mut(link).unwrap();
+		match model {
+			LinkModel::Meso => {
+				l.meso = Some(MesoLink::new(l, &self.meso));
+				let vehs = l.get_vehicles().rev().collect::<Vec<_>>();
+				for id in vehs {
+					self.links.get_mut(link).unwrap().remove_veh(id);
+					self.enter_meso(id, link);
+				}
+			}
+			LinkModel::Micro => {
+				if l.lanes.is_empty() {
+					return Err(SimError::UnknownLane { link, lane: 0 });
+				}
+				let meso = l.meso.take().unwrap();
+				for id in meso.get_vehicles() {
+					let veh = self.vehs.get_mut(id).unwrap();
+					let (lane, pos, vel) = (veh.lane, veh.pos, veh.vel);
+					veh.meso = false;
+					veh.enter_link(lane, pos, vel, &self.links);
+					self.links.get_mut(link).unwrap().add_veh(id);
+				}
+			}
+		}
+		Ok(())
+	}
+
+	pub fn get_link_model(&self, link: usize) -> Option<LinkModel> {
+		self.links.get(link).map(|l| match l.meso {
+			Some(_) => LinkModel::Meso,
+			None => LinkModel::Micro
+		})
+	}
+
+	/// Adds a vehicle to the queue of a mesoscopic link, which its position has been set on.
+	pub(super) fn enter_meso(&mut self, id: VehicleId, link: usize) {
+		let l = self.links.get_mut(link).unwrap();
+		let meso = l.meso.as_mut().unwrap();
+		let veh = self.vehs.get_mut(id).unwrap();
+		// A link without lanes is treated as having one, so the density is finite
+		let lanes = l.lanes.len().max(1) as f32;
+		let density = 1000.0 * (meso.queue.len() + 1) as f32 / (l.length * lanes);
+		let speed = (l.speed_limit * (1.0 - density / self.meso.jam_density))
+			.max(self.meso.min_speed)
+			.min(l.speed_limit);
+		let steps = ((l.length - veh.pos).max(0.0) / speed / self.step_delta).ceil() as usize;
+		let exit_step = self.step + steps.max(1);
+		let i = meso.queue.iter().rposition(|v| v.exit_step <= exit_step).map(|i| i + 1).unwrap_or(0);
+		meso.queue.insert(i, MesoVehicle { veh: id, exit_step, speed });
+		// A link without lanes leaves the vehicle in the lane it was in, which is never followed
+		let num_lanes = l.lanes.len() as u8;
+		if num_lanes > 0 {
+			veh.lane = veh.get_lane(0).filter(|&lane| lane < num_lanes).unwrap_or(veh.lane.min(num_lanes - 1));
+		}
+		veh.meso = true;
+		veh.vel = speed;
+		veh.changing_lanes = false;
+		veh.stopline = None;
+	}
+
+	/// Stops microscopic vehicles at the end of their link while the mesoscopic link they are
+	/// about to enter is at jam density, as vehicles leaving mesoscopic links wait for space.
+	pub(super) fn hold_for_meso(&mut self) {
+		let mut full = vec![false; self.links.len()];
+		for link in self.links.iter() {
+			full[link.id] = link.meso.as_ref().map(|m| m.is_full()).unwrap_or(false);
+		}
+		if !full.contains(&true) {
+			return;
+		}
+		let links = &self.links;
+		for veh in self.vehs.iter_mut() {
+			if veh.meso || !veh.get_link(1).map(|next| full[next]).unwrap_or(false) {
+				continue;
+			}
+			veh.stop(links.get(veh.link).unwrap().length);
+		}
+	}
+
+	/// Moves the vehicles which are due to leave mesoscopic links on to their next links, as
+	/// far as capacity and space allow, then updates where each vehicle on them is.
+	pub(super) fn step_meso(&mut self) {
+		let meso_links = self.links.iter()
+			.filter(|l| l.meso.is_some())
+			.map(|l| l.id)
+			.collect::<Vec<_>>();
+		for &link in meso_links.iter() {
+			let l = self.links.get_mut(link).unwrap();
+			let lanes = l.lanes.len().max(1) as f32;
+			let meso = l.meso.as_mut().unwrap();
+			let rate = self.meso.capacity * lanes * self.step_delta / 3600.0;
+			meso.exit_credit = (meso.exit_credit + rate).min(rate.max(1.0));
+			while self.leave_meso(link) {}
+		}
+
+		// Estimate where the remaining vehicles are from when they can leave, queueing those
+		// which are waiting at jam density
+		let spacing = 1000.0 / self.meso.jam_density;
+		for &link in meso_links.iter() {
+			let l = self.links.get(link).unwrap();
+			let lanes = l.lanes.len().max(1) as f32;
+			let has_lanes = !l.lanes.is_empty();
+			for (i, v) in l.meso.as_ref().unwrap().queue.iter().enumerate() {
+				let veh = self.vehs.get_mut(v.veh).unwrap();
+				let steps_left = v.exit_step.saturating_sub(self.step) as f32;
+				let queue_pos = l.length - (i as f32 / lanes).floor() * spacing;
+				veh.pos = (l.length - steps_left * self.step_delta * v.speed).min(queue_pos).max(0.0);
+				veh.vel = if steps_left > 0.0 { v.speed } else { 0.0 };
+				veh.last_acc = 0.0;
+				if veh.vel < STATIONARY_VEL {
+					veh.stationary_steps += 1;
+				} else {
+					veh.stationary_steps = 0;
+				}
+				veh.path = None;
+				if has_lanes {
+					veh.update_path(&self.links);
+				} else {
+					veh.lat = 0.0;
+					veh.dlat = 0.0;
+				}
+			}
+		}
+	}
+
+	/// Moves the vehicle at the front of a mesoscopic link on, if it can leave.
+	/// Returns whether it did.
+	fn leave_meso(&mut self, link: usize) -> bool {
+		let l = self.links.get(link).unwrap();
+		let meso = l.meso.as_ref().unwrap();
+		let front = match meso.queue.front() {
+			Some(front) if front.exit_step <= self.step && meso.exit_credit >= 1.0 => *front,
+			_ => return false
+		};
+		let next_link = self.vehs.get(front.veh).unwrap().get_link(1);
+		// The vehicle waits at the front of the queue, holding up those behind it, until there is
+		// space for it on the next link
+		let entry = match next_link {
+			Some(next_link) => match &self.links.get(next_link).unwrap().meso {
+				Some(next_meso) if next_meso.is_full() => return false,
+				Some(_) => None,
+				None => match self.find_micro_entry(front, link, next_link) {
+					Some(entry) => Some(entry),
+					None => return false
+				}
+			},
+			None => None
+		};
+
+		let meso = self.links.get_mut(link).unwrap().meso.as_mut().unwrap();
+		meso.queue.pop_front();
+		meso.exit_credit -= 1.0;
+		let veh = self.vehs.get_mut(front.veh).unwrap();
+		veh.advance_route();
+		veh.meso = false;
+		let next_link = match next_link {
+			Some(next_link) => next_link,
+			// Removed with the other vehicles which have reached their destinations
+			None => return true
+		};
+		match entry {
+			Some((lane, vel)) => {
+				veh.enter_link(lane, 0.0, vel, &self.links);
+				self.links.get_mut(next_link).unwrap().add_veh(front.veh);
+			}
+			None => {
+				veh.pos = 0.0;
+				self.enter_meso(front.veh, next_link);
+			}
+		}
+		true
+	}
+
+	/// Chooses the lane and speed for a vehicle leaving a mesoscopic link to enter a microscopic
+	/// one with, or `None` if the start of every lane it could enter is blocked.
+	/// It takes the lane best suited to its route which has space, at a speed it could follow
+	/// the vehicle ahead in that lane at.
+	fn find_micro_entry(&mut self, front: MesoVehicle, link: usize, next_link: usize) -> Option<(u8, f32)> {
+		// Any lane may be entered from a link without lanes, which has no lane connections
+		let l = self.links.get(link).unwrap();
+		let connected = if l.lanes.is_empty() {
+			(0..self.links.get(next_link).unwrap().lanes.len() as u8).collect::<Vec<_>>()
+		} else {
+			l.get_lane_connections(next_link).map(|(_, lane)| lane).collect::<Vec<_>>()
+		};
+		let veh = self.vehs.get_mut(front.veh).unwrap();
+		let lanes = veh.lanes_by_preference(1, &self.links);
+		let veh = self.vehs.get(front.veh).unwrap();
+		let next = self.links.get(next_link).unwrap();
+		let mut max_vel = front.speed.min(next.speed_limit);
+		if let Some(max_speed) = veh.max_speed {
+			max_vel = max_vel.min(max_speed);
+		}
+		lanes.into_iter()
+			.filter(|lane| connected.contains(lane))
+			.find_map(|lane| {
+				// The gap to the rearmost vehicle in the lane, including any changing out of it
+				let gap = next.get_vehicles()
+					.map(|id| self.vehs.get(id).unwrap())
+					.filter(|other| other.lane == lane || (other.changing_lanes && other.old_lane == lane))
+					.map(|other| other.pos - 0.5 * (other.len + veh.len))
+					.fold(f32::INFINITY, f32::min);
+				if gap < MIN_GAP {
+					return None;
+				}
+				Some((lane, max_vel.min((gap - MIN_GAP) / HEADWAY)))
+			})
+	}
+}
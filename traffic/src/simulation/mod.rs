@@ -0,0 +1,995 @@
+mod vehicle;
+mod link;
+mod error;
+mod snapshot;
+mod event;
+mod gridlock;
+mod safety;
+mod closure;
+mod profile;
+mod meso;
+mod signal;
+mod corridor;
+mod pedestrian;
+
+use core::cmp::Ordering;
+use std::time::Instant;
+use std::collections::{BinaryHeap, BTreeMap, BTreeSet, HashMap, HashSet};
+use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
+use crate::util::{IdMap, IdKey, LinearFunc, CubicFunc};
+use vehicle::Vehicle;
+use link::{Link, Lane, LinkConnection, Obstacle};
+pub use vehicle::{VehicleState, VehicleId};
+pub use error::SimError;
+pub use event::{Event, EventKind};
+pub use gridlock::{GridlockConfig, Recovery};
+pub use safety::{SafetyConfig, SafetyStats, PairMeasure};
+pub use closure::{LaneClosure, RoutePenalty};
+pub use profile::StepProfile;
+pub use meso::{LinkModel, MesoConfig};
+pub use signal::{Detector, DetectorReading, Controller, ActuatedController, Phase, Recall};
+pub use signal::{CoordinatedController, TimedPhase, SavedController};
+pub use corridor::Corridor;
+pub use pedestrian::{CrossingBuilder, CrossingType, PedestrianState};
+
+#[derive(Serialize, Deserialize)]
+pub struct Simulation {
+	step: usize,
+	step_delta: f32,
+	// Links, stop lines and the like keep the plain ids they were added with, rather than
+	// generational keys like vehicles, as they are never removed, so their slots are never reused
+	// todo: generational keys for any of them which become removable
+	links: IdMap<Link>,
+	vehs: IdMap<Vehicle, VehicleId>,
+	/// The key of each vehicle, by user id
+	#[serde(serialize_with = "snapshot::sorted::serialize_map", deserialize_with = "snapshot::sorted::deserialize_map")]
+	user_ids: HashMap<usize, VehicleId>,
+	stoplines: IdMap<StopLine>,
+	crossings: IdMap<pedestrian::Crossing>,
+	closures: IdMap<closure::Closure>,
+	#[serde(serialize_with = "snapshot::sorted::serialize_map", deserialize_with = "snapshot::sorted::deserialize_map")]
+	route_table: HashMap<RouteTableKey, RouteTableEntry>,
+	lane_route_period: usize,
+	gridlock: GridlockConfig,
+	/// The cycles of waiting stop lines found in the last step
+	gridlocks: BTreeSet<Vec<usize>>,
+	safety: safety::SafetyState,
+	meso: MesoConfig,
+	detectors: IdMap<signal::DetectorState>,
+	/// Controllers can be of any type, so are saved separately in snapshots
+	#[serde(skip)]
+	controllers: Vec<signal::ControllerSlot>,
+	/// Events are left out of snapshots, as bincode cannot read back their tagged form
+	#[serde(skip)]
+	events: Vec<Event>,
+	/// Whether to step links and vehicles in parallel, which gives identical results
+	#[serde(skip)]
+	parallel: bool,
+	/// The time spent in each phase of the steps taken while profiling
+	#[serde(skip)]
+	profile: Option<StepProfile>
+}
+
+impl Simulation {
+	pub fn new(step_delta: f32) -> Self {
+		Self {
+			step: 0,
+			step_delta,
+			links: IdMap::new(),
+			vehs: IdMap::new(),
+			user_ids: HashMap::new(),
+			stoplines: IdMap::new(),
+			crossings: IdMap::new(),
+			closures: IdMap::new(),
+			route_table: HashMap::new(),
+			lane_route_period: 5,
+			gridlock: GridlockConfig::default(),
+			gridlocks: BTreeSet::new(),
+			safety: safety::SafetyState::default(),
+			meso: MesoConfig::default(),
+			detectors: IdMap::new(),
+			controllers: vec![],
+			events: vec![],
+			parallel: false,
+			profile: None
+		}
+	}
+
+	/// Adds an unplaced vehicle, which is given a key of its own as user ids may be reused.
+	pub fn add_vehicle(&mut self, user_id: usize) -> Result<VehicleId, SimError> {
+		if self.user_ids.contains_key(&user_id) {
+			return Err(SimError::DuplicateId { kind: "vehicle", id: user_id });
+		}
+		let id = self.vehs.insert_free(Vehicle::new(user_id));
+		self.vehs.get_mut(id).unwrap().id = id;
+		self.user_ids.insert(user_id, id);
+		Ok(id)
+	}
+
+	/// Checks that a vehicle could be placed at the given position.
+	pub fn check_vehicle_pos(&self, link: usize, lane: u8, pos: f32) -> Result<(), SimError> {
+		let new_link = self.links.get(link).ok_or(SimError::UnknownLink(link))?;
+		if lane as usize >= new_link.lanes.len() {
+			return Err(SimError::UnknownLane { link, lane });
+		}
+		if !(0.0..=new_link.length).contains(&pos) {
+			return Err(SimError::InvalidGeometry(format!("Position {} is not on link {}", pos, link)));
+		}
+		Ok(())
+	}
+
+	/// Adds a vehicle at the given position, routed to `dst_link`, returning its id.
+	/// Nothing is added if any of the arguments are invalid.
+	pub fn insert_vehicle(&mut self, user_id: usize, link: usize, lane: u8, pos: f32, dst_link: usize) -> Result<VehicleId, SimError> {
+		let route = self.find_route(link, dst_link)?;
+		self.check_vehicle_pos(link, lane, pos)?;
+		let id = self.add_vehicle(user_id)?;
+		self.set_vehicle_pos(id, link, lane, pos)?;
+		self.vehs.get_mut(id).unwrap().set_route(route);
+		Ok(id)
+	}
+
+	/// Checks whether a vehicle of the given length could be placed without overlapping another.
+	pub fn is_space_clear(&self, link: usize, lane: u8, pos: f32, len: f32) -> bool {
+		let link = match self.links.get(link) {
+			Some(link) => link,
+			None => return false
+		};
+		if let Some(meso) = &link.meso {
+			return !meso.is_full();
+		}
+		link.get_vehicles().all(|vid| {
+			let veh = self.vehs.get(vid).unwrap();
+			let min_gap = 0.5 * (veh.len + len) + 2.0;
+			// A vehicle changing lanes still occupies the lane it is leaving
+			let in_lane = veh.lane == lane || (veh.changing_lanes && veh.old_lane == lane);
+			!in_lane || (veh.pos - pos).abs() >= min_gap
+		})
+	}
+
+	/// Places a vehicle at the given position, with a route consisting of only that link.
+	pub fn set_vehicle_pos(&mut self, id: VehicleId, link: usize, lane: u8, pos: f32) -> Result<(), SimError> {
+		self.check_vehicle_pos(link, lane, pos)?;
+		let veh = self.vehs.get_mut(id).ok_or(SimError::StaleVehicle(id))?;
+		if let Some(old_link) = veh.get_link(0) {
+			self.links.get_mut(old_link).unwrap().remove_veh(id);
+		}
+		veh.set_pos(link, lane, pos);
+		veh.update_path(&self.links);
+		if self.links.get(link).unwrap().meso.is_some() {
+			self.enter_meso(id, link);
+		} else {
+			self.links.get_mut(link).unwrap().add_veh(id);
+		}
+		self.release_commitments(id);
+		Ok(())
+	}
+
+	/// Moves a placed vehicle to the given position, keeping its speed and destination.
+	/// Nothing is changed if the destination cannot be reached from the new position.
+	pub fn relocate_vehicle(&mut self, id: VehicleId, link: usize, lane: u8, pos: f32) -> Result<(), SimError> {
+		let veh = self.vehs.get(id).ok_or(SimError::StaleVehicle(id))?;
+		let dst_link = veh.get_dest().ok_or(SimError::VehicleNotPlaced(id))?;
+		let route = self.find_route(link, dst_link)?;
+		self.set_vehicle_pos(id, link, lane, pos)?;
+		self.vehs.get_mut(id).unwrap().set_route(route);
+		Ok(())
+	}
+
+	pub fn remove_vehicle(&mut self, id: VehicleId) -> Result<(), SimError> {
+		let veh = self.vehs.remove(id).ok_or(SimError::StaleVehicle(id))?;
+		self.user_ids.remove(&veh.user_id);
+		if let Some(link) = veh.get_link(0) {
+			self.links.get_mut(link).unwrap().remove_veh(id);
+		}
+		self.release_commitments(id);
+		Ok(())
+	}
+
+	/// Finds the vehicle with the given user id.
+	pub fn find_vehicle(&self, user_id: usize) -> Option<VehicleId> {
+		self.user_ids.get(&user_id).cloned()
+	}
+
+	/// Forgets that a vehicle was let through any stop lines, as it has moved or gone.
+	fn release_commitments(&mut self, id: VehicleId) {
+		for stopline in self.stoplines.iter_mut() {
+			stopline.committed_vehs.remove(&id);
+		}
+	}
+
+	pub fn set_vehicle_dest(&mut self, id: VehicleId, link: usize) -> Result<(), SimError> {
+		let veh = self.vehs.get(id).ok_or(SimError::StaleVehicle(id))?;
+		let src_link = veh.get_link(0).ok_or(SimError::VehicleNotPlaced(id))?;
+		let route = self.find_route(src_link, link)?;
+		self.vehs.get_mut(id).unwrap().set_route(route);
+		Ok(())
+	}
+
+	/// Limits the speed of a vehicle, on top of the speed limits of the links it drives on,
+	/// or lifts the limit if `None`.
+	pub fn set_vehicle_max_speed(&mut self, id: VehicleId, max_speed: Option<f32>) -> Result<(), SimError> {
+		if let Some(speed) = max_speed {
+			if !speed.is_finite() || speed <= 0.0 {
+				return Err(SimError::InvalidSpeed(speed));
+			}
+		}
+		let veh = self.vehs.get_mut(id).ok_or(SimError::StaleVehicle(id))?;
+		veh.max_speed = max_speed;
+		Ok(())
+	}
+
+	pub fn add_link(&mut self, id: usize, length: f32, speed_limit: f32) -> Result<(), SimError> {
+		if !length.is_finite() || length <= 0.0 {
+			return Err(SimError::InvalidGeometry(format!("Link length must be positive, got {}", length)));
+		}
+		if !speed_limit.is_finite() || speed_limit <= 0.0 {
+			return Err(SimError::InvalidGeometry(format!("Speed limit must be positive, got {}", speed_limit)));
+		}
+		self.links.insert(id, Link::new(id, length, speed_limit))
+			.map(|_| ())
+			.map_err(|e| SimError::from_insert(e, "link", id))
+	}
+
+	pub fn add_lane(&mut self, link: usize, dist_func: LinearFunc, lat_func: CubicFunc) -> Result<(), SimError> {
+		let lanes = &mut self.links.get_mut(link).ok_or(SimError::UnknownLink(link))?.lanes;
+		// Lane index `!0` is reserved to mean "no lane"
+		if lanes.len() >= u8::MAX as usize {
+			return Err(SimError::InvalidGeometry(format!("Link {} has too many lanes", link)));
+		}
+		lanes.push(Lane {
+			dist: dist_func,
+			lat: lat_func
+		});
+		Ok(())
+	}
+
+	pub fn add_connection(&mut self, src_link: usize, dst_link: usize, lanes: &[(u8, u8)], offset: f32) -> Result<(), SimError> {
+		let num_lanes_in = self.links.get(src_link).ok_or(SimError::UnknownLink(src_link))?.lanes.len();
+		let num_lanes_out = self.links.get(dst_link).ok_or(SimError::UnknownLink(dst_link))?.lanes.len();
+		for &(lane_in, lane_out) in lanes {
+			if lane_in as usize >= num_lanes_in {
+				return Err(SimError::UnknownLane { link: src_link, lane: lane_in });
+			}
+			if lane_out as usize >= num_lanes_out {
+				return Err(SimError::UnknownLane { link: dst_link, lane: lane_out });
+			}
+		}
+		let conn = LinkConnection {
+			link_in: src_link,
+			link_out: dst_link,
+			lanes: lanes.iter().cloned().collect(),
+			offset
+		};
+		self.links.get_mut(src_link).unwrap().links_out.push(conn.clone());
+		self.links.get_mut(dst_link).unwrap().links_in.push(conn);
+		// Cached routes may no longer be the shortest
+		self.route_table.clear();
+		Ok(())
+	}
+
+	pub fn add_conflict(&mut self, stop1: usize, stop2: usize, priority: Ordering, max_pos: f32) -> Result<(), SimError> {
+		if !self.stoplines.has_key(stop2) {
+			return Err(SimError::UnknownStopLine(stop2));
+		}
+		let stopline = self.stoplines.get_mut(stop1).ok_or(SimError::UnknownStopLine(stop1))?;
+		stopline.conflicts.push(Conflict {
+			stopline: stop2,
+			priority,
+			max_pos
+		});
+		Ok(())
+	}
+
+	/// Spreads each step over rayon's thread pool. The results are the same either way.
+	pub fn set_parallel(&mut self, parallel: bool) {
+		self.parallel = parallel;
+	}
+
+	pub fn get_step(&self) -> usize {
+		self.step
+	}
+
+	pub fn step(&mut self) {
+		let mut clock = Instant::now();
+		self.update_closures();
+		self.update_signals();
+		self.update_crossings();
+		let parallel = self.parallel;
+		profile::lap(&mut self.profile, &mut clock, |p| &mut p.other);
+
+		// Update lane decisions
+		let per = self.lane_route_period;
+		let step = self.step;
+		let links = &self.links;
+		for_each_veh(&mut self.vehs, parallel, |veh| {
+			veh.stopline = None; // reapplied by the stop lines below
+			if veh.meso || veh.id.index() % per != step % per { return; }
+			veh.lane_decisions(links);
+		});
+		profile::lap(&mut self.profile, &mut clock, |p| &mut p.lane_decisions);
+
+		// Car-following model
+		let vehs = &self.vehs;
+		if parallel {
+			self.links.par_iter_mut().for_each(|link| {
+				link.update_obstacles(vehs);
+				link.index_obstacles();
+			});
+		} else {
+			self.links.iter_mut().for_each(|link| {
+				link.update_obstacles(vehs);
+				link.index_obstacles();
+			});
+		}
+		profile::lap(&mut self.profile, &mut clock, |p| &mut p.update_obstacles);
+		let mut obstacles = vec![None; self.vehs.len()];
+		for link in self.links.iter() {
+			for (i, obst) in link.get_obstacles().iter().enumerate() {
+				if let Some(veh) = obst.veh {
+					obstacles[veh.index()] = Some((link.id, i));
+				}
+			}
+		}
+		let links = &self.links;
+		for_each_veh(&mut self.vehs, parallel, |veh| {
+			if let Some((link, i)) = obstacles[veh.id.index()] {
+				links.get(link).unwrap().car_follow_model(i, veh, links);
+			}
+		});
+		profile::lap(&mut self.profile, &mut clock, |p| &mut p.car_following);
+
+		// Stop lines
+		// Each reads how clear the stop lines it conflicts with are, as updated so far during the
+		// step, so stop lines which conflict are stepped in order, but groups of them in parallel
+		let approaches = self.find_approaches(&obstacles);
+		let groups = conflict_groups(&self.stoplines);
+		let mut group_lines = groups.iter()
+			.map(|group| group.iter()
+				.map(|id| std::mem::replace(self.stoplines.get_mut(*id).unwrap(), StopLine::default()))
+				.collect::<Vec<_>>())
+			.collect::<Vec<_>>();
+		let (vehs, links, crossings) = (&self.vehs, &self.links, &self.crossings);
+		let step_group = |(group, lines): (&Vec<usize>, &mut Vec<StopLine>)| {
+			for i in 0..lines.len() {
+				let mut stopline = std::mem::replace(&mut lines[i], StopLine::default());
+				let clear_before = |id: usize| lines[group.binary_search(&id).unwrap()].clear_before;
+				stopline.step(vehs, links, crossings, &approaches[stopline.link], &clear_before);
+				lines[i] = stopline;
+			}
+		};
+		if parallel {
+			groups.par_iter().zip(group_lines.par_iter_mut()).for_each(step_group);
+		} else {
+			groups.iter().zip(group_lines.iter_mut()).for_each(step_group);
+		}
+		for stopline in group_lines.into_iter().flatten() {
+			let id = stopline.id;
+			*self.stoplines.get_mut(id).unwrap() = stopline;
+		}
+		for stopline in self.stoplines.iter_mut() {
+			for (vid, pos) in stopline.stops.drain(..) {
+				let veh = self.vehs.get_mut(vid).unwrap();
+				veh.stop(pos);
+				veh.stopline = Some(stopline.id);
+			}
+		}
+		self.hold_for_meso();
+		profile::lap(&mut self.profile, &mut clock, |p| &mut p.stop_lines);
+
+		// Integrate vehicles
+		let delta = self.step_delta;
+		let links = &self.links;
+		let changes = map_vehs(&mut self.vehs, parallel, |veh| {
+			if veh.meso {
+				return None;
+			}
+			veh.get_link(0)?;
+			veh.apply_speedlimit(links);
+			veh.integrate(delta, links).map(|change| (veh.id, change))
+		});
+		for (id, (old_link, new_link)) in changes {
+			self.links.get_mut(old_link).unwrap().remove_veh(id);
+			if new_link == !0 {
+				continue;
+			}
+			if self.links.get(new_link).unwrap().meso.is_some() {
+				self.enter_meso(id, new_link);
+			} else {
+				self.links.get_mut(new_link).unwrap().add_veh(id);
+			}
+		}
+		profile::lap(&mut self.profile, &mut clock, |p| &mut p.integration);
+
+		// Queues of mesoscopic links
+		self.step_meso();
+		profile::lap(&mut self.profile, &mut clock, |p| &mut p.meso);
+		
+		// Remove exited vehicles
+		for (id, veh) in self.vehs.remove_where(|v| v.get_link(0).is_none()) {
+			self.user_ids.remove(&veh.user_id);
+			self.release_commitments(id);
+		}
+		self.update_detectors();
+		profile::lap(&mut self.profile, &mut clock, |p| &mut p.other);
+
+		self.step += 1;
+
+		self.measure_safety();
+		self.detect_gridlock();
+		profile::lap(&mut self.profile, &mut clock, |p| &mut p.other);
+		if let Some(profile) = &mut self.profile {
+			profile.steps += 1;
+		}
+	}
+
+	/// Finds the vehicles whose routes reach each link with a stop line, beyond the link they are
+	/// on, given where each vehicle is in its link's obstacles.
+	fn find_approaches(&self, obstacles: &[Option<(usize, usize)>]) -> Vec<Vec<Approach>> {
+		let mut approaches = vec![vec![]; self.links.len()];
+		let mut has_stopline = vec![false; self.links.len()];
+		for stopline in self.stoplines.iter() {
+			has_stopline[stopline.link] = self.links.get(stopline.link).unwrap().meso.is_none();
+		}
+		if !has_stopline.contains(&true) {
+			return approaches;
+		}
+		for veh in self.vehs.iter() {
+			let rank = match obstacles[veh.id.index()] {
+				Some((_, i)) => i,
+				None => continue
+			};
+			// Stop lines only apply to vehicles which have chosen a lane on their link
+			let num_lanes = veh.get_lanes().len();
+			for (index, &link) in veh.get_links().iter().enumerate().take(num_lanes).skip(1) {
+				if has_stopline[link] {
+					approaches[link].push(Approach { veh: veh.id, index, rank });
+				}
+			}
+		}
+		approaches
+	}
+
+	pub fn get_step_delta(&self) -> f32 {
+		self.step_delta
+	}
+
+	pub fn get_vehicle_states<'a>(&'a self) -> impl Iterator<Item=VehicleState> + 'a {
+		self.vehs.iter().map(|v| v.get_state())
+	}
+
+	pub fn get_vehicle_state(&self, id: VehicleId) -> Option<VehicleState> {
+		self.vehs.get(id).map(|v| v.get_state())
+	}
+
+	pub fn num_vehicles(&self) -> usize {
+		self.vehs.iter().count()
+	}
+
+	pub fn get_link_length(&self, link: usize) -> Option<f32> {
+		self.links.get(link).map(|l| l.length)
+	}
+
+	pub fn find_route(&mut self, src_link: usize, dst_link: usize) -> Result<Vec<usize>, SimError> {
+		for &link in &[src_link, dst_link] {
+			if !self.links.has_key(link) {
+				return Err(SimError::UnknownLink(link));
+			}
+		}
+		let mut vec = vec![src_link];
+		let mut link = src_link;
+		while link != dst_link {
+			let next = Self::min_dist_to_link(&self.links, &mut self.route_table, link, dst_link);
+			if next.dist == f32::INFINITY {
+				return Err(SimError::UnreachableDestination { src_link, dst_link });
+			}
+			link = next.next_link;
+			vec.push(link);
+		}
+		Ok(vec)
+	}
+
+	fn min_dist_to_link(links: &IdMap<Link>, route_table: &mut HashMap<RouteTableKey, RouteTableEntry>,
+		src_link: usize, dst_link: usize) -> RouteTableEntry
+	{
+		if src_link == dst_link {
+			return RouteTableEntry {
+				next_link: !0,
+				dist: 0.0
+			};
+		}
+
+		let key = RouteTableKey { src_link, dst_link };
+		if !route_table.contains_key(&key) {
+			Self::route_to_link(links, route_table, dst_link);
+		}
+		route_table[&key]
+	}
+
+	/// Adds the shortest route from every link to `dst_link` to the route table, searching
+	/// backwards from it so that cycles in the network, e.g. roundabouts, are handled.
+	fn route_to_link(links: &IdMap<Link>, route_table: &mut HashMap<RouteTableKey, RouteTableEntry>, dst_link: usize) {
+		let mut dists = vec![f32::INFINITY; links.len()];
+		let mut done = vec![false; links.len()];
+		let mut queue = BinaryHeap::new();
+		dists[dst_link] = 0.0;
+		queue.push(RouteSearchEntry { dist: 0.0, link: dst_link });
+		while let Some(RouteSearchEntry { dist, link }) = queue.pop() {
+			if done[link] {
+				continue;
+			}
+			done[link] = true;
+			let link = links.get(link).unwrap();
+			let next_dist = dist + link.length + link.route_penalty;
+			for conn in link.links_in.iter() {
+				if next_dist < dists[conn.link_in] {
+					dists[conn.link_in] = next_dist;
+					queue.push(RouteSearchEntry { dist: next_dist, link: conn.link_in });
+				}
+			}
+		}
+
+		// Ties go to the first connection out of each link
+		for src in links.iter().filter(|l| l.id != dst_link) {
+			let mut next_link = !0;
+			let mut dist = f32::INFINITY;
+			for conn in src.links_out.iter() {
+				let link = links.get(conn.link_out).unwrap();
+				let next_dist = dists[conn.link_out] + link.length + link.route_penalty;
+				if next_dist < dist {
+					next_link = conn.link_out;
+					dist = next_dist;
+				}
+			}
+			let key = RouteTableKey { src_link: src.id, dst_link };
+			route_table.insert(key, RouteTableEntry { next_link, dist });
+		}
+	}
+}
+
+/// Runs `f` on every vehicle, in parallel if asked to.
+fn for_each_veh<F>(vehs: &mut IdMap<Vehicle, VehicleId>, parallel: bool, f: F) where F: Fn(&mut Vehicle) + Sync + Send {
+	if parallel {
+		vehs.par_iter_mut().for_each(f);
+	} else {
+		vehs.iter_mut().for_each(f);
+	}
+}
+
+/// Runs `f` on every vehicle, in parallel if asked to, collecting its results in vehicle order.
+fn map_vehs<T, F>(vehs: &mut IdMap<Vehicle, VehicleId>, parallel: bool, f: F) -> Vec<T>
+	where T: Send, F: Fn(&mut Vehicle) -> Option<T> + Sync + Send
+{
+	if parallel {
+		vehs.par_iter_mut().filter_map(f).collect()
+	} else {
+		vehs.iter_mut().filter_map(f).collect()
+	}
+}
+
+/// Splits the stop lines into groups which conflict with each other, directly or indirectly.
+/// Each group is sorted by id.
+fn conflict_groups(stoplines: &IdMap<StopLine>) -> Vec<Vec<usize>> {
+	fn find(parents: &mut [usize], i: usize) -> usize {
+		let mut root = i;
+		while parents[root] != root {
+			root = parents[root];
+		}
+		parents[i] = root;
+		root
+	}
+	let mut parents = (0..stoplines.len()).collect::<Vec<_>>();
+	for stopline in stoplines.iter() {
+		for conflict in stopline.conflicts.iter() {
+			let a = find(&mut parents, stopline.id);
+			let b = find(&mut parents, conflict.stopline);
+			parents[a.max(b)] = a.min(b);
+		}
+	}
+	let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+	for stopline in stoplines.iter() {
+		let root = find(&mut parents, stopline.id);
+		groups.entry(root).or_default().push(stopline.id);
+	}
+	groups.into_values().collect()
+}
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct RouteTableKey {
+	pub src_link: usize,
+	pub dst_link: usize
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct RouteTableEntry {
+	pub next_link: usize,
+	pub dist: f32
+}
+
+/// A link waiting to be searched from, ordered so the nearest is taken from the queue first.
+#[derive(PartialEq)]
+struct RouteSearchEntry {
+	dist: f32,
+	link: usize
+}
+
+impl Eq for RouteSearchEntry {}
+
+impl Ord for RouteSearchEntry {
+	fn cmp(&self, other: &Self) -> Ordering {
+		other.dist.partial_cmp(&self.dist).unwrap().then(other.link.cmp(&self.link))
+	}
+}
+
+impl PartialOrd for RouteSearchEntry {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+// todo: `len` and `sight_pos` are not used by the stop line logic yet
+#[allow(dead_code)]
+#[derive(Clone, Serialize, Deserialize)]
+struct StopLine {
+	id: usize,
+	link: usize,
+	lane: u8,
+	pos: f32,
+	len: f32,
+	kind: StopLineType,
+	sight_pos: f32,
+	conflicts: Vec<Conflict>,
+	/// The pedestrian crossings ahead, which must be clear of pedestrians, with `stopline` holding
+	/// the crossing's id
+	crossings: Vec<Conflict>,
+	#[serde(serialize_with = "snapshot::sorted::serialize_set", deserialize_with = "snapshot::sorted::deserialize_set")]
+	committed_vehs: HashSet<VehicleId>,
+	time_until_enter: f32,
+	min_arrival: usize,
+	clear_before: f32,
+	/// The conflicting stop lines which kept a vehicle waiting during the last step
+	blocked_by: Vec<usize>,
+	/// The vehicles held back during the current step, and where they must stop, which are
+	/// applied to the vehicles once every stop line has been stepped
+	#[serde(skip)]
+	stops: Vec<(VehicleId, f32)>
+}
+
+impl StopLine {
+	fn default() -> Self {
+		Self {
+			id: 0,
+			link: 0,
+			lane: 0,
+			pos: 0.0,
+			len: 0.0,
+			kind: StopLineType::None,
+			sight_pos: 0.0,
+			conflicts: vec![],
+			crossings: vec![],
+			committed_vehs: HashSet::new(),
+			time_until_enter: 0.0,
+			min_arrival: 0,
+			clear_before: 0.0,
+			blocked_by: vec![],
+			stops: vec![]
+		}
+	}
+
+	fn step(&mut self, vehs: &IdMap<Vehicle, VehicleId>, links: &IdMap<Link>, crossings: &IdMap<pedestrian::Crossing>, approaches: &[Approach], clear_before: &dyn Fn(usize) -> f32) {
+		// Reset statistics, remove cleared vehicles
+		self.time_until_enter = f32::INFINITY;
+		self.min_arrival = usize::MAX;
+		self.clear_before = f32::INFINITY;
+		self.blocked_by.clear();
+		let mut cleared_vehs = vec![];
+		for vid in self.committed_vehs.iter().cloned() {
+			if let Some(veh) = vehs.get(vid) {
+				if veh.link == self.link {
+					let pos = veh.pos - 0.5 * veh.len;
+					if pos < self.clear_before {
+						self.clear_before = pos;
+					}
+					continue;
+				}
+				if !veh.get_links().contains(&self.link) {
+					cleared_vehs.push(vid);
+					continue;
+				}
+				self.clear_before = 0.0;
+			} else {
+				cleared_vehs.push(vid);
+			}
+		}
+		for vid in cleared_vehs.iter() {
+			self.committed_vehs.remove(vid);
+		}
+		// Apply stopline to upstream vehicles
+		let link = links.get(self.link).unwrap();
+		for vid in link.get_vehicles().rev() {
+			let veh = vehs.get(vid).unwrap();
+			if veh.lane != self.lane || veh.pos > self.pos {
+				continue;
+			}
+			if self.apply_to_veh(veh, self.pos, crossings, clear_before) {
+				return;
+			}
+		}
+		// Then to vehicles on upstream links, in the order of a depth-first search upstream from the
+		// stop line, skipping vehicles behind one which is stopped
+		let mut approaches = approaches.iter()
+			.filter(|a| vehs.get(a.veh).unwrap().get_lane(a.index) == Some(self.lane))
+			.cloned()
+			.collect::<Vec<_>>();
+		approaches.sort_by(|a, b| a.search_order(b, vehs, links));
+		let mut stopped: Option<Approach> = None;
+		for approach in approaches {
+			if stopped.map(|s| approach.is_behind(&s, vehs)).unwrap_or(false) {
+				continue;
+			}
+			let veh = vehs.get(approach.veh).unwrap();
+			let mut pos = self.pos;
+			for link in veh.get_links()[..approach.index].iter().rev() {
+				pos += links.get(*link).unwrap().length;
+			}
+			if self.apply_to_veh(veh, pos, crossings, clear_before) {
+				stopped = Some(approach);
+			}
+		}
+	}
+
+	fn apply_to_veh(&mut self, veh: &Vehicle, pos: f32, crossings: &IdMap<pedestrian::Crossing>, clear_before: &dyn Fn(usize) -> f32) -> bool {
+		// todo:
+		// - Copy code for this function from C#
+		// - Calculate time_until_enter for stopline
+		// - Commits, ensuring they are cleared properly
+		// - Ensuring other fields of stopline are calculated (e.g. clear_before)
+
+		// If committed, keep going
+		if self.committed_vehs.contains(&veh.id) {
+			return false;
+		}
+
+		match self.kind {
+			// Stop at a red light, or an amber one if it is not too late
+			StopLineType::TrafficLight { state: TrafficLightState::Red } => {
+				self.stops.push((veh.id, pos));
+				return true;
+			}
+			StopLineType::TrafficLight { state: TrafficLightState::Amber } if veh.can_stop_before(pos) => {
+				self.stops.push((veh.id, pos));
+				return true;
+			}
+			StopLineType::TrafficLight { state: TrafficLightState::Amber } => {}
+			// Keep going towards a green light, committing once close to it
+			StopLineType::TrafficLight { state: TrafficLightState::Green } => if veh.pos < pos - 6.0 {
+				return false;
+			}
+			// Give way to pedestrians waiting at a zebra crossing if there is time to stop, otherwise keep
+			// going towards it unless pedestrians are on it, committing once close to it or too late
+			// to stop
+			StopLineType::Zebra if veh.can_stop_before(pos) && self.has_waiting(crossings) => {
+				self.stops.push((veh.id, pos));
+				return true;
+			}
+			StopLineType::Zebra => if veh.pos < pos - 6.0 && veh.can_stop_before(pos) && self.is_clear(crossings, clear_before) {
+				return false;
+			}
+			// If before the stop sign, slow down
+			_ => if veh.pos < pos - 6.0 {
+				self.stops.push((veh.id, pos));
+				return true;
+			}
+		}
+
+		// If not clear, stop
+		if !self.is_clear(crossings, clear_before) {
+			self.stops.push((veh.id, pos));
+			self.blocked_by = self.conflicts.iter()
+				.filter(|c| clear_before(c.stopline) < c.max_pos)
+				.map(|c| c.stopline)
+				.collect();
+			return true;
+		}
+
+		// Commit
+		//if veh.pos > pos - 6.0 {
+			self.committed_vehs.insert(veh.id);
+			self.clear_before = 0.0;
+		//}
+		
+		false
+	}
+
+	fn has_waiting(&self, crossings: &IdMap<pedestrian::Crossing>) -> bool {
+		self.crossings.iter().any(|c| crossings.get(c.stopline).unwrap().has_waiting())
+	}
+
+	fn is_clear(&self, crossings: &IdMap<pedestrian::Crossing>, clear_before: &dyn Fn(usize) -> f32) -> bool {
+		self.conflicts.iter().all(|c| clear_before(c.stopline) >= c.max_pos)
+			&& self.crossings.iter().all(|c| crossings.get(c.stopline).unwrap().clear_before >= c.max_pos)
+	}
+}
+
+/// A vehicle whose route passes through a link with a stop line.
+#[derive(Clone, Copy)]
+struct Approach {
+	veh: VehicleId,
+	/// Where the link is in the vehicle's route
+	index: usize,
+	/// Where the vehicle is in the obstacles of the link it is on
+	rank: usize
+}
+
+impl Approach {
+	/// The links from the vehicle's current link to the one with the stop line.
+	fn path<'a>(&self, vehs: &'a IdMap<Vehicle, VehicleId>) -> &'a [usize] {
+		&vehs.get(self.veh).unwrap().get_links()[..=self.index]
+	}
+
+	/// Orders vehicles as a depth-first search upstream from the stop line would reach them,
+	/// following the incoming connections of each link in order, and vehicles on a link from the
+	/// front.
+	fn search_order(&self, other: &Approach, vehs: &IdMap<Vehicle, VehicleId>, links: &IdMap<Link>) -> Ordering {
+		let (a, b) = (self.path(vehs), other.path(vehs));
+		for k in 1.. {
+			match (k < a.len(), k < b.len()) {
+				(false, false) => return other.rank.cmp(&self.rank),
+				(false, true) => return Ordering::Less,
+				(true, false) => return Ordering::Greater,
+				(true, true) => {
+					let (link_a, link_b) = (a[a.len() - 1 - k], b[b.len() - 1 - k]);
+					if link_a != link_b {
+						let links_in = &links.get(a[a.len() - k]).unwrap().links_in;
+						let conn = |link| links_in.iter().position(|c| c.link_in == link);
+						return conn(link_a).cmp(&conn(link_b));
+					}
+				}
+			}
+		}
+		unreachable!()
+	}
+
+	/// Whether the search would reach this vehicle through or behind `other`.
+	fn is_behind(&self, other: &Approach, vehs: &IdMap<Vehicle, VehicleId>) -> bool {
+		let (a, b) = (self.path(vehs), other.path(vehs));
+		a.ends_with(b)
+	}
+}
+
+pub struct StopLineBuilder {
+	id: usize,
+	link: usize,
+	lane: u8,
+	pos: f32,
+	len: Option<f32>,
+	kind: Option<StopLineType>,
+	sight_dist: Option<f32>,
+	conflicts: Vec<Conflict>
+}
+
+impl StopLineBuilder {
+	pub fn new(id: usize, link: usize, lane: u8, pos: f32) -> Self {
+		Self {
+			id,
+			link,
+			lane,
+			pos,
+			len: None,
+			kind: None,
+			sight_dist: None,
+			conflicts: vec![]
+		}
+	}
+
+	pub fn with_length(mut self, len: f32) -> Self {
+		self.len = Some(len);
+		self
+	}
+
+	pub fn of_type(mut self, kind: StopLineType) -> Self {
+		self.kind = Some(kind);
+		self
+	}
+
+	pub fn with_sight_dist(mut self, dist: f32) -> Self {
+		self.sight_dist = Some(dist);
+		self
+	}
+
+	pub fn conflicts_with(mut self, stopline: usize, priority: Ordering, max_pos: f32) -> Self {
+		self.conflicts.push(Conflict {
+			stopline,
+			priority,
+			max_pos
+		});
+		self
+	}
+
+	pub fn add_to_simulation(self, simulation: &mut Simulation) -> Result<(), SimError> {
+		let link = simulation.links.get(self.link).ok_or(SimError::UnknownLink(self.link))?;
+		if self.lane as usize >= link.lanes.len() {
+			return Err(SimError::UnknownLane { link: self.link, lane: self.lane });
+		}
+		if !(0.0..=link.length).contains(&self.pos) {
+			return Err(SimError::InvalidGeometry(format!("Stop line position {} is not on link {}", self.pos, self.link)));
+		}
+		for conflict in self.conflicts.iter() {
+			if !simulation.stoplines.has_key(conflict.stopline) {
+				return Err(SimError::UnknownStopLine(conflict.stopline));
+			}
+		}
+		let len = self.len.unwrap_or(link.length - self.pos);
+		if len < 0.0 {
+			return Err(SimError::InvalidGeometry(format!("Stop line length must not be negative, got {}", len)));
+		}
+		// An unspecified type behaves as an uncontrolled stop line
+		let kind = self.kind.unwrap_or(StopLineType::None);
+		let sight_pos = self.pos - self.sight_dist.unwrap_or(50.0);
+		let id = self.id;
+		simulation.stoplines.insert(id, StopLine {
+			id: self.id,
+			link: self.link,
+			lane: self.lane,
+			pos: self.pos,
+			len,
+			kind,
+			sight_pos,
+			conflicts: self.conflicts,
+			crossings: vec![],
+			committed_vehs: HashSet::new(),
+			time_until_enter: 0.0,
+			min_arrival: usize::MAX,
+			clear_before: 0.0,
+			blocked_by: vec![],
+			stops: vec![]
+		}).map(|_| ()).map_err(|e| SimError::from_insert(e, "stop line", id))
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum StopLineType {
+	None,
+	Giveway,
+	Stop,
+	/// Before a zebra crossing, where vehicles give way to pedestrians
+	Zebra,
+	/// Keeps its state unless a controller sets it
+	TrafficLight {
+		state: TrafficLightState
+	}
+}
+
+impl std::str::FromStr for StopLineType {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<StopLineType, ()> {
+		match s {
+			"none" => Ok(StopLineType::None),
+			"giveway" => Ok(StopLineType::Giveway),
+			"stop" => Ok(StopLineType::Stop),
+			"zebra" => Ok(StopLineType::Zebra),
+			"light" => Ok(StopLineType::TrafficLight {
+				state: TrafficLightState::Red
+			}),
+			_ => Err(())
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TrafficLightState {
+	Green,
+	Amber,
+	Red
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct Conflict {
+	stopline: usize,
+	#[allow(dead_code)] // todo: give way according to priority
+	#[serde(with = "snapshot::ordering")]
+	priority: Ordering,
+	max_pos: f32
+}
\ No newline at end of file
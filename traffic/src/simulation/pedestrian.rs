@@ -0,0 +1,206 @@
+use core::cmp::Ordering;
+use serde::{Serialize, Deserialize};
+use super::{Simulation, SimError, Conflict, TrafficLightState};
+
+/// How pedestrians are given the right to cross.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CrossingType {
+	/// Pedestrians cross whenever no vehicle is committed to crossing ahead of them, and vehicles
+	/// which can still stop give way to them
+	#[default]
+	Zebra,
+	/// Pedestrians only start to cross while their signal, set by a controller, is green
+	Signalised
+}
+
+/**
+ * A pedestrian crossing, which conflicts with the stop lines of the lanes it crosses.
+ *
+ * It takes part in the same conflicts as stop lines: a vehicle stop line is not clear while a
+ * pedestrian is on the crossing, and pedestrians wait until the vehicles committed to each
+ * conflicting stop line have cleared it.
+ * */
+#[derive(Clone, Serialize, Deserialize)]
+pub(super) struct Crossing {
+	id: usize,
+	length: f32,
+	kind: CrossingType,
+	pub(super) signal: TrafficLightState,
+	/// The vehicle stop lines whose vehicles must be past `max_pos` for pedestrians to start
+	conflicts: Vec<Conflict>,
+	peds: Vec<Pedestrian>,
+	/// 0 while any pedestrian is on the crossing, as a vehicle cannot pass them wherever they are
+	pub(super) clear_before: f32
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Pedestrian {
+	user_id: usize,
+	speed: f32,
+	/// The distance walked from the kerb they started at
+	dist: f32,
+	/// Whether they walk from the end of the crossing to its start
+	reverse: bool,
+	walking: bool,
+	/// The time spent waiting at the kerb
+	wait: f32
+}
+
+/// The state of a pedestrian at or on a crossing.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct PedestrianState {
+	pub user_id: usize,
+	pub crossing: usize,
+	/// The distance from the start of the crossing
+	pub pos: f32,
+	pub walking: bool,
+	/// The time spent waiting to cross, in seconds
+	pub wait: f32
+}
+
+pub struct CrossingBuilder {
+	id: usize,
+	length: f32,
+	kind: Option<CrossingType>,
+	conflicts: Vec<(usize, f32)>
+}
+
+impl CrossingBuilder {
+	pub fn new(id: usize, length: f32) -> Self {
+		Self {
+			id,
+			length,
+			kind: None,
+			conflicts: vec![]
+		}
+	}
+
+	pub fn of_type(mut self, kind: CrossingType) -> Self {
+		self.kind = Some(kind);
+		self
+	}
+
+	/// Makes the crossing conflict with the stop line of a lane it crosses, whose vehicles must be
+	/// past `max_pos` on its link before pedestrians can start.
+	pub fn conflicts_with(mut self, stopline: usize, max_pos: f32) -> Self {
+		self.conflicts.push((stopline, max_pos));
+		self
+	}
+
+	pub fn add_to_simulation(self, simulation: &mut Simulation) -> Result<(), SimError> {
+		if !self.length.is_finite() || self.length <= 0.0 {
+			return Err(SimError::InvalidGeometry(format!("Crossing length must be positive, got {}", self.length)));
+		}
+		for &(stopline, _) in self.conflicts.iter() {
+			if !simulation.stoplines.has_key(stopline) {
+				return Err(SimError::UnknownStopLine(stopline));
+			}
+		}
+		let id = self.id;
+		simulation.crossings.insert(id, Crossing {
+			id,
+			length: self.length,
+			// An unspecified type behaves as a zebra crossing
+			kind: self.kind.unwrap_or_default(),
+			signal: TrafficLightState::Red,
+			conflicts: self.conflicts.iter()
+				.map(|&(stopline, max_pos)| Conflict { stopline, priority: Ordering::Greater, max_pos })
+				.collect(),
+			peds: vec![],
+			clear_before: f32::INFINITY
+		}).map_err(|e| SimError::from_insert(e, "crossing", id))?;
+		// Vehicles wait for the whole crossing to clear
+		for &(stopline, _) in self.conflicts.iter() {
+			simulation.stoplines.get_mut(stopline).unwrap().crossings.push(Conflict {
+				stopline: id,
+				priority: Ordering::Less,
+				max_pos: self.length
+			});
+		}
+		Ok(())
+	}
+}
+
+impl Crossing {
+	/// Whether pedestrians are waiting to start across a zebra crossing, which vehicles that can
+	/// still stop give way to.
+	pub(super) fn has_waiting(&self) -> bool {
+		self.kind == CrossingType::Zebra && self.peds.iter().any(|p| !p.walking)
+	}
+}
+
+impl Simulation {
+	/// Adds a pedestrian waiting at the start of a crossing, or at its end if `reverse`, who walks
+	/// across at `speed` and leaves the simulation on the other side.
+	pub fn add_pedestrian(&mut self, user_id: usize, crossing: usize, speed: f32, reverse: bool) -> Result<(), SimError> {
+		if !self.crossings.has_key(crossing) {
+			return Err(SimError::UnknownCrossing(crossing));
+		}
+		if !speed.is_finite() || speed <= 0.0 {
+			return Err(SimError::InvalidSpeed(speed));
+		}
+		if self.crossings.iter().any(|c| c.peds.iter().any(|p| p.user_id == user_id)) {
+			return Err(SimError::DuplicateId { kind: "pedestrian", id: user_id });
+		}
+		self.crossings.get_mut(crossing).unwrap().peds.push(Pedestrian {
+			user_id,
+			speed,
+			dist: 0.0,
+			reverse,
+			walking: false,
+			wait: 0.0
+		});
+		Ok(())
+	}
+
+	pub fn get_pedestrian_states<'a>(&'a self) -> impl Iterator<Item=PedestrianState> + 'a {
+		self.crossings.iter().flat_map(|c| c.peds.iter().map(move |p| PedestrianState {
+			user_id: p.user_id,
+			crossing: c.id,
+			pos: if p.reverse { c.length - p.dist } else { p.dist },
+			walking: p.walking,
+			wait: p.wait
+		}))
+	}
+
+	pub fn num_pedestrians(&self) -> usize {
+		self.crossings.iter().map(|c| c.peds.len()).sum()
+	}
+
+	/// The state of a crossing's pedestrian signal, or `None` if it is not signalised.
+	pub fn get_crossing_signal(&self, crossing: usize) -> Option<TrafficLightState> {
+		let crossing = self.crossings.get(crossing)?;
+		match crossing.kind {
+			CrossingType::Signalised => Some(crossing.signal),
+			CrossingType::Zebra => None
+		}
+	}
+
+	/// Starts pedestrians across the crossings which are clear, and walks those already crossing.
+	pub(super) fn update_crossings(&mut self) {
+		let delta = self.step_delta;
+		let stoplines = &self.stoplines;
+		for crossing in self.crossings.iter_mut() {
+			// Vehicles committed to a conflicting stop line are let through first
+			let clear = crossing.conflicts.iter()
+				.all(|c| stoplines.get(c.stopline).map(|s| s.clear_before >= c.max_pos).unwrap_or(true));
+			let may_start = clear && match crossing.kind {
+				CrossingType::Zebra => true,
+				CrossingType::Signalised => crossing.signal == TrafficLightState::Green
+			};
+			for ped in crossing.peds.iter_mut() {
+				// Once started, pedestrians keep walking whatever their signal
+				ped.walking |= may_start;
+				if ped.walking {
+					ped.dist += ped.speed * delta;
+				} else {
+					ped.wait += delta;
+				}
+			}
+			let length = crossing.length;
+			crossing.peds.retain(|p| p.dist < length);
+			crossing.clear_before = if crossing.peds.iter().any(|p| p.walking) { 0.0 } else { f32::INFINITY };
+		}
+	}
+}
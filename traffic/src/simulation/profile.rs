@@ -0,0 +1,48 @@
+use std::time::{Duration, Instant};
+use super::Simulation;
+
+/// The time spent in each phase of `Simulation::step`, added up over the steps taken while
+/// profiling.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StepProfile {
+	pub steps: usize,
+	pub lane_decisions: Duration,
+	/// Updating, sorting and indexing the obstacles of each link
+	pub update_obstacles: Duration,
+	pub car_following: Duration,
+	pub stop_lines: Duration,
+	/// Applying speed limits, integrating and moving vehicles between links
+	pub integration: Duration,
+	/// Moving vehicles through the queues of mesoscopic links
+	pub meso: Duration,
+	/// Closures, safety measures and gridlock detection
+	pub other: Duration
+}
+
+impl StepProfile {
+	pub fn total(&self) -> Duration {
+		self.lane_decisions + self.update_obstacles + self.car_following + self.stop_lines
+			+ self.integration + self.meso + self.other
+	}
+}
+
+impl Simulation {
+	/// Starts timing each phase of every step from now on, or stops if `false`.
+	pub fn set_profiling(&mut self, profiling: bool) {
+		self.profile = if profiling { Some(StepProfile::default()) } else { None };
+	}
+
+	/// The time spent in each phase since profiling started.
+	pub fn get_profile(&self) -> Option<&StepProfile> {
+		self.profile.as_ref()
+	}
+}
+
+/// Adds the time since `start` to a phase of the profile and restarts the clock, if profiling.
+pub(super) fn lap(profile: &mut Option<StepProfile>, start: &mut Instant, phase: fn(&mut StepProfile) -> &mut Duration) {
+	if let Some(profile) = profile {
+		let now = Instant::now();
+		*phase(profile) += now - *start;
+		*start = now;
+	}
+}
@@ -0,0 +1,299 @@
+use std::collections::{BTreeMap, BTreeSet};
+use serde::{Serialize, Deserialize};
+use super::{Simulation, Event, EventKind, VehicleId};
+
+/**
+ * Thresholds below (or for DRAC, above) which a surrogate safety measure counts as a conflict.
+ * The defaults are the values commonly used in the literature.
+ * */
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SafetyConfig {
+	/// Time-to-collision, in seconds
+	pub ttc: f32,
+	/// Post-encroachment time, in seconds
+	pub pet: f32,
+	/// Deceleration rate to avoid a crash, in m/s^2
+	pub drac: f32
+}
+
+impl Default for SafetyConfig {
+	fn default() -> Self {
+		Self {
+			ttc: 1.5,
+			pet: 1.5,
+			drac: 3.35
+		}
+	}
+}
+
+/// The surrogate safety measures between a vehicle and the vehicle ahead of it in the last step.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PairMeasure {
+	pub link: usize,
+	pub follower: usize,
+	pub leader: usize,
+	/// The distance between the front of the follower and the rear of the leader
+	pub gap: f32,
+	/// Time-to-collision, if the follower is closing in on the leader
+	pub ttc: Option<f32>,
+	/// Deceleration rate to avoid a crash, zero if the follower is not closing in
+	pub drac: f32
+}
+
+/**
+ * Safety statistics aggregated over a run, for a link or the whole network.
+ *
+ * Conflicts are counted once per encounter, however many steps a pair of vehicles spends below
+ * the threshold.
+ * */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SafetyStats {
+	/// Total time spent by vehicles on the link, in vehicle-seconds, for normalising the counts
+	pub vehicle_time: f32,
+	pub collisions: usize,
+	pub ttc_conflicts: usize,
+	pub min_ttc: Option<f32>,
+	pub drac_conflicts: usize,
+	pub max_drac: f32,
+	pub pet_conflicts: usize,
+	pub min_pet: Option<f32>
+}
+
+impl SafetyStats {
+	/// Adds the statistics of another link to these.
+	pub fn merge(&mut self, other: &SafetyStats) {
+		self.vehicle_time += other.vehicle_time;
+		self.collisions += other.collisions;
+		self.ttc_conflicts += other.ttc_conflicts;
+		self.min_ttc = min_option(self.min_ttc, other.min_ttc);
+		self.drac_conflicts += other.drac_conflicts;
+		self.max_drac = self.max_drac.max(other.max_drac);
+		self.pet_conflicts += other.pet_conflicts;
+		self.min_pet = min_option(self.min_pet, other.min_pet);
+	}
+}
+
+fn min_option(a: Option<f32>, b: Option<f32>) -> Option<f32> {
+	match (a, b) {
+		(Some(a), Some(b)) => Some(a.min(b)),
+		(a, None) => a,
+		(None, b) => b
+	}
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub(super) struct SafetyState {
+	/// Whether to measure safety at the end of each step
+	enabled: bool,
+	config: SafetyConfig,
+	stats: BTreeMap<usize, SafetyStats>,
+	pairs: Vec<PairMeasure>,
+	// Pairs of vehicles (follower, leader) overlapping or below a threshold in the last step
+	colliding: BTreeSet<(VehicleId, VehicleId)>,
+	ttc_pairs: BTreeSet<(VehicleId, VehicleId)>,
+	drac_pairs: BTreeSet<(VehicleId, VehicleId)>,
+	/// The last vehicle to clear each conflict area and when, by stop line and conflict index
+	last_clear: BTreeMap<(usize, usize), (usize, usize)>
+}
+
+impl Simulation {
+	pub fn set_safety_config(&mut self, config: SafetyConfig) {
+		self.safety.config = config;
+	}
+
+	pub fn get_safety_config(&self) -> SafetyConfig {
+		self.safety.config
+	}
+
+	/// Starts measuring collisions and surrogate safety measures at the end of every step, or
+	/// stops if `false`. Off by default, as it takes another pass over the vehicles on each link.
+	pub fn set_safety_measures(&mut self, enabled: bool) {
+		self.safety.enabled = enabled;
+		if !enabled {
+			// Encounters in progress are forgotten, so none are counted twice if measuring resumes
+			let stats = std::mem::take(&mut self.safety.stats);
+			self.safety = SafetyState { enabled, config: self.safety.config, stats, ..SafetyState::default() };
+		}
+	}
+
+	/// The safety measures of each following pair of vehicles in the last step.
+	pub fn get_pair_measures(&self) -> &[PairMeasure] {
+		&self.safety.pairs
+	}
+
+	/// The safety statistics of each link which has had vehicles on it.
+	pub fn get_safety_stats<'a>(&'a self) -> impl Iterator<Item=(usize, &'a SafetyStats)> + 'a {
+		self.safety.stats.iter().map(|(link, stats)| (*link, stats))
+	}
+
+	/// The safety statistics of the whole network.
+	pub fn get_total_safety_stats(&self) -> SafetyStats {
+		let mut total = SafetyStats::default();
+		for stats in self.safety.stats.values() {
+			total.merge(stats);
+		}
+		total
+	}
+
+	/// Detects overlapping vehicles and computes the surrogate safety measures of the last step.
+	pub(super) fn measure_safety(&mut self) {
+		if !self.safety.enabled {
+			return;
+		}
+		let step = self.step;
+		let delta = self.step_delta;
+		let config = self.safety.config;
+		for link in self.links.iter_mut() {
+			link.update_obstacles(&self.vehs);
+		}
+
+		// Following pairs, found from the vehicle footprints on each link
+		// todo: pairs which straddle the end of a link, and vehicles approaching lane closures
+		let mut pairs = vec![];
+		let mut colliding = BTreeSet::new();
+		for link in self.links.iter() {
+			let obsts = link.get_obstacles();
+			for (i, a) in obsts.iter().enumerate() {
+				let a_id = match a.veh {
+					Some(id) => id,
+					None => continue
+				};
+				let follower = self.vehs.get(a_id).unwrap();
+				self.safety.stats.entry(link.id).or_default().vehicle_time += delta;
+				let front = a.pos + follower.len;
+				for b in obsts[(i + 1)..].iter() {
+					let b_id = match b.veh {
+						Some(id) => id,
+						None => continue
+					};
+					let leader = self.vehs.get(b_id).unwrap();
+					if (follower.lat - leader.lat).abs() >= 0.5 * (follower.wid + leader.wid) {
+						continue;
+					}
+					let gap = b.pos - front;
+					if gap <= 0.0 {
+						colliding.insert((a_id, b_id));
+						continue;
+					}
+					let closing = follower.vel - leader.vel;
+					let (ttc, drac) = if closing > 0.0 {
+						(Some(gap / closing), closing * closing / (2.0 * gap))
+					} else {
+						(None, 0.0)
+					};
+					pairs.push((a_id, b_id, PairMeasure {
+						link: link.id,
+						follower: follower.user_id,
+						leader: leader.user_id,
+						gap,
+						ttc,
+						drac
+					}));
+					break;
+				}
+			}
+		}
+
+		for &(a, b) in colliding.iter() {
+			if self.safety.colliding.contains(&(a, b)) {
+				continue;
+			}
+			let link = self.vehs.get(a).unwrap().link;
+			self.safety.stats.entry(link).or_default().collisions += 1;
+			self.events.push(Event {
+				step,
+				kind: EventKind::Collision {
+					link,
+					user_ids: [self.vehs.get(a).unwrap().user_id, self.vehs.get(b).unwrap().user_id]
+				}
+			});
+		}
+		self.safety.colliding = colliding;
+
+		let mut ttc_pairs = BTreeSet::new();
+		let mut drac_pairs = BTreeSet::new();
+		for (a, b, measure) in pairs.iter() {
+			let key = (*a, *b);
+			let stats = self.safety.stats.entry(measure.link).or_default();
+			stats.min_ttc = min_option(stats.min_ttc, measure.ttc);
+			stats.max_drac = stats.max_drac.max(measure.drac);
+			let mut new_conflict = false;
+			if measure.ttc.map(|ttc| ttc < config.ttc).unwrap_or(false) {
+				ttc_pairs.insert(key);
+				if !self.safety.ttc_pairs.contains(&key) {
+					stats.ttc_conflicts += 1;
+					new_conflict = true;
+				}
+			}
+			if measure.drac > config.drac {
+				drac_pairs.insert(key);
+				if !self.safety.drac_pairs.contains(&key) {
+					stats.drac_conflicts += 1;
+					new_conflict = true;
+				}
+			}
+			let was_conflict = self.safety.ttc_pairs.contains(&key) || self.safety.drac_pairs.contains(&key);
+			if new_conflict && !was_conflict {
+				self.events.push(Event {
+					step,
+					kind: EventKind::NearMiss {
+						link: measure.link,
+						follower: measure.follower,
+						leader: measure.leader,
+						ttc: measure.ttc,
+						drac: measure.drac
+					}
+				});
+			}
+		}
+		self.safety.ttc_pairs = ttc_pairs;
+		self.safety.drac_pairs = drac_pairs;
+		self.safety.pairs = pairs.into_iter().map(|(_, _, measure)| measure).collect();
+
+		// Post-encroachment time, from when a vehicle clears a conflict area until one from the
+		// conflicting stop line reaches its own stop line
+		// todo: vehicles which cross a stop line and leave its link within a single step
+		for stopline in self.stoplines.iter() {
+			for (i, conflict) in stopline.conflicts.iter().enumerate() {
+				let other = self.stoplines.get(conflict.stopline).unwrap();
+				for vid in self.links.get(other.link).unwrap().get_vehicles() {
+					let veh = self.vehs.get(vid).unwrap();
+					let rear = veh.pos - 0.5 * veh.len;
+					if veh.lane == other.lane && rear >= conflict.max_pos && rear - veh.vel * delta < conflict.max_pos {
+						self.safety.last_clear.insert((stopline.id, i), (veh.user_id, step));
+					}
+				}
+			}
+		}
+		for stopline in self.stoplines.iter() {
+			for vid in self.links.get(stopline.link).unwrap().get_vehicles() {
+				let veh = self.vehs.get(vid).unwrap();
+				let front = veh.pos + 0.5 * veh.len;
+				if veh.lane != stopline.lane || front < stopline.pos || front - veh.vel * delta >= stopline.pos {
+					continue;
+				}
+				for i in 0..stopline.conflicts.len() {
+					let (other_id, cleared) = match self.safety.last_clear.get(&(stopline.id, i)) {
+						Some(clear) if clear.0 != veh.user_id => *clear,
+						_ => continue
+					};
+					let pet = (step - cleared) as f32 * delta;
+					let stats = self.safety.stats.entry(stopline.link).or_default();
+					stats.min_pet = min_option(stats.min_pet, Some(pet));
+					if pet < config.pet {
+						stats.pet_conflicts += 1;
+						self.events.push(Event {
+							step,
+							kind: EventKind::Encroachment {
+								stop_line: stopline.id,
+								user_id: veh.user_id,
+								conflicting_user_id: other_id,
+								pet
+							}
+						});
+					}
+				}
+			}
+		}
+	}
+}
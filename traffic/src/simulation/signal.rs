@@ -0,0 +1,548 @@
+use serde::{Serialize, Deserialize};
+use super::{Simulation, SimError, StopLineType, TrafficLightState, VehicleId};
+
+/// A loop detector covering part of a lane, e.g. upstream of a traffic light.
+/// Detectors on mesoscopic links see no vehicles.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Detector {
+	pub link: usize,
+	pub lane: u8,
+	pub pos: f32,
+	pub len: f32
+}
+
+/// What a detector measured during the last step.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DetectorReading {
+	/// Whether any vehicle is over the detector
+	pub occupied: bool,
+	/// The number of vehicles which reached the detector during the step
+	pub arrivals: usize,
+	/// The time since a vehicle was last over the detector, in seconds
+	pub gap: f32
+}
+
+impl DetectorReading {
+	/// Whether the detector saw a vehicle during the step.
+	pub fn is_actuated(&self) -> bool {
+		self.occupied || self.arrivals > 0
+	}
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(super) struct DetectorState {
+	detector: Detector,
+	/// The vehicles over the detector at the end of the last step
+	vehs: Vec<VehicleId>,
+	reading: DetectorReading
+}
+
+/**
+ * Decides the state of a set of traffic lights once a step, from what a set of detectors saw.
+ *
+ * Implement this to plug in an adaptive algorithm. The built-in [`ActuatedController`] serves as
+ * an example.
+ * */
+pub trait Controller: Send {
+	/// The traffic light stop lines the controller sets.
+	fn stop_lines(&self) -> Vec<usize>;
+
+	/// The signalised pedestrian crossings the controller sets.
+	fn crossings(&self) -> Vec<usize> {
+		vec![]
+	}
+
+	/// The detectors the controller reads.
+	fn detectors(&self) -> Vec<usize>;
+
+	/// Advances the controller by `delta` seconds to `time`, the simulation clock, given the
+	/// reading of each of its detectors in the order of `detectors`, and returns the state of each
+	/// of its stop lines in the order of `stop_lines`, followed by each of its crossings in the
+	/// order of `crossings`.
+	fn step(&mut self, time: f32, delta: f32, readings: &[DetectorReading]) -> Vec<TrafficLightState>;
+
+	/// The state of the controller to save in a snapshot. Snapshots of a simulation with a
+	/// controller which cannot be saved fail.
+	///
+	/// User-defined controllers can save themselves as [`SavedController::Custom`], and are
+	/// rebuilt by the function given to `Simulation::load_snapshot_with`.
+	fn save(&self) -> Option<SavedController> {
+		None
+	}
+}
+
+/// A controller as saved in a snapshot.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SavedController {
+	Actuated(ActuatedController),
+	Coordinated(CoordinatedController),
+	/// A user-defined controller, identified by `name`, with whatever state it needs to rebuild it
+	Custom {
+		name: String,
+		state: Vec<u8>
+	}
+}
+
+impl SavedController {
+	/// Rebuilds a built-in controller, or a user-defined one with `restore`.
+	pub(super) fn restore<F>(self, restore: &mut F) -> Result<Box<dyn Controller>, SimError>
+	where F: FnMut(&str, &[u8]) -> Result<Box<dyn Controller>, SimError> {
+		match self {
+			SavedController::Actuated(c) => Ok(Box::new(c)),
+			SavedController::Coordinated(c) => Ok(Box::new(c)),
+			SavedController::Custom { name, state } => restore(&name, &state)
+		}
+	}
+}
+
+pub(super) struct ControllerSlot {
+	controller: Box<dyn Controller>,
+	stop_lines: Vec<usize>,
+	crossings: Vec<usize>,
+	detectors: Vec<usize>
+}
+
+/// A controller slot as saved in a snapshot, whose controller is rebuilt once it has been read.
+#[derive(Serialize, Deserialize)]
+pub(super) struct SavedSlot {
+	controller: SavedController,
+	stop_lines: Vec<usize>,
+	crossings: Vec<usize>,
+	detectors: Vec<usize>
+}
+
+impl ControllerSlot {
+	pub(super) fn save(&self) -> Result<SavedSlot, SimError> {
+		let controller = self.controller.save()
+			.ok_or_else(|| SimError::InvalidSnapshot("A controller cannot be saved".into()))?;
+		Ok(SavedSlot {
+			controller,
+			stop_lines: self.stop_lines.clone(),
+			crossings: self.crossings.clone(),
+			detectors: self.detectors.clone()
+		})
+	}
+}
+
+impl SavedSlot {
+	pub(super) fn restore<F>(self, restore: &mut F) -> Result<ControllerSlot, SimError>
+	where F: FnMut(&str, &[u8]) -> Result<Box<dyn Controller>, SimError> {
+		Ok(ControllerSlot {
+			controller: self.controller.restore(restore)?,
+			stop_lines: self.stop_lines,
+			crossings: self.crossings,
+			detectors: self.detectors
+		})
+	}
+}
+
+/// Whether a phase is served even when its detectors have not called it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Recall {
+	#[default]
+	None,
+	/// The phase is served for at least its minimum green every cycle
+	Min,
+	/// The phase is served for its maximum green every cycle
+	Max
+}
+
+/// A set of traffic lights which turn green together, and the detectors which call and extend it.
+/// Pedestrians may start across its crossings during its green.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Phase {
+	pub stop_lines: Vec<usize>,
+	#[serde(default)]
+	pub crossings: Vec<usize>,
+	#[serde(default)]
+	pub detectors: Vec<usize>,
+	/// In seconds, as are the other times
+	pub min_green: f32,
+	pub max_green: f32,
+	/// How long the green is extended by each vehicle detected
+	pub extension: f32,
+	pub amber: f32,
+	pub all_red: f32,
+	#[serde(default)]
+	pub recall: Recall
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Interval {
+	Green,
+	Amber,
+	AllRed
+}
+
+/**
+ * A vehicle-actuated controller, which serves its phases in turn.
+ *
+ * A phase is called when its detectors see a vehicle while it is red. Once green, it holds for
+ * its minimum green, then is extended for as long as vehicles keep arriving within `extension`
+ * of each other. It ends when that gap is exceeded (gaps out) or at its maximum green (maxes
+ * out), but only if another phase has been called; otherwise it rests in green. Phases which
+ * have not been called are skipped.
+ * */
+// todo: Pedestrian push buttons, so phases with crossings need not be recalled
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ActuatedController {
+	phases: Vec<Phase>,
+	current: usize,
+	interval: Interval,
+	/// The time since the current interval started
+	elapsed: f32,
+	/// The time since a vehicle was last detected during the current green
+	gap: f32,
+	calls: Vec<bool>,
+	stop_lines: Vec<usize>,
+	crossings: Vec<usize>,
+	detectors: Vec<usize>
+}
+
+impl ActuatedController {
+	/// Creates a controller which starts at the beginning of the first phase's green.
+	pub fn new(phases: Vec<Phase>) -> Result<Self, SimError> {
+		if phases.is_empty() {
+			return Err(SimError::InvalidController("A controller needs at least one phase".into()));
+		}
+		for phase in phases.iter() {
+			let times = [phase.min_green, phase.max_green, phase.extension, phase.amber, phase.all_red];
+			if times.iter().any(|t| !t.is_finite() || *t < 0.0) {
+				return Err(SimError::InvalidController("Phase times must not be negative".into()));
+			}
+			if phase.max_green < phase.min_green {
+				return Err(SimError::InvalidController(format!("Maximum green {} is less than minimum green {}",
+					phase.max_green, phase.min_green)));
+			}
+		}
+		let mut stop_lines = vec![];
+		let mut crossings = vec![];
+		let mut detectors = vec![];
+		for phase in phases.iter() {
+			for &id in phase.stop_lines.iter() {
+				if !stop_lines.contains(&id) { stop_lines.push(id); }
+			}
+			for &id in phase.crossings.iter() {
+				if !crossings.contains(&id) { crossings.push(id); }
+			}
+			for &id in phase.detectors.iter() {
+				if !detectors.contains(&id) { detectors.push(id); }
+			}
+		}
+		Ok(Self {
+			calls: vec![false; phases.len()],
+			phases,
+			current: 0,
+			interval: Interval::Green,
+			elapsed: 0.0,
+			gap: 0.0,
+			stop_lines,
+			crossings,
+			detectors
+		})
+	}
+
+	fn is_called(&self, phase: usize) -> bool {
+		self.calls[phase] || self.phases[phase].recall != Recall::None
+	}
+
+	/// The next phase after the current one which has been called, if any.
+	fn next_called(&self) -> Option<usize> {
+		(1..self.phases.len())
+			.map(|i| (self.current + i) % self.phases.len())
+			.find(|&i| self.is_called(i))
+	}
+}
+
+impl Controller for ActuatedController {
+	fn stop_lines(&self) -> Vec<usize> {
+		self.stop_lines.clone()
+	}
+
+	fn crossings(&self) -> Vec<usize> {
+		self.crossings.clone()
+	}
+
+	fn detectors(&self) -> Vec<usize> {
+		self.detectors.clone()
+	}
+
+	fn step(&mut self, _time: f32, delta: f32, readings: &[DetectorReading]) -> Vec<TrafficLightState> {
+		let actuated = |phase: &Phase| phase.detectors.iter()
+			.any(|id| readings[self.detectors.iter().position(|d| d == id).unwrap()].is_actuated());
+		let actuations = self.phases.iter().map(actuated).collect::<Vec<_>>();
+		for (i, &actuated) in actuations.iter().enumerate() {
+			if actuated && !(i == self.current && self.interval == Interval::Green) {
+				self.calls[i] = true;
+			}
+		}
+
+		self.elapsed += delta;
+		let phase = &self.phases[self.current];
+		match self.interval {
+			Interval::Green => {
+				self.gap = if actuations[self.current] { 0.0 } else { self.gap + delta };
+				let gapped_out = phase.recall != Recall::Max && self.gap >= phase.extension;
+				let ended = self.elapsed >= phase.min_green && (gapped_out || self.elapsed >= phase.max_green);
+				if ended && self.next_called().is_some() {
+					self.interval = Interval::Amber;
+					self.elapsed = 0.0;
+				}
+			}
+			Interval::Amber => if self.elapsed >= phase.amber {
+				self.interval = Interval::AllRed;
+				self.elapsed = 0.0;
+			}
+			Interval::AllRed => if self.elapsed >= phase.all_red {
+				// Greens only end when another phase has been called
+				self.current = self.next_called().unwrap_or(self.current);
+				self.calls[self.current] = false;
+				self.interval = Interval::Green;
+				self.elapsed = 0.0;
+				self.gap = 0.0;
+			}
+		}
+
+		let phase = &self.phases[self.current];
+		signal_states(&self.stop_lines, &self.crossings, (&phase.stop_lines, &phase.crossings), self.interval)
+	}
+
+	fn save(&self) -> Option<SavedController> {
+		Some(SavedController::Actuated(self.clone()))
+	}
+}
+
+/// The state of each stop line and then each crossing, given the ones in the current phase and
+/// its interval. Pedestrians are only signalled to start during the green.
+fn signal_states(stop_lines: &[usize], crossings: &[usize], phase: (&[usize], &[usize]), interval: Interval) -> Vec<TrafficLightState> {
+	let lines = stop_lines.iter().map(|id| match (phase.0.contains(id), interval) {
+		(true, Interval::Green) => TrafficLightState::Green,
+		(true, Interval::Amber) => TrafficLightState::Amber,
+		_ => TrafficLightState::Red
+	});
+	let crossings = crossings.iter().map(|id| match (phase.1.contains(id), interval) {
+		(true, Interval::Green) => TrafficLightState::Green,
+		_ => TrafficLightState::Red
+	});
+	lines.chain(crossings).collect()
+}
+
+/// A phase of a fixed time controller.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimedPhase {
+	pub stop_lines: Vec<usize>,
+	#[serde(default)]
+	pub crossings: Vec<usize>,
+	/// In seconds, as are the other times
+	pub green: f32,
+	pub amber: f32,
+	pub all_red: f32
+}
+
+/**
+ * A fixed time controller which runs its phases in turn over a common cycle, so that it can be
+ * coordinated with its neighbours.
+ *
+ * The first phase turns green `offset` seconds into each cycle of the simulation clock, which
+ * starts at step 0. Controllers on a corridor with the same cycle and offsets from
+ * [`Corridor::green_wave_offsets`](super::Corridor::green_wave_offsets) form a green wave.
+ * */
+// todo: Coordinated actuated control, where phases off the corridor can gap out early
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CoordinatedController {
+	cycle: f32,
+	offset: f32,
+	phases: Vec<TimedPhase>,
+	stop_lines: Vec<usize>,
+	crossings: Vec<usize>
+}
+
+impl CoordinatedController {
+	/// Creates a controller whose phase times add up to `cycle`.
+	pub fn new(cycle: f32, offset: f32, phases: Vec<TimedPhase>) -> Result<Self, SimError> {
+		if phases.is_empty() {
+			return Err(SimError::InvalidController("A controller needs at least one phase".into()));
+		}
+		if !cycle.is_finite() || cycle <= 0.0 || !offset.is_finite() {
+			return Err(SimError::InvalidController(format!("Invalid cycle {} or offset {}", cycle, offset)));
+		}
+		for phase in phases.iter() {
+			let times = [phase.green, phase.amber, phase.all_red];
+			if times.iter().any(|t| !t.is_finite() || *t < 0.0) {
+				return Err(SimError::InvalidController("Phase times must not be negative".into()));
+			}
+		}
+		let total = phases.iter().map(|p| p.green + p.amber + p.all_red).sum::<f32>();
+		if (total - cycle).abs() > 1e-3 {
+			return Err(SimError::InvalidController(format!("Phase times add up to {}, not the cycle {}", total, cycle)));
+		}
+		let mut stop_lines = vec![];
+		let mut crossings = vec![];
+		for phase in phases.iter() {
+			for &id in phase.stop_lines.iter() {
+				if !stop_lines.contains(&id) { stop_lines.push(id); }
+			}
+			for &id in phase.crossings.iter() {
+				if !crossings.contains(&id) { crossings.push(id); }
+			}
+		}
+		Ok(Self { cycle, offset, phases, stop_lines, crossings })
+	}
+
+	pub fn get_cycle(&self) -> f32 {
+		self.cycle
+	}
+
+	pub fn get_offset(&self) -> f32 {
+		self.offset
+	}
+
+	/// The current phase and interval at the given time.
+	fn interval_at(&self, time: f32) -> (usize, Interval) {
+		let mut t = (time - self.offset).rem_euclid(self.cycle);
+		for (i, phase) in self.phases.iter().enumerate() {
+			if t < phase.green { return (i, Interval::Green); }
+			t -= phase.green;
+			if t < phase.amber { return (i, Interval::Amber); }
+			t -= phase.amber;
+			if t < phase.all_red { return (i, Interval::AllRed); }
+			t -= phase.all_red;
+		}
+		// Only reached through rounding at the end of the cycle
+		(self.phases.len() - 1, Interval::AllRed)
+	}
+}
+
+impl Controller for CoordinatedController {
+	fn stop_lines(&self) -> Vec<usize> {
+		self.stop_lines.clone()
+	}
+
+	fn crossings(&self) -> Vec<usize> {
+		self.crossings.clone()
+	}
+
+	fn detectors(&self) -> Vec<usize> {
+		vec![]
+	}
+
+	fn step(&mut self, time: f32, _delta: f32, _readings: &[DetectorReading]) -> Vec<TrafficLightState> {
+		let (current, interval) = self.interval_at(time);
+		let phase = &self.phases[current];
+		signal_states(&self.stop_lines, &self.crossings, (&phase.stop_lines, &phase.crossings), interval)
+	}
+
+	fn save(&self) -> Option<SavedController> {
+		Some(SavedController::Coordinated(self.clone()))
+	}
+}
+
+impl Simulation {
+	pub fn add_detector(&mut self, id: usize, detector: Detector) -> Result<(), SimError> {
+		let link = self.links.get(detector.link).ok_or(SimError::UnknownLink(detector.link))?;
+		if detector.lane as usize >= link.lanes.len() {
+			return Err(SimError::UnknownLane { link: detector.link, lane: detector.lane });
+		}
+		if !(detector.pos >= 0.0 && detector.len >= 0.0 && detector.pos + detector.len <= link.length) {
+			return Err(SimError::InvalidGeometry(format!("Detector must lie within the link, got {} to {}",
+				detector.pos, detector.pos + detector.len)));
+		}
+		let state = DetectorState { detector, vehs: vec![], reading: DetectorReading::default() };
+		self.detectors.insert(id, state)
+			.map(|_| ())
+			.map_err(|e| SimError::from_insert(e, "detector", id))
+	}
+
+	pub fn get_detector_reading(&self, id: usize) -> Option<DetectorReading> {
+		self.detectors.get(id).map(|d| d.reading)
+	}
+
+	/// Hands control of some traffic lights to a controller, which sets them from the next step.
+	/// Each traffic light can only have one controller.
+	pub fn add_controller(&mut self, controller: Box<dyn Controller>) -> Result<(), SimError> {
+		let stop_lines = controller.stop_lines();
+		let crossings = controller.crossings();
+		let detectors = controller.detectors();
+		for &id in stop_lines.iter() {
+			let stopline = self.stoplines.get(id).ok_or(SimError::UnknownStopLine(id))?;
+			if !matches!(stopline.kind, StopLineType::TrafficLight { .. }) {
+				return Err(SimError::InvalidController(format!("Stop line {} is not a traffic light", id)));
+			}
+			if self.controllers.iter().any(|c| c.stop_lines.contains(&id)) {
+				return Err(SimError::InvalidController(format!("Stop line {} already has a controller", id)));
+			}
+		}
+		for &id in crossings.iter() {
+			if !self.crossings.has_key(id) {
+				return Err(SimError::UnknownCrossing(id));
+			}
+			if self.get_crossing_signal(id).is_none() {
+				return Err(SimError::InvalidController(format!("Crossing {} is not signalised", id)));
+			}
+			if self.controllers.iter().any(|c| c.crossings.contains(&id)) {
+				return Err(SimError::InvalidController(format!("Crossing {} already has a controller", id)));
+			}
+		}
+		if let Some(&id) = detectors.iter().find(|&&id| !self.detectors.has_key(id)) {
+			return Err(SimError::UnknownDetector(id));
+		}
+		self.controllers.push(ControllerSlot { controller, stop_lines, crossings, detectors });
+		Ok(())
+	}
+
+	/// The state of a traffic light, or `None` if the stop line is not one.
+	pub fn get_traffic_light(&self, stop_line: usize) -> Option<TrafficLightState> {
+		match self.stoplines.get(stop_line)?.kind {
+			StopLineType::TrafficLight { state } => Some(state),
+			_ => None
+		}
+	}
+
+	/// Steps each controller and sets its traffic lights and pedestrian signals.
+	pub(super) fn update_signals(&mut self) {
+		let delta = self.step_delta;
+		let time = self.step as f32 * delta;
+		let (detectors, stoplines, crossings) = (&self.detectors, &mut self.stoplines, &mut self.crossings);
+		for slot in self.controllers.iter_mut() {
+			let readings = slot.detectors.iter()
+				.map(|&id| detectors.get(id).map(|d| d.reading).unwrap_or_default())
+				.collect::<Vec<_>>();
+			let states = slot.controller.step(time, delta, &readings);
+			for (&id, &state) in slot.stop_lines.iter().zip(states.iter()) {
+				if let Some(stopline) = stoplines.get_mut(id) {
+					stopline.kind = StopLineType::TrafficLight { state };
+				}
+			}
+			for (&id, &state) in slot.crossings.iter().zip(states.iter().skip(slot.stop_lines.len())) {
+				if let Some(crossing) = crossings.get_mut(id) {
+					crossing.signal = state;
+				}
+			}
+		}
+	}
+
+	/// Reads each detector from where the vehicles are at the end of the step.
+	pub(super) fn update_detectors(&mut self) {
+		let delta = self.step_delta;
+		let (links, all_vehs) = (&self.links, &self.vehs);
+		for state in self.detectors.iter_mut() {
+			let d = state.detector;
+			let vehs = links.get(d.link).unwrap().get_vehicles()
+				.filter(|&id| {
+					let veh = all_vehs.get(id).unwrap();
+					let in_lane = veh.lane == d.lane || (veh.changing_lanes && veh.old_lane == d.lane);
+					in_lane && veh.pos + 0.5 * veh.len >= d.pos && veh.pos - 0.5 * veh.len <= d.pos + d.len
+				})
+				.collect::<Vec<_>>();
+			let arrivals = vehs.iter().filter(|id| !state.vehs.contains(id)).count();
+			let occupied = !vehs.is_empty();
+			state.reading = DetectorReading {
+				occupied,
+				arrivals,
+				gap: if occupied { 0.0 } else { state.reading.gap + delta }
+			};
+			state.vehs = vehs;
+		}
+	}
+}
@@ -0,0 +1,115 @@
+use std::io::{Read, Write};
+use super::{Simulation, SimError, Controller};
+use super::signal::SavedSlot;
+
+/// Identifies a snapshot file, and the layout of the state which follows it.
+// todo: bump the version whenever a serialised struct changes
+const SNAPSHOT_MAGIC: [u8; 4] = *b"TSIM";
+const SNAPSHOT_VERSION: u32 = 13;
+
+impl Simulation {
+	/// Writes the complete state of the simulation, such that restoring it and stepping on
+	/// gives exactly the same results as stepping on from here.
+	///
+	/// Fails if any controller cannot be saved; see `Controller::save`.
+	pub fn save_snapshot<W: Write>(&self, mut w: W) -> Result<(), SimError> {
+		let controllers = self.controllers.iter().map(|c| c.save()).collect::<Result<Vec<_>, _>>()?;
+		w.write_all(&SNAPSHOT_MAGIC).map_err(|e| SimError::InvalidSnapshot(e.to_string()))?;
+		bincode::serialize_into(&mut w, &SNAPSHOT_VERSION)
+			.and_then(|_| bincode::serialize_into(&mut w, self))
+			.and_then(|_| bincode::serialize_into(&mut w, &controllers))
+			.map_err(|e| SimError::InvalidSnapshot(e.to_string()))
+	}
+
+	/// Restores a simulation written by `save_snapshot`, which must not have any user-defined
+	/// controllers.
+	pub fn load_snapshot<R: Read>(r: R) -> Result<Self, SimError> {
+		Self::load_snapshot_with(r, |name, _| {
+			Err(SimError::InvalidSnapshot(format!("Cannot restore the user-defined controller \"{}\"", name)))
+		})
+	}
+
+	/// Restores a simulation written by `save_snapshot`, rebuilding each user-defined controller
+	/// from the name and state it saved.
+	pub fn load_snapshot_with<R, F>(mut r: R, mut restore: F) -> Result<Self, SimError>
+	where R: Read, F: FnMut(&str, &[u8]) -> Result<Box<dyn Controller>, SimError> {
+		let mut magic = [0; 4];
+		r.read_exact(&mut magic).map_err(|e| SimError::InvalidSnapshot(e.to_string()))?;
+		if magic != SNAPSHOT_MAGIC {
+			return Err(SimError::InvalidSnapshot("Not a simulation snapshot".into()));
+		}
+		let version: u32 = bincode::deserialize_from(&mut r)
+			.map_err(|e| SimError::InvalidSnapshot(e.to_string()))?;
+		if version != SNAPSHOT_VERSION {
+			return Err(SimError::InvalidSnapshot(format!("Unsupported version {}, expected {}", version, SNAPSHOT_VERSION)));
+		}
+		let mut sim: Simulation = bincode::deserialize_from(&mut r)
+			.map_err(|e| SimError::InvalidSnapshot(e.to_string()))?;
+		let controllers: Vec<SavedSlot> = bincode::deserialize_from(r)
+			.map_err(|e| SimError::InvalidSnapshot(e.to_string()))?;
+		sim.controllers = controllers.into_iter()
+			.map(|c| c.restore(&mut restore))
+			.collect::<Result<_, _>>()?;
+		Ok(sim)
+	}
+
+	pub fn to_snapshot_bytes(&self) -> Result<Vec<u8>, SimError> {
+		let mut buffer = vec![];
+		self.save_snapshot(&mut buffer)?;
+		Ok(buffer)
+	}
+
+	pub fn from_snapshot_bytes(bytes: &[u8]) -> Result<Self, SimError> {
+		Self::load_snapshot(bytes)
+	}
+}
+
+/// Serde support for `Ordering`, which is stored as -1, 0 or 1.
+pub mod ordering {
+	use std::cmp::Ordering;
+	use serde::{Serialize, Deserialize, Serializer, Deserializer};
+
+	pub fn serialize<S: Serializer>(ord: &Ordering, s: S) -> Result<S::Ok, S::Error> {
+		(*ord as i8).serialize(s)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Ordering, D::Error> {
+		Ok(i8::deserialize(d)?.cmp(&0))
+	}
+}
+
+/// Serde support for hashed collections, written in sorted order so that equal states give
+/// identical snapshots.
+pub mod sorted {
+	use std::collections::{HashMap, HashSet};
+	use std::hash::Hash;
+	use serde::{Serialize, Deserialize, Serializer, Deserializer};
+
+	pub fn serialize_map<K, V, S>(map: &HashMap<K, V>, s: S) -> Result<S::Ok, S::Error>
+		where K: Serialize + Ord, V: Serialize, S: Serializer
+	{
+		let mut entries = map.iter().collect::<Vec<_>>();
+		entries.sort_by(|a, b| a.0.cmp(b.0));
+		entries.serialize(s)
+	}
+
+	pub fn deserialize_map<'de, K, V, D>(d: D) -> Result<HashMap<K, V>, D::Error>
+		where K: Deserialize<'de> + Eq + Hash, V: Deserialize<'de>, D: Deserializer<'de>
+	{
+		Ok(Vec::<(K, V)>::deserialize(d)?.into_iter().collect())
+	}
+
+	pub fn serialize_set<T, S>(set: &HashSet<T>, s: S) -> Result<S::Ok, S::Error>
+		where T: Serialize + Ord, S: Serializer
+	{
+		let mut items = set.iter().collect::<Vec<_>>();
+		items.sort();
+		items.serialize(s)
+	}
+
+	pub fn deserialize_set<'de, T, D>(d: D) -> Result<HashSet<T>, D::Error>
+		where T: Deserialize<'de> + Eq + Hash, D: Deserializer<'de>
+	{
+		Ok(Vec::<T>::deserialize(d)?.into_iter().collect())
+	}
+}
@@ -1,21 +1,34 @@
 use std::f32;
 use std::cmp::Ordering;
 use smallvec::{SmallVec, smallvec};
+use serde::{Serialize, Deserialize};
 use super::{Link, Obstacle};
-use crate::util::{CubicFuncPiece, IdMap};
+use crate::util::{CubicFuncPiece, IdMap, IdKey, Key};
 
 const COMF_DECEL: f32 = -2.5;
+/// The time gap vehicles aim to keep to the vehicle ahead, in seconds.
+pub const HEADWAY: f32 = 2.0;
+/// The distance vehicles aim to keep to the vehicle ahead when stopped.
+pub const MIN_GAP: f32 = 2.0;
+/// Vehicles slower than this are considered to be stationary.
+pub const STATIONARY_VEL: f32 = 0.1;
+#[allow(dead_code)] // todo: limit braking to the maximum deceleration
 const MAX_DECEL: f32 = -6.0;
 
-#[derive(Clone)]
+/// The key of a vehicle, which stops referring to it once it is removed.
+pub type VehicleId = Key;
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Vehicle {
 	// Attributes
-	pub id: usize,
+	pub id: VehicleId,
 	pub user_id: usize,
 	pub len: f32,
 	pub wid: f32,
 	pub max_acc: f32,
 	pub follow_c: f32,
+	/// The speed the vehicle has been told not to exceed, if any
+	pub max_speed: Option<f32>,
 	// State
 	pub link: usize,
 	pub lane: u8,
@@ -25,40 +38,62 @@ pub struct Vehicle {
 	pub vel: f32,
 	pub acc: f32,
 	pub path: Option<CubicFuncPiece>,
+	/// The acceleration actually applied during the last step
+	pub last_acc: f32,
+	/// The stop line holding the vehicle back during the current step, if any
+	pub stopline: Option<usize>,
+	/// The number of steps the vehicle has been stationary for
+	pub stationary_steps: usize,
+	/// Whether the vehicle is on a mesoscopic link, so is moved by the link's queue rather than
+	/// by car following
+	pub meso: bool,
 	link_route: Vec<usize>,
 	lane_route: Vec<u8>,
 	lane_dists: Vec<LaneDistances>,
+	#[allow(dead_code)] // todo: record when vehicles arrive
 	pub arrival_step: Option<usize>,
 	// Derived state
 	pub lat: f32,
 	pub dlat: f32
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
 pub struct VehicleState {
 	pub user_id: usize,
 	pub link: usize,
+	pub lane: u8,
 	pub pos: f32,
 	pub vel: f32,
+	pub acc: f32,
 	pub lat: f32,
-	pub dlat: f32
+	pub dlat: f32,
+	/// The lateral direction of an ongoing lane change: -1, 0 or 1
+	pub lane_change: i8,
+	pub len: f32,
+	pub wid: f32,
+	/// The stop line the vehicle is waiting at, if any
+	pub stopline: Option<usize>
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 struct LaneDistances {
-	pub lanes: SmallVec<[[f32; 4]; 8]>
+	pub lanes: SmallVec<[[f32; 4]; 8]>,
+	/// The position on the link past which a closed section of lane has ended, so the distances
+	/// must be recomputed for the lane to be used again
+	pub until: f32
 }
 
 impl Vehicle {
 	pub fn new(user_id: usize) -> Self {
 		let max_acc = 3.0;
 		Self {
-			id: 0,
+			id: VehicleId::new(0, 0),
 			user_id,
 			len: 4.6,
 			wid: 2.0,
 			max_acc,
 			follow_c: 2.0 * (max_acc * -COMF_DECEL).sqrt(),
+			max_speed: None,
 			link: !0,
 			lane: 0,
 			old_lane: 0,
@@ -67,6 +102,10 @@ impl Vehicle {
 			vel: 0.0,
 			acc: 0.0,
 			path: None,
+			last_acc: 0.0,
+			stopline: None,
+			stationary_steps: 0,
+			meso: false,
 			link_route: vec![],
 			lane_route: vec![],
 			lane_dists: vec![],
@@ -80,8 +119,20 @@ impl Vehicle {
 		self.link = link;
 		self.pos = pos;
 		self.lane = lane;
+		self.old_lane = lane;
+		self.changing_lanes = false;
+		self.path = None;
 		self.link_route = vec![self.link];
 		self.lane_route = vec![self.lane];
+		self.lane_dists = vec![];
+		self.stopline = None;
+		self.stationary_steps = 0;
+		self.meso = false;
+	}
+
+	/// The link at the end of the vehicle's route.
+	pub fn get_dest(&self) -> Option<usize> {
+		self.link_route.last().cloned()
 	}
 
 	pub fn get_link(&self, i: usize) -> Option<usize> {
@@ -96,9 +147,13 @@ impl Vehicle {
 		self.lane_route.get(i).cloned()
 	}
 
+	pub fn get_lanes(&self) -> &[u8] {
+		&self.lane_route
+	}
+
 	pub fn set_route(&mut self, route: Vec<usize>) {
 		self.link_route = route;
-		if self.link_route.get(0) != Some(&self.link) {
+		if self.link_route.first() != Some(&self.link) {
 			self.link_route.insert(0, self.link);
 		}
 		self.lane_dists = vec![];
@@ -109,7 +164,7 @@ impl Vehicle {
 		if self.changing_lanes {
 			let half_delta = 0.5 * (self.path.unwrap().get_y2() - self.lat);
 			Obstacle {
-				veh: self.id,
+				veh: Some(self.id),
 				pos,
 				vel: self.vel,
 				lat: self.lat + half_delta,
@@ -118,7 +173,7 @@ impl Vehicle {
 			}
 		} else {
 			Obstacle {
-				veh: self.id,
+				veh: Some(self.id),
 				pos,
 				vel: self.vel,
 				lat: self.lat,
@@ -132,18 +187,18 @@ impl Vehicle {
 		let mut link_iter = self.link_route.iter().rev().cloned();
 		let link_id = link_iter.next().unwrap();
 		let link = links.get(link_id).unwrap();
-		self.lane_dists = vec![LaneDistances {
-			lanes: smallvec![[f32::INFINITY; 4]; link.lanes.len()]
-		}];
+		let mut lanes = smallvec![[f32::INFINITY; 4]; link.lanes.len()];
+		let until = self.apply_closures(self.link_route.len() - 1, link, &mut lanes);
+		self.lane_dists = vec![LaneDistances { lanes, until }];
 		let mut succ_link_id = link_id;
-		while let Some(link_id) = link_iter.next() {
+		for (i, link_id) in link_iter.enumerate() {
 			let link = links.get(link_id).unwrap();
 			let mut lanes: SmallVec<[[f32; 4]; 8]> = smallvec![[0.0; 4]; link.lanes.len()];
 			let succ_lanes = &self.lane_dists.last().unwrap().lanes;
 			let mut min_offset = 4;
 			for (lane_in, lane_out) in link.get_lane_connections(succ_link_id) {
 				for (i, lane) in lanes.iter_mut().enumerate() {
-					let offset = (i as isize - lane_in as isize).abs() as usize;
+					let offset = (i as isize - lane_in as isize).unsigned_abs();
 					if offset < min_offset { min_offset = offset; }
 					for (a, b) in lane.iter_mut().skip(offset).zip(succ_lanes[lane_out as usize].iter()) {
 						if *b > *a { *a = *b; }
@@ -151,14 +206,13 @@ impl Vehicle {
 				}
 			}
 			for lane in lanes.iter_mut() {
-				for i in 0..(4 - min_offset) {
-					lane[i] = lane[i + min_offset] + link.length;
-				}
-				for i in (4 - min_offset)..4 {
-					lane[i] = f32::INFINITY;
+				lane.rotate_left(min_offset);
+				for (i, dist) in lane.iter_mut().enumerate() {
+					*dist = if i < 4 - min_offset { *dist + link.length } else { f32::INFINITY };
 				}
 			}
-			self.lane_dists.push(LaneDistances { lanes });
+			let until = self.apply_closures(self.link_route.len() - 2 - i, link, &mut lanes);
+			self.lane_dists.push(LaneDistances { lanes, until });
 			succ_link_id = link_id;
 		}
 		self.lane_dists.reverse();
@@ -171,6 +225,59 @@ impl Vehicle {
 		println!("-----"); */
 	}
 
+	/// Limits the distances which can be travelled in closed lanes of the `i`th link of the route
+	/// to the start of the closure, so that vehicles must change lanes to get past it and do not
+	/// change into it alongside it. Returns the end of the first of these closures, past which
+	/// the lanes should be chosen again.
+	fn apply_closures(&self, i: usize, link: &Link, lanes: &mut SmallVec<[[f32; 4]; 8]>) -> f32 {
+		let mut until = f32::INFINITY;
+		let mut capped = lanes.clone();
+		for (lane, pos, end) in link.get_closures() {
+			// Closures behind the vehicle do not matter, nor do those it was already in when they
+			// closed, which it may as well drive out of
+			if i == 0 && (end <= self.pos || (lane == self.lane && pos <= self.pos)) {
+				continue;
+			}
+			if let Some(dists) = capped.get_mut(lane as usize) {
+				for dist in dists.iter_mut() {
+					if pos < *dist { *dist = pos; }
+				}
+				until = until.min(end);
+			}
+		}
+		if until == f32::INFINITY {
+			return until;
+		}
+		for (lane, dists) in lanes.iter_mut().enumerate() {
+			if capped[lane][0] == dists[0] {
+				continue;
+			}
+			// The lane can only be followed past the closure by changing to a neighbouring lane
+			let left = if lane > 0 { capped.get(lane - 1) } else { None };
+			let right = capped.get(lane + 1);
+			dists[0] = capped[lane][0];
+			for k in 1..4 {
+				let via = left.iter().chain(right.iter()).map(|n| n[k - 1]).fold(0.0, f32::max);
+				dists[k] = f32::max(capped[lane][k], via);
+			}
+		}
+		until
+	}
+
+	/// Forgets the distances which can be travelled in each lane, e.g. because lanes have been
+	/// closed or reopened, so that they are recomputed at the next lane decision.
+	pub fn invalidate_lanes(&mut self) {
+		self.lane_dists = vec![];
+	}
+
+	/// Replaces the rest of the vehicle's route, choosing the lanes to take immediately.
+	pub fn reroute(&mut self, route: Vec<usize>, links: &IdMap<Link>) {
+		self.set_route(route);
+		self.compute_lane_dists(links);
+		self.lane_route.truncate(1);
+		self.extend_lane_route(links);
+	}
+
 	fn compare_lanes(&self, i: usize, lane1: u8, lane2: u8) -> Ordering {
 		let dists = &self.lane_dists[i].lanes;
 		let lane1 = dists.get(lane1 as usize);
@@ -193,12 +300,26 @@ impl Vehicle {
 		}
 	}
 	
+	/// The lanes of the `i`th link of the route, from the best for following the rest of it to
+	/// the worst.
+	pub fn lanes_by_preference(&mut self, i: usize, links: &IdMap<Link>) -> Vec<u8> {
+		if self.lane_dists.len() < self.link_route.len() {
+			self.compute_lane_dists(links);
+		}
+		let mut lanes = (0..self.lane_dists[i].lanes.len() as u8).collect::<Vec<_>>();
+		lanes.sort_by(|&a, &b| self.compare_lanes(i, b, a));
+		lanes
+	}
+
 	pub fn lane_decisions(&mut self, links: &IdMap<Link>) {
-		if self.changing_lanes || self.link_route.len() < 2 {
+		// Vehicles on the last link of their route still decide lanes, to avoid closures on it,
+		// but unplaced vehicles have no lanes to decide between
+		if self.changing_lanes || self.link_route.is_empty() {
 			return;
 		}
 
-		if self.lane_dists.len() < self.link_route.len() {
+		let passed_closure = self.lane_dists.first().map(|d| self.pos >= d.until).unwrap_or(false);
+		if self.lane_dists.len() < self.link_route.len() || passed_closure {
 			self.compute_lane_dists(links);
 		}
 
@@ -230,6 +351,27 @@ impl Vehicle {
 				self.lane_route = vec![self.lane];
 			}
 		}
+		self.extend_lane_route(links);
+
+		// Handle lane change path
+		if self.changing_lanes {
+			// todo: How to handle not enough distance left?
+			let dist_left = self.lane_dists[0].lanes[self.old_lane as usize][0] - self.pos;
+			let dist = f32::min(40.0, 0.8 * dist_left);
+			let end_lat = self.get_lat_at_pos(self.pos + dist, links);
+			self.path = Some(CubicFuncPiece {
+				min_x: self.pos,
+				max_x: self.pos + dist,
+				y1: self.lat,
+				yd: end_lat - self.lat
+			});
+		}
+
+		// todo: handle case where vlat != 0
+	}
+
+	/// Chooses the lane to take on each remaining link of the route.
+	fn extend_lane_route(&mut self, links: &IdMap<Link>) {
 		while self.lane_route.len() < self.link_route.len() {
 			let i = self.lane_route.len() - 1;
 			let prev_lane = self.lane_route[i];
@@ -248,22 +390,6 @@ impl Vehicle {
 			}
 			self.lane_route.push(next_lane);
 		}
-
-		// Handle lane change path
-		if self.changing_lanes {
-			// todo: How to handle not enough distance left?
-			let dist_left = self.lane_dists[0].lanes[self.old_lane as usize][0] - self.pos;
-			let dist = f32::min(40.0, 0.8 * dist_left);
-			let end_lat = self.get_lat_at_pos(self.pos + dist, links);
-			self.path = Some(CubicFuncPiece {
-				min_x: self.pos,
-				max_x: self.pos + dist,
-				y1: self.lat,
-				yd: end_lat - self.lat
-			});
-		}
-
-		// todo: handle case where vlat != 0
 	}
 
 	fn get_lat_at_pos(&self, pos: f32, links: &IdMap<Link>) -> f32 {
@@ -293,6 +419,11 @@ impl Vehicle {
 			apply_limit(self, next_link.speed_limit, link.length - self.pos);
 		}
 
+		// The vehicle's own limit
+		if let Some(max_speed) = self.max_speed {
+			apply_limit(self, max_speed, 0.0);
+		}
+
         fn apply_limit(veh: &mut Vehicle, limit: f32, dist: f32) {
             let limit = if dist > 0.0 {
                 ((limit * limit) - 2.0 * COMF_DECEL * dist).sqrt()
@@ -315,61 +446,101 @@ impl Vehicle {
 		let dist = pos - (self.pos + (0.5 * self.len));
 
 		if dist <= 0.0 {
-			self.acc = std::f32::MIN;
+			self.acc = f32::MIN;
 			return;
 		}
 
 		let approach_rate = self.vel - vel;
-		let headway = 2.0;
-		let mingap = 2.0;
-		let ss = mingap + (headway * self.vel) + ((approach_rate * self.vel) / self.follow_c);
+		let ss = MIN_GAP + (HEADWAY * self.vel) + ((approach_rate * self.vel) / self.follow_c);
 		let acc = self.max_acc * (1.0 - (ss / dist).powf(2.0));
 		self.apply_acc(acc);
 	}
 
+	/// Whether the vehicle can stop before `pos` without braking harder than is comfortable.
+	pub fn can_stop_before(&self, pos: f32) -> bool {
+		let dist = pos - (self.pos + 0.5 * self.len);
+		self.vel * self.vel <= 2.0 * -COMF_DECEL * dist
+	}
+
 	pub fn stop(&mut self, pos: f32) {
 		self.follow(pos, 0.0);
 	}
 
-	pub fn integrate(&mut self, delta: f32, links: &mut IdMap<Link>) {
+	/// Advances the vehicle by one step. If it moved onto another link, or reached its
+	/// destination (`!0`), returns the links it left and entered, which the caller must update.
+	pub fn integrate(&mut self, delta: f32, links: &IdMap<Link>) -> Option<(usize, usize)> {
 		// Integrate position, reset acceleration
+		let old_vel = self.vel;
 		self.vel += self.acc * delta;
 		if self.vel < 0.0 {
 			self.vel = 0.0;
 		}
+		self.last_acc = (self.vel - old_vel) / delta;
+		if self.vel < STATIONARY_VEL {
+			self.stationary_steps += 1;
+		} else {
+			self.stationary_steps = 0;
+		}
 		self.pos += self.vel * delta; // todo: road curvature
 		self.acc = self.max_acc;
 		
 		// Advance the link
 		let len = links.get(self.link).unwrap().length;
+		let mut change = None;
 		if self.pos > len {
-			links.get_mut(self.link).unwrap().remove_veh(self.id);
-			self.link_route.remove(0);
-			self.lane_route.remove(0);
-			if !self.lane_dists.is_empty() {
-				self.lane_dists.remove(0);
-			}
-			if !self.link_route.is_empty() {
-				let next_link = self.link_route[0];
-				let lat_off = links.get(self.link).unwrap().get_offset_to_link(next_link);
-				self.link = next_link;
-				links.get_mut(self.link).unwrap().add_veh(self.id);
-				self.pos -= len;
-				self.lane = self.lane_route[0];
-				if self.changing_lanes {
-					self.path = self.path.map(|p| p.translate(-len, lat_off));
-				} else {
-					self.path = None;
+			let old_link = self.link;
+			match self.advance_route() {
+				Some(next_link) => {
+					let lat_off = links.get(old_link).unwrap().get_offset_to_link(next_link);
+					change = Some((old_link, next_link));
+					self.pos -= len;
+					self.lane = self.lane_route[0];
+					if self.changing_lanes {
+						self.path = self.path.map(|p| p.translate(-len, lat_off));
+					} else {
+						self.path = None;
+					}
+				}
+				None => {
+					// Vehicle has reached destination
+					return Some((old_link, !0));
 				}
-			} else {
-				// Vehicle has reached destination
-				self.link = !0;
-				return;
 			}
 		}
 
 		// Update path, lat and vlat
 		self.update_path(links);
+		change
+	}
+
+	/// Moves on to the next link of the route, returning it, or `None` if the vehicle has
+	/// reached its destination.
+	pub fn advance_route(&mut self) -> Option<usize> {
+		self.link_route.remove(0);
+		if !self.lane_route.is_empty() {
+			self.lane_route.remove(0);
+		}
+		if !self.lane_dists.is_empty() {
+			self.lane_dists.remove(0);
+		}
+		self.link = self.link_route.first().cloned().unwrap_or(!0);
+		self.link_route.first().cloned()
+	}
+
+	/// Places the vehicle on the link at the front of its route, keeping the rest of the route
+	/// and choosing its lanes from scratch.
+	pub fn enter_link(&mut self, lane: u8, pos: f32, vel: f32, links: &IdMap<Link>) {
+		self.link = self.link_route[0];
+		self.lane = lane;
+		self.old_lane = lane;
+		self.changing_lanes = false;
+		self.pos = pos;
+		self.vel = vel;
+		self.path = None;
+		self.lane_route = vec![lane];
+		self.compute_lane_dists(links);
+		self.extend_lane_route(links);
+		self.update_path(links);
 	}
 
 	pub fn update_path(&mut self, links: &IdMap<Link>) {
@@ -385,13 +556,23 @@ impl Vehicle {
 	}
 
 	pub fn get_state(&self) -> VehicleState {
+		let lane_change = match self.path {
+			Some(path) if self.changing_lanes => (path.get_y2() - self.lat).signum() as i8,
+			_ => 0
+		};
 		VehicleState {
 			user_id: self.user_id,
 			link: self.link,
+			lane: self.lane,
 			pos: self.pos,
 			vel: self.vel,
+			acc: self.last_acc,
 			lat: self.lat,
-			dlat: self.dlat
+			dlat: self.dlat,
+			lane_change,
+			len: self.len,
+			wid: self.wid,
+			stopline: self.stopline
 		}
 	}
 }
\ No newline at end of file
@@ -0,0 +1,198 @@
+use std::clone::Clone;
+use std::fmt;
+use std::marker::PhantomData;
+use serde::{Serialize, Deserialize};
+use rayon::prelude::*;
+
+/// The largest id a value can be inserted with, as slots are allocated up to it.
+pub const MAX_ID: usize = (1 << 20) - 1;
+
+/// Returned when a value cannot be inserted with a given id.
+#[derive(Debug, PartialEq)]
+pub enum InsertError {
+    /// The id is already in use
+    KeyExists,
+    /// The id is larger than `MAX_ID`
+    OutOfRange
+}
+
+/**
+ * A key into an `IdMap`, made from the index of the slot the value is stored in.
+ *
+ * Keys may also record which of the values stored in the slot over time they were issued for,
+ * so that they stop working once that value is removed, rather than referring to whatever
+ * reuses the slot.
+ * */
+pub trait IdKey: Copy {
+    fn new(index: usize, generation: u32) -> Self;
+    fn index(self) -> usize;
+    /// Whether the key refers to the value stored in a slot at the given generation.
+    fn is_current(self, generation: u32) -> bool;
+}
+
+/// Plain ids, chosen by the user, refer to whatever currently has that id.
+impl IdKey for usize {
+    fn new(index: usize, _generation: u32) -> Self {
+        index
+    }
+
+    fn index(self) -> usize {
+        self
+    }
+
+    fn is_current(self, _generation: u32) -> bool {
+        true
+    }
+}
+
+/// A key which only refers to the value it was issued for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Key {
+    index: u32,
+    generation: u32
+}
+
+impl IdKey for Key {
+    fn new(index: usize, generation: u32) -> Self {
+        Self {
+            index: index as u32,
+            generation
+        }
+    }
+
+    fn index(self) -> usize {
+        self.index as usize
+    }
+
+    fn is_current(self, generation: u32) -> bool {
+        self.generation == generation
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IdMap<T, K = usize> where T: Clone, K: IdKey {
+    vec: Vec<Option<T>>,
+    /// How many times each slot has been emptied
+    generations: Vec<u32>,
+    free_slots: Vec<usize>,
+    #[serde(skip)]
+    key: PhantomData<K>
+}
+
+impl<T, K> IdMap<T, K> where T: Clone, K: IdKey {
+    pub fn new() -> Self {
+        Self {
+            vec: vec![],
+            generations: vec![],
+            free_slots: vec![],
+            key: PhantomData
+        }
+    }
+
+    /// Inserts a value at the given index, if it is free.
+    pub fn insert(&mut self, id: usize, value: T) -> Result<K, InsertError> {
+        if id > MAX_ID {
+            return Err(InsertError::OutOfRange);
+        }
+        if id < self.vec.len() {
+            let ind = self.free_slots.iter()
+                .position(|x| *x == id)
+                .ok_or(InsertError::KeyExists)?;
+            self.free_slots.swap_remove(ind);
+        } else {
+            for i in self.vec.len()..id {
+                self.free_slots.push(i);
+            }
+            self.vec.resize(id + 1, None);
+            self.generations.resize(id + 1, 0);
+        }
+        self.vec[id] = Some(value);
+        Ok(K::new(id, self.generations[id]))
+    }
+
+    pub fn remove(&mut self, key: K) -> Option<T> {
+        self.get(key)?;
+        let id = key.index();
+        self.generations[id] = self.generations[id].wrapping_add(1);
+        self.free_slots.push(id);
+        self.vec[id].take()
+    }
+
+    pub fn get(&self, key: K) -> Option<&T> {
+        let id = key.index();
+        if !key.is_current(*self.generations.get(id)?) {
+            return None;
+        }
+        self.vec[id].as_ref()
+    }
+
+    pub fn get_mut(&mut self, key: K) -> Option<&mut T> {
+        let id = key.index();
+        if !key.is_current(*self.generations.get(id)?) {
+            return None;
+        }
+        self.vec[id].as_mut()
+    }
+
+    pub fn has_key(&self, key: K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Inserts a value in any free slot, returning its key.
+    pub fn insert_free(&mut self, value: T) -> K {
+        if self.free_slots.is_empty() {
+            let ind = self.vec.len();
+            self.vec.push(Some(value));
+            self.generations.push(0);
+            K::new(ind, 0)
+        } else {
+            let ind = self.free_slots.pop().unwrap();
+            self.vec[ind] = Some(value);
+            K::new(ind, self.generations[ind])
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&T> {
+        self.vec.iter().filter_map(|x| x.as_ref())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item=&mut T> {
+        self.vec.iter_mut().filter_map(|x| x.as_mut())
+    }
+
+    /// The number of slots, which is one more than the largest index in use.
+    pub fn len(&self) -> usize {
+        self.vec.len()
+    }
+
+    /// Removes every element matching the predicate, returning them along with their keys.
+    pub fn remove_where<P>(&mut self, predicate: P) -> Vec<(K, T)> where P: Fn(&T) -> bool {
+        let mut removed = vec![];
+        for (i, elem) in self.vec.iter_mut().enumerate() {
+            let mut remove = false;
+            if let Some(item) = elem {
+                if predicate(item) {
+                    remove = true;
+                }
+            }
+            if remove {
+                removed.push((K::new(i, self.generations[i]), elem.take().unwrap()));
+                self.generations[i] = self.generations[i].wrapping_add(1);
+                self.free_slots.push(i);
+            }
+        }
+        removed
+    }
+}
+
+impl<T, K> IdMap<T, K> where T: Clone + Send + Sync, K: IdKey + Send + Sync {
+    pub fn par_iter_mut(&mut self) -> impl ParallelIterator<Item=&mut T> {
+        self.vec.par_iter_mut().filter_map(|x| x.as_mut())
+    }
+}
@@ -1,11 +1,13 @@
 mod idmap;
 mod piecewise;
+mod rng;
 
 use std::cmp::Ordering;
-pub use idmap::IdMap;
+pub use idmap::{IdMap, IdKey, Key, InsertError, MAX_ID};
 pub use piecewise::{LinearFunc, CubicFunc, CubicFuncPiece};
+pub use rng::Rng;
 
-pub fn insertion_sort<T, F>(vec: &mut Vec<T>, cmp: F) where F: Fn(&T, &T) -> Ordering {
+pub fn insertion_sort<T, F>(vec: &mut [T], cmp: F) where F: Fn(&T, &T) -> Ordering {
     let len = vec.len();
     if len <= 1 {
         return;
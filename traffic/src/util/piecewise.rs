@@ -1,14 +1,15 @@
 use std::cmp::Ordering;
+use serde::{Serialize, Deserialize};
 
 /**
  * Represents a piece-wise linear function that maps an f32 to an f32.
  * */
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct LinearFunc {
     pieces: Vec<LinearFuncPiece>
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 struct LinearFuncPiece {
     min_x: f32,
     max_x: f32,
@@ -22,7 +23,7 @@ impl LinearFunc {
         let mut pieces = vec![];
 
         let (mut x1, mut y1) = points.next().unwrap();
-        while let Some((x2, y2)) = points.next() {
+        for (x2, y2) in points {
             pieces.push(LinearFuncPiece {
                 min_x: x1,
                 max_x: *x2,
@@ -63,12 +64,12 @@ impl LinearFunc {
 /**
  * Represents a piece-wise linear function that maps an f32 to an f32.
  * */
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CubicFunc {
     pieces: Vec<CubicFuncPiece>
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct CubicFuncPiece {
     pub min_x: f32,
     pub max_x: f32,
@@ -82,7 +83,7 @@ impl CubicFunc {
         let mut pieces = vec![];
 
         let (mut x1, mut y1) = points.next().unwrap();
-        while let Some((x2, y2)) = points.next() {
+        for (x2, y2) in points {
             pieces.push(CubicFuncPiece {
                 min_x: x1,
                 max_x: *x2,
@@ -122,6 +123,13 @@ impl CubicFunc {
         };
         self.pieces[ind]
     }
+
+    /// The smallest and largest values the function takes.
+    pub fn get_range(&self) -> (f32, f32) {
+        self.pieces.iter()
+            .map(|p| p.get_range())
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(a, b), (c, d)| (a.min(c), b.max(d)))
+    }
 }
 
 impl CubicFuncPiece {
@@ -144,6 +152,12 @@ impl CubicFuncPiece {
     pub fn get_y2(&self) -> f32 {
         self.y1 + self.yd
     }
+
+    /// The smallest and largest values the piece takes, which are at its ends.
+    pub fn get_range(&self) -> (f32, f32) {
+        let y2 = self.get_y2();
+        (self.y1.min(y2), self.y1.max(y2))
+    }
     
     pub fn get_y(&self, x: f32) -> f32 {
         self.get_y_and_dy(x).0
@@ -0,0 +1,35 @@
+/**
+ * A small, fast pseudo-random number generator (xorshift64*).
+ *
+ * Used wherever the simulation needs randomness, so that runs are reproducible from a seed.
+ * */
+#[derive(Clone)]
+pub struct Rng {
+    state: u64
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // The state must never be zero
+        Self {
+            state: seed ^ 0x9E37_79B9_7F4A_7C15
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Returns a number uniformly distributed in `[0, 1)`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Returns an exponentially distributed number with the given mean.
+    pub fn next_exp(&mut self, mean: f32) -> f32 {
+        -mean * (1.0 - self.next_f32()).ln()
+    }
+}
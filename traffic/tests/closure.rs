@@ -0,0 +1,79 @@
+use traffic::{Simulation, LaneClosure, RoutePenalty, LinearFunc, CubicFunc};
+
+const START: f32 = 200.0;
+const END: f32 = 300.0;
+
+/// A two lane link whose left lane is closed part of the way along, and is the only lane which
+/// leads on to the next link.
+fn build() -> Simulation {
+	let mut sim = Simulation::new(0.1);
+	sim.add_link(0, 600.0, 14.0).unwrap();
+	sim.add_link(1, 100.0, 14.0).unwrap();
+	for lat in [0.0, 3.5] {
+		sim.add_lane(0, LinearFunc::from_points(&[(0.0, 0.0), (600.0, 600.0)]), CubicFunc::from_points(&[(0.0, lat), (600.0, lat)])).unwrap();
+	}
+	sim.add_lane(1, LinearFunc::from_points(&[(0.0, 0.0), (100.0, 100.0)]), CubicFunc::from_points(&[(0.0, 0.0), (100.0, 0.0)])).unwrap();
+	sim.add_connection(0, 1, &[(0, 0)], 0.0).unwrap();
+	sim.add_closure(0, LaneClosure {
+		link: 0,
+		lane: 0,
+		start_pos: START,
+		end_pos: END,
+		from: 0.0,
+		until: None,
+		routing: RoutePenalty::None
+	}).unwrap();
+	sim
+}
+
+#[test]
+fn closed_section_is_avoided_and_lane_reused_after_it() {
+	let mut sim = build();
+	let mut next_id = 0;
+	for step in 0..3000 {
+		if step < 2000 && step % 40 == 0 {
+			for lane in 0..2 {
+				if sim.is_space_clear(0, lane, 5.0, 10.0) {
+					sim.insert_vehicle(next_id, 0, lane, 5.0, 1).unwrap();
+					next_id += 1;
+				}
+			}
+		}
+		sim.step();
+		for veh in sim.get_vehicle_states().filter(|v| v.link == 0 && v.lane == 0) {
+			assert!(veh.pos <= START || veh.pos >= END, "vehicle {} in the closed section at {}", veh.user_id, veh.pos);
+		}
+	}
+	assert!(next_id > 50);
+	// Every vehicle got past the closure and back into the only lane which leads on
+	assert_eq!(sim.num_vehicles(), 0);
+}
+
+#[test]
+fn vehicles_alongside_closure_do_not_change_into_it() {
+	let mut sim = build();
+	// Already past the start of the closure, in the lane next to it
+	sim.insert_vehicle(0, 0, 1, START + 10.0, 1).unwrap();
+	let mut reached_end = false;
+	for _ in 0..400 {
+		sim.step();
+		let veh = match sim.get_vehicle_states().next() {
+			Some(veh) => veh,
+			None => break
+		};
+		if veh.link == 0 && veh.pos < END {
+			assert_eq!(veh.lane, 1);
+		}
+		reached_end |= veh.link == 1;
+	}
+	assert!(reached_end);
+}
+
+#[test]
+fn unplaced_vehicle_survives_step() {
+	let mut sim = build();
+	let id = sim.add_vehicle(0).unwrap();
+	sim.step();
+	// Removed with the vehicles which have finished their routes
+	assert_eq!(sim.get_vehicle_state(id), None);
+}
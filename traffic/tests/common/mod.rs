@@ -0,0 +1,119 @@
+/*!
+ * A small network shared by the integration tests, with the demand to keep it busy.
+ * */
+
+#![allow(dead_code)]
+
+use traffic::{Simulation, StopLineType, TrafficLightState, Phase, Recall};
+use traffic::scenario::{NetworkSpec, LinkSpec, LaneSpec, ConnectionSpec, LanePair, StopLineSpec, ConflictSpec};
+use traffic::scenario::{DetectorSpec, ControllerSpec};
+use traffic::record::{Action, frame_digest};
+
+pub const STEP_DELTA: f32 = 0.1;
+
+/// The links vehicles enter the network on, and their destinations.
+pub const ENTRIES: &[(usize, usize)] = &[(0, 4), (2, 5)];
+
+/**
+ * Two roads crossing at an actuated traffic light.
+ *
+ * The main road has two lanes, which merge into one on the junction link, and the side road
+ * has one. Each junction link has a stop line which conflicts with the other.
+ * */
+pub fn network() -> NetworkSpec {
+	let link = |id, length, lanes: u8| LinkSpec {
+		id,
+		length,
+		speed_limit: 14.0,
+		lanes: (0..lanes).map(|l| LaneSpec { start_lat: 3.5 * l as f32, end_lat: 3.5 * l as f32 }).collect(),
+		model: None
+	};
+	let connect = |src_link, dst_link, lanes: &[(u8, u8)]| ConnectionSpec {
+		src_link,
+		dst_link,
+		lanes: lanes.iter().map(|&(from, to)| LanePair { from, to }).collect(),
+		offset: 0.0
+	};
+	let light = |id, link| StopLineSpec {
+		id,
+		link,
+		lane: 0,
+		pos: 5.0,
+		length: 0.0,
+		kind: StopLineType::TrafficLight { state: TrafficLightState::Red }
+	};
+	let phase = |stop_line, detector| Phase {
+		stop_lines: vec![stop_line],
+		crossings: vec![],
+		detectors: vec![detector],
+		min_green: 5.0,
+		max_green: 20.0,
+		extension: 2.0,
+		amber: 3.0,
+		all_red: 1.0,
+		recall: Recall::None
+	};
+	NetworkSpec {
+		links: vec![link(0, 300.0, 2), link(1, 30.0, 1), link(2, 200.0, 1), link(3, 30.0, 1), link(4, 200.0, 1), link(5, 200.0, 1)],
+		connections: vec![
+			connect(0, 1, &[(0, 0), (1, 0)]),
+			connect(1, 4, &[(0, 0)]),
+			connect(2, 3, &[(0, 0)]),
+			connect(3, 5, &[(0, 0)])
+		],
+		stop_lines: vec![light(0, 1), light(1, 3)],
+		conflicts: vec![
+			ConflictSpec { stop1: 0, stop2: 1, priority: 0, max_pos: 25.0 },
+			ConflictSpec { stop1: 1, stop2: 0, priority: 0, max_pos: 25.0 }
+		],
+		detectors: vec![
+			DetectorSpec { id: 0, link: 0, lane: 0, pos: 250.0, length: 2.0 },
+			DetectorSpec { id: 1, link: 2, lane: 0, pos: 150.0, length: 2.0 }
+		],
+		controllers: vec![ControllerSpec::Actuated { phases: vec![phase(0, 0), phase(1, 1)] }],
+		..NetworkSpec::default()
+	}
+}
+
+pub fn build() -> Simulation {
+	let mut sim = Simulation::new(STEP_DELTA);
+	network().add_to_simulation(&mut sim).unwrap();
+	sim
+}
+
+/// The vehicles to add before the next step: one at the start of each entry lane every few
+/// seconds, if there is space for it.
+pub fn arrivals(sim: &Simulation, next_id: &mut usize) -> Vec<Action> {
+	if !sim.get_step().is_multiple_of(20) {
+		return vec![];
+	}
+	let mut actions = vec![];
+	for &(src_link, dst_link) in ENTRIES.iter() {
+		let lanes = if src_link == 0 { 2 } else { 1 };
+		for lane in 0..lanes {
+			if sim.is_space_clear(src_link, lane, 5.0, 10.0) {
+				actions.push(Action::Vehicle { user_id: *next_id, src_link, dst_link, lane, pos: 5.0 });
+				*next_id += 1;
+			}
+		}
+	}
+	actions
+}
+
+/// Adds the arrivals for the next step, then takes it.
+pub fn step(sim: &mut Simulation, next_id: &mut usize) {
+	for action in arrivals(sim, next_id) {
+		action.apply(sim).unwrap();
+	}
+	sim.step();
+}
+
+/// Asserts that two simulations are in exactly the same state, as far as can be seen.
+pub fn assert_same(a: &Simulation, b: &Simulation) {
+	assert_eq!(a.get_step(), b.get_step());
+	assert_eq!(a.get_vehicle_states().collect::<Vec<_>>(), b.get_vehicle_states().collect::<Vec<_>>());
+	for id in 0..2 {
+		assert_eq!(a.get_traffic_light(id), b.get_traffic_light(id));
+	}
+	assert_eq!(frame_digest(a), frame_digest(b));
+}
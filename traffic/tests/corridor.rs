@@ -0,0 +1,37 @@
+use traffic::{Simulation, SimError, StopLineBuilder, StopLineType, TrafficLightState, LinearFunc, CubicFunc};
+
+/// Two links in a row, each with a traffic light 5 metres before its end.
+fn build() -> Simulation {
+	let mut sim = Simulation::new(0.1);
+	for (id, length) in [(0, 200.0), (1, 300.0)] {
+		sim.add_link(id, length, 10.0).unwrap();
+		sim.add_lane(id, LinearFunc::from_points(&[(0.0, 0.0), (length, length)]), CubicFunc::from_points(&[(0.0, 0.0), (length, 0.0)])).unwrap();
+		StopLineBuilder::new(id, id, 0, length - 5.0)
+			.of_type(StopLineType::TrafficLight { state: TrafficLightState::Red })
+			.add_to_simulation(&mut sim)
+			.unwrap();
+	}
+	sim.add_connection(0, 1, &[(0, 0)], 0.0).unwrap();
+	sim
+}
+
+#[test]
+fn green_wave_offsets_follow_travel_time() {
+	let corridor = build().corridor(&[0, 1]).unwrap();
+	assert_eq!(corridor.get_signals(), &[(0, 195.0), (1, 495.0)]);
+	assert_eq!(corridor.travel_time(495.0, None), Ok(49.5));
+	assert_eq!(corridor.green_wave_offsets(60.0, None), Ok(vec![(0, 0.0), (1, 30.0)]));
+	assert_eq!(corridor.green_wave_offsets(20.0, Some(15.0)), Ok(vec![(0, 0.0), (1, 0.0)]));
+}
+
+#[test]
+fn green_wave_rejects_invalid_speed_and_cycle() {
+	let corridor = build().corridor(&[0, 1]).unwrap();
+	for speed in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+		assert!(matches!(corridor.travel_time(100.0, Some(speed)), Err(SimError::InvalidSpeed(_))));
+		assert!(corridor.green_wave_offsets(60.0, Some(speed)).is_err());
+	}
+	for cycle in [0.0, -60.0, f32::NAN, f32::INFINITY] {
+		assert!(corridor.green_wave_offsets(cycle, None).is_err());
+	}
+}
@@ -0,0 +1,65 @@
+use traffic::{Simulation, SimError, LinkModel, MesoConfig, LinearFunc, CubicFunc};
+
+/// Vehicles a 100 metre lane holds at the default jam density.
+const STORAGE: usize = 14;
+
+#[test]
+fn vehicles_wait_to_enter_full_meso_link() {
+	let mut sim = Simulation::new(0.1);
+	for (id, length) in [(0, 300.0), (1, 100.0), (2, 100.0)] {
+		sim.add_link(id, length, 14.0).unwrap();
+		sim.add_lane(id, LinearFunc::from_points(&[(0.0, 0.0), (length, length)]), CubicFunc::from_points(&[(0.0, 0.0), (length, 0.0)])).unwrap();
+	}
+	sim.add_connection(0, 1, &[(0, 0)], 0.0).unwrap();
+	sim.add_connection(1, 2, &[(0, 0)], 0.0).unwrap();
+	sim.set_link_model(1, LinkModel::Meso).unwrap();
+	// Far less than arrives, so the meso link fills up
+	sim.set_meso_config(MesoConfig { capacity: 200.0, ..MesoConfig::default() });
+
+	let mut next_id = 0;
+	let mut filled = false;
+	for _ in 0..3000 {
+		if sim.is_space_clear(0, 0, 5.0, 10.0) {
+			sim.insert_vehicle(next_id, 0, 0, 5.0, 2).unwrap();
+			next_id += 1;
+		}
+		sim.step();
+		let on_meso = sim.get_vehicle_states().filter(|v| v.link == 1).count();
+		assert!(on_meso <= STORAGE, "{} vehicles on a link which holds {}", on_meso, STORAGE);
+		filled |= on_meso == STORAGE;
+	}
+	assert!(filled);
+	// Held at the end of the link before it
+	assert!(sim.get_vehicle_states().any(|v| v.link == 0 && v.pos > 290.0 && v.vel < 0.1));
+}
+
+#[test]
+fn vehicles_pass_through_meso_link_without_lanes() {
+	let mut sim = Simulation::new(0.1);
+	for id in 0..3 {
+		sim.add_link(id, 100.0, 14.0).unwrap();
+		// The middle link has no lanes, as when only its travel time is known
+		if id != 1 {
+			sim.add_lane(id, LinearFunc::from_points(&[(0.0, 0.0), (100.0, 100.0)]), CubicFunc::from_points(&[(0.0, 0.0), (100.0, 0.0)])).unwrap();
+		}
+	}
+	sim.add_connection(0, 1, &[], 0.0).unwrap();
+	sim.add_connection(1, 2, &[], 0.0).unwrap();
+	sim.set_link_model(0, LinkModel::Meso).unwrap();
+	sim.set_link_model(1, LinkModel::Meso).unwrap();
+	// Vehicles on it would have no lane to follow
+	assert_eq!(sim.set_link_model(1, LinkModel::Micro), Err(SimError::UnknownLane { link: 1, lane: 0 }));
+
+	let id = sim.insert_vehicle(0, 0, 0, 5.0, 2).unwrap();
+	let mut links = vec![];
+	for _ in 0..600 {
+		sim.step();
+		match sim.get_vehicle_state(id) {
+			Some(v) if links.last() != Some(&v.link) => links.push(v.link),
+			Some(_) => {},
+			None => break
+		}
+	}
+	assert_eq!(links, [0, 1, 2]);
+	assert_eq!(sim.num_vehicles(), 0);
+}
@@ -0,0 +1,15 @@
+mod common;
+
+#[test]
+fn parallel_step_matches_serial() {
+	let mut serial = common::build();
+	let mut parallel = common::build();
+	parallel.set_parallel(true);
+	let (mut serial_id, mut parallel_id) = (0, 0);
+	for _ in 0..1800 {
+		common::step(&mut serial, &mut serial_id);
+		common::step(&mut parallel, &mut parallel_id);
+		common::assert_same(&serial, &parallel);
+	}
+	assert!(serial.num_vehicles() > 0);
+}
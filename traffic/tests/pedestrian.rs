@@ -0,0 +1,42 @@
+use traffic::{Simulation, StopLineBuilder, StopLineType, CrossingBuilder, CrossingType, LinearFunc, CubicFunc};
+
+#[test]
+fn pedestrian_crosses_zebra_in_steady_flow() {
+	let mut sim = Simulation::new(0.1);
+	sim.add_link(0, 300.0, 14.0).unwrap();
+	sim.add_lane(0, LinearFunc::from_points(&[(0.0, 0.0), (300.0, 300.0)]), CubicFunc::from_points(&[(0.0, 0.0), (300.0, 0.0)])).unwrap();
+	StopLineBuilder::new(0, 0, 0, 200.0).of_type(StopLineType::Zebra).with_length(6.0).add_to_simulation(&mut sim).unwrap();
+	CrossingBuilder::new(0, 6.0).of_type(CrossingType::Zebra).conflicts_with(0, 206.0).add_to_simulation(&mut sim).unwrap();
+
+	let mut next_id = 0;
+	let mut insert = |sim: &mut Simulation| {
+		if sim.is_space_clear(0, 0, 5.0, 10.0) {
+			sim.insert_vehicle(next_id, 0, 0, 5.0, 0).unwrap();
+			next_id += 1;
+		}
+	};
+	// Fill the link with a steady flow before the pedestrian arrives
+	for _ in 0..600 {
+		insert(&mut sim);
+		sim.step();
+	}
+	assert!(sim.get_vehicle_states().any(|v| v.pos > 200.0));
+
+	sim.add_pedestrian(0, 0, 1.4, false).unwrap();
+	let mut steps = 0;
+	while sim.num_pedestrians() > 0 {
+		// Time to stop the approaching vehicles, clear the crossing and walk across it
+		assert!(steps < 300, "pedestrian still waiting after {} steps", steps);
+		insert(&mut sim);
+		sim.step();
+		steps += 1;
+	}
+
+	// Traffic moves on once the pedestrian has crossed
+	let mut passed = false;
+	for _ in 0..300 {
+		sim.step();
+		passed |= sim.get_vehicle_states().any(|v| v.pos > 206.0);
+	}
+	assert!(passed);
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use traffic::scenario::{LinkSpec, LaneSpec};
+use traffic::record::{Action, Recording};
+
+/// Records the network being built and a run through it.
+fn record(steps: usize) -> (traffic::Simulation, Recording) {
+	let network = common::network();
+	let mut sim = traffic::Simulation::new(common::STEP_DELTA);
+	let mut recording = Recording::new(common::STEP_DELTA);
+	let actions = network.links.into_iter().map(Action::Link)
+		.chain(network.connections.into_iter().map(Action::Connection))
+		.chain(network.stop_lines.into_iter().map(Action::StopLine))
+		.chain(network.conflicts.into_iter().map(Action::Conflict))
+		.chain(network.detectors.into_iter().map(Action::Detector))
+		.chain(network.controllers.into_iter().map(Action::Controller));
+	for action in actions {
+		recording.apply(&mut sim, action).unwrap();
+	}
+	let mut next_id = 0;
+	for _ in 0..steps {
+		for action in common::arrivals(&sim, &mut next_id) {
+			recording.apply(&mut sim, action).unwrap();
+		}
+		recording.step(&mut sim);
+	}
+	(sim, recording)
+}
+
+#[test]
+fn replay_matches_recorded_frames() {
+	let (sim, recording) = record(1200);
+	assert!(sim.num_vehicles() > 0);
+	let replayed = recording.replay().unwrap();
+	common::assert_same(&sim, &replayed);
+}
+
+#[test]
+fn failed_link_is_not_added() {
+	let (mut sim, mut recording) = record(100);
+	let entries = recording.entries.len();
+	let link = LinkSpec {
+		id: 10,
+		length: 100.0,
+		speed_limit: 14.0,
+		lanes: vec![LaneSpec { start_lat: 0.0, end_lat: 0.0 }; 300],
+		model: None
+	};
+	assert!(recording.apply(&mut sim, Action::Link(link)).is_err());
+	assert_eq!(sim.get_link_length(10), None);
+	assert_eq!(recording.entries.len(), entries);
+
+	recording.step(&mut sim);
+	let replayed = recording.replay().unwrap();
+	common::assert_same(&sim, &replayed);
+}
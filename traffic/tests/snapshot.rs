@@ -0,0 +1,45 @@
+mod common;
+
+use traffic::Simulation;
+
+#[test]
+fn restored_simulation_steps_the_same() {
+	let mut sim = common::build();
+	let mut next_id = 0;
+	for _ in 0..600 {
+		common::step(&mut sim, &mut next_id);
+	}
+	let mut restored = Simulation::from_snapshot_bytes(&sim.to_snapshot_bytes().unwrap()).unwrap();
+	common::assert_same(&sim, &restored);
+	assert!(sim.num_vehicles() > 0);
+
+	let mut restored_id = next_id;
+	for _ in 0..1200 {
+		common::step(&mut sim, &mut next_id);
+		common::step(&mut restored, &mut restored_id);
+		common::assert_same(&sim, &restored);
+	}
+}
+
+#[test]
+fn snapshot_with_unsaveable_controller_fails() {
+	struct Fixed;
+
+	impl traffic::Controller for Fixed {
+		fn stop_lines(&self) -> Vec<usize> {
+			vec![]
+		}
+
+		fn detectors(&self) -> Vec<usize> {
+			vec![]
+		}
+
+		fn step(&mut self, _time: f32, _delta: f32, _readings: &[traffic::DetectorReading]) -> Vec<traffic::TrafficLightState> {
+			vec![]
+		}
+	}
+
+	let mut sim = common::build();
+	sim.add_controller(Box::new(Fixed)).unwrap();
+	assert!(sim.to_snapshot_bytes().is_err());
+}
@@ -0,0 +1,23 @@
+use traffic::{Simulation, SimError, LinearFunc, CubicFunc};
+
+#[test]
+fn stale_vehicle_key_fails_to_resolve() {
+	let mut sim = Simulation::new(0.1);
+	sim.add_link(0, 100.0, 14.0).unwrap();
+	sim.add_lane(0, LinearFunc::from_points(&[(0.0, 0.0), (100.0, 100.0)]), CubicFunc::from_points(&[(0.0, 0.0), (100.0, 0.0)])).unwrap();
+
+	let old = sim.insert_vehicle(1, 0, 0, 10.0, 0).unwrap();
+	sim.remove_vehicle(old).unwrap();
+	// Takes the slot the removed vehicle was in
+	let new = sim.insert_vehicle(2, 0, 0, 50.0, 0).unwrap();
+	assert_ne!(old, new);
+	let slot = |key: traffic::VehicleId| key.to_string().split('v').next().unwrap().to_string();
+	assert_eq!(slot(old), slot(new));
+
+	assert_eq!(sim.get_vehicle_state(old), None);
+	assert_eq!(sim.remove_vehicle(old), Err(SimError::StaleVehicle(old)));
+	assert_eq!(sim.set_vehicle_dest(old, 0), Err(SimError::StaleVehicle(old)));
+	assert_eq!(sim.get_vehicle_state(new).map(|v| v.user_id), Some(2));
+	assert_eq!(sim.find_vehicle(1), None);
+	assert_eq!(sim.find_vehicle(2), Some(new));
+}